@@ -0,0 +1,618 @@
+//! Email addresses, their verification, notification settings, changing the username and
+//! password and resetting forgotten passwords.
+
+use crate::{
+    config::Config,
+    csrf::CsrfToken,
+    database::*,
+    log_error,
+    mail::{Email, Mailer},
+    model::*,
+    notify, password, rate_limit, render, session, token, validation, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{dev::HttpResponseBuilder, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+/// Renders `message.html`, a page that only shows some text
+pub fn render_message(
+    tera: &tera::Tera,
+    title: &str,
+    message: &str,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("title", title);
+    ctx.insert("message", message);
+    render(tera, "message.html", &ctx, response)
+}
+
+/// Shown instead of logging in a user that was disabled by an admin
+pub fn render_disabled(tera: &tera::Tera) -> actix_web::Result<HttpResponse> {
+    render_message(
+        tera,
+        "Account disabled",
+        "Your account was disabled by an administrator.",
+        HttpResponse::Forbidden(),
+    )
+}
+
+async fn send_email(mailer: &Mailer, email: Email) -> actix_web::Result<()> {
+    mailer
+        .send(email)
+        .await
+        .map_err(|err| log_error(err, "Can't send email"))
+}
+
+/// Sends a verification link to the (unverified) address of the user
+pub async fn send_verification<D: DbExt>(
+    db: &D,
+    mailer: &Mailer,
+    config: &Config,
+    user_id: u64,
+    user: &User,
+) -> actix_web::Result<()> {
+    let email = match &user.email {
+        Some(email) if !user.email_verified => email,
+        _ => return Ok(()),
+    };
+    let token = token::issue(db, TokenPurpose::EmailVerification, user_id, email)?;
+    send_email(
+        mailer,
+        Email {
+            to: email.clone(),
+            subject: "Confirm your email address".to_owned(),
+            body: format!(
+                "Hello {},\n\n\
+                 please confirm your email address for Nextflix by opening this link:\n\n\
+                 {}/verify_email?token={}\n\n\
+                 The link is valid for {} hours. If you didn't sign up, you can ignore this email.",
+                user.username,
+                config.public_url,
+                token,
+                token::EMAIL_VERIFICATION_TTL / 3600
+            ),
+        },
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct EmailQuery {
+    sent: Option<String>,
+}
+
+/// `ctx` is the [`session::user_context`] of the current user
+fn render_email_settings(
+    tera: &tera::Tera,
+    mut ctx: tera::Context,
+    csrf: &CsrfToken,
+    email: &str,
+    sent: bool,
+    errors: &validation::FieldErrors,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("email", email);
+    ctx.insert("sent", &sent);
+    ctx.insert("errors", errors);
+    render(tera, "email.html", &ctx, response)
+}
+
+pub async fn email_settings<D: DbExt>(
+    query: web::Query<EmailQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    render_email_settings(
+        &tera,
+        session::user_context(&**db, &current)?,
+        &csrf,
+        current.user.email.as_deref().unwrap_or(""),
+        query.sent.is_some(),
+        &validation::FieldErrors::default(),
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmailParams {
+    pub email: String,
+}
+
+/// Changes the email address, which has to be verified again. An empty address removes it.
+pub async fn email_settings_post<D: DbExt>(
+    params: web::Form<EmailParams>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    mailer: web::Data<Mailer>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let user_id = current.user_id;
+    let email = params.email.trim();
+    if email.is_empty() {
+        db.modify_user(user_id, |user| {
+            user.email = None;
+            user.email_verified = false;
+            Ok(())
+        })?;
+        return Ok(HttpResponse::Found()
+            .header("location", "/settings/email")
+            .finish());
+    }
+    let mut errors = validation::FieldErrors::default();
+    validation::validate_email(email, &mut errors);
+    if !errors.is_empty() {
+        return render_email_settings(
+            &tera,
+            session::user_context(&**db, &current)?,
+            &csrf,
+            email,
+            false,
+            &errors,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    // Submitting the unverified address again sends a new link
+    if current.user.email.as_deref() != Some(email) || !current.user.email_verified {
+        let user = db.modify_user(user_id, |user| {
+            user.email = Some(email.to_owned());
+            user.email_verified = false;
+            Ok(user.clone())
+        })?;
+        send_verification(&**db, &mailer, &config, user_id, &user).await?;
+    }
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/email?sent")
+        .finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationParams {
+    pub notifications: NotificationPreference,
+}
+
+pub async fn notification_settings_post<D: DbExt>(
+    params: web::Form<NotificationParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    db.modify_user(current.user_id, |user| {
+        user.notifications = params.notifications;
+        Ok(())
+    })?;
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/email")
+        .finish())
+}
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: String,
+}
+
+pub async fn verify_email<D: DbExt>(
+    query: web::Query<TokenQuery>,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let invalid = || {
+        render_message(
+            &tera,
+            "Invalid link",
+            "This link is invalid or has expired.",
+            HttpResponse::NotFound(),
+        )
+    };
+    let token = match token::redeem(&**db, &query.token, TokenPurpose::EmailVerification)? {
+        Some(token) => token,
+        None => return invalid(),
+    };
+    let verified = db.modify_user(token.user_id, |user| {
+        // The address was changed after the link was sent
+        if user.email.as_deref() != Some(&token.email) {
+            return Ok(false);
+        }
+        user.email_verified = true;
+        Ok(true)
+    });
+    match verified {
+        Ok(false) | Err(DbError::NotFound) => invalid(),
+        Ok(true) => render_message(
+            &tera,
+            "Email address confirmed",
+            &format!("{} is now confirmed.", token.email),
+            HttpResponse::Ok(),
+        ),
+        Err(DbError::Conflict(_)) => render_message(
+            &tera,
+            "Email address in use",
+            "This address already belongs to another account.",
+            HttpResponse::Conflict(),
+        ),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn forgot_password(csrf: CsrfToken, tera: Tera) -> actix_web::Result<HttpResponse> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "forgot_password.html", &ctx, HttpResponse::Ok())
+}
+
+/// Queues a reset link if the address belongs to a user. The response is the same either way,
+/// also when there were too many requests for the address, so that it can't be used to find out
+/// who has an account.
+pub async fn forgot_password_post<D: DbExt>(
+    params: web::Form<EmailParams>,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let address = params.email.trim();
+    if rate_limit::allow_password_reset(&**db, address)? {
+        match db.get_user_by_email(address)? {
+            Some((user_id, user)) if user.email_verified => {
+                notify::queue_password_reset(&**db, user_id)?;
+            }
+            _ => {}
+        }
+    }
+    render_message(
+        &tera,
+        "Check your inbox",
+        "If an account with a confirmed address exists, we've sent it a link to reset the password.",
+        HttpResponse::Ok(),
+    )
+}
+
+fn render_reset_password(
+    tera: &tera::Tera,
+    csrf: &CsrfToken,
+    token: &str,
+    errors: &validation::FieldErrors,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("token", token);
+    ctx.insert("errors", errors);
+    render(tera, "reset_password.html", &ctx, response)
+}
+
+fn invalid_reset_link(tera: &tera::Tera) -> actix_web::Result<HttpResponse> {
+    render_message(
+        tera,
+        "Invalid link",
+        "This link is invalid, has expired or was already used. You can request a new one.",
+        HttpResponse::NotFound(),
+    )
+}
+
+/// Returns the token and its user if the token is valid for resetting the password
+fn reset_token_user<D: DbExt>(db: &D, token: &str) -> DbResult<Option<(Token, User)>> {
+    let token = match token::peek(db, token, TokenPurpose::PasswordReset)? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    Ok(match db.get_user(token.user_id)? {
+        Some(user) if user.email.as_deref() == Some(&token.email) && user.email_verified => {
+            Some((token, user))
+        }
+        _ => None,
+    })
+}
+
+pub async fn reset_password<D: DbExt>(
+    query: web::Query<TokenQuery>,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    if reset_token_user(&**db, &query.token)?.is_none() {
+        return invalid_reset_link(&tera);
+    }
+    render_reset_password(
+        &tera,
+        &csrf,
+        &query.token,
+        &validation::FieldErrors::default(),
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetPasswordParams {
+    pub token: String,
+    pub password: String,
+    pub password_repeat: String,
+}
+
+/// Sets the new password and ends all sessions of the user
+pub async fn reset_password_post<D: DbExt>(
+    params: web::Form<ResetPasswordParams>,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let (token, user) = match reset_token_user(&**db, &params.token)? {
+        Some(found) => found,
+        None => return invalid_reset_link(&tera),
+    };
+    let mut errors = validation::FieldErrors::default();
+    validation::validate_password(
+        &params.password,
+        &user.username,
+        password::max_bytes(&config.password),
+        &mut errors,
+    );
+    if params.password != params.password_repeat {
+        errors.add("password_repeat", "Passwords don't match");
+    }
+    if !errors.is_empty() {
+        return render_reset_password(
+            &tera,
+            &csrf,
+            &params.token,
+            &errors,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    // Somebody else could have used the token in the meantime
+    if token::redeem(&**db, &params.token, TokenPurpose::PasswordReset)?.is_none() {
+        return invalid_reset_link(&tera);
+    }
+    let password_hash = password::hash_blocking(&config.password, &params.password).await?;
+    db.modify_user(token.user_id, |user| {
+        user.password_hash = password_hash.clone();
+        Ok(())
+    })?;
+    db.remove_sessions_by_user(token.user_id)?;
+    rate_limit::unlock(&**db, &user.username)?;
+    log::info!("password reset {}", token.user_id);
+    Ok(HttpResponse::Found()
+        .header("location", "/login?password_reset")
+        .finish())
+}
+
+#[derive(Deserialize)]
+pub struct CredentialsQuery {
+    password_changed: Option<String>,
+    username_changed: Option<String>,
+    display_name_changed: Option<String>,
+}
+
+/// Context of `credentials.html` with an empty form
+fn credentials_context<D: DbExt>(
+    db: &D,
+    current: &session::CurrentUser,
+    csrf: &CsrfToken,
+) -> DbResult<tera::Context> {
+    let mut ctx = session::user_context(db, current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("has_password", &!current.user.password_hash.is_empty());
+    ctx.insert("username", &current.user.username);
+    ctx.insert(
+        "display_name",
+        current.user.display_name.as_deref().unwrap_or(""),
+    );
+    ctx.insert("errors", &validation::FieldErrors::default());
+    ctx.insert("notice", &None::<&str>);
+    Ok(ctx)
+}
+
+pub async fn credentials<D: DbExt>(
+    query: web::Query<CredentialsQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let mut ctx = credentials_context(&**db, &current, &csrf)?;
+    if query.password_changed.is_some() {
+        ctx.insert("notice", "Your password was changed.");
+    } else if query.username_changed.is_some() {
+        ctx.insert("notice", "Your username was changed.");
+    } else if query.display_name_changed.is_some() {
+        ctx.insert("notice", "Your display name was changed.");
+    }
+    render(&tera, "credentials.html", &ctx, HttpResponse::Ok())
+}
+
+/// Checks the current password, which users that only log in through OpenID Connect don't have
+/// Checks the current password like a login attempt, so that a stolen session can't be used to
+/// guess it. Returns the response to send instead if there were too many wrong passwords.
+async fn check_current_password<D: DbExt>(
+    db: &D,
+    req: &HttpRequest,
+    user: &User,
+    password: &str,
+    errors: &mut validation::FieldErrors,
+) -> actix_web::Result<Option<HttpResponse>> {
+    if user.password_hash.is_empty() {
+        return Ok(None);
+    }
+    let ip = rate_limit::client_ip(req);
+    if let Some(retry_after) = rate_limit::check(db, &user.username, &ip)? {
+        return Ok(Some(rate_limit::too_many_attempts(retry_after)));
+    }
+    if password::verify_blocking(password, &user.password_hash).await? {
+        rate_limit::record_success(db, &user.username, &ip)?;
+    } else {
+        rate_limit::record_failure(db, &user.username, &ip)?;
+        errors.add("current_password", "Wrong password");
+    }
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangePasswordParams {
+    pub current_password: String,
+    pub password: String,
+    pub password_repeat: String,
+}
+
+/// Sets a new password and ends all other sessions of the user
+pub async fn change_password_post<D: DbExt>(
+    params: web::Form<ChangePasswordParams>,
+    req: HttpRequest,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let mut errors = validation::FieldErrors::default();
+    if let Some(response) = check_current_password(
+        &**db,
+        &req,
+        &current.user,
+        &params.current_password,
+        &mut errors,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+    validation::validate_password(
+        &params.password,
+        &current.user.username,
+        password::max_bytes(&config.password),
+        &mut errors,
+    );
+    if params.password != params.password_repeat {
+        errors.add("password_repeat", "Passwords don't match");
+    }
+    if !errors.is_empty() {
+        let mut ctx = credentials_context(&**db, &current, &csrf)?;
+        ctx.insert("errors", &errors);
+        return render(
+            &tera,
+            "credentials.html",
+            &ctx,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    let password_hash = password::hash_blocking(&config.password, &params.password).await?;
+    db.modify_user(current.user_id, |user| {
+        user.password_hash = password_hash.clone();
+        Ok(())
+    })?;
+    for (session_id, _) in db.get_sessions_by_user(current.user_id)? {
+        if session_id != current.session_id {
+            db.remove_session(&session_id)?;
+        }
+    }
+    log::info!("password changed {}", current.user_id);
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/credentials?password_changed")
+        .finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeUsernameParams {
+    pub username: String,
+    pub current_password: String,
+}
+
+/// Renames the user. Sessions, friends and everything else refer to the user id, so nothing
+/// else has to change.
+pub async fn change_username_post<D: DbExt>(
+    params: web::Form<ChangeUsernameParams>,
+    req: HttpRequest,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let username = params.username.trim();
+    let mut errors = validation::FieldErrors::default();
+    if let Some(response) = check_current_password(
+        &**db,
+        &req,
+        &current.user,
+        &params.current_password,
+        &mut errors,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+    validation::validate_username(username, &mut errors);
+    let mut response = HttpResponse::UnprocessableEntity();
+    if errors.is_empty() {
+        // Updates `users` and `users_username` in one transaction and fails if the name is taken
+        let renamed = db.modify_user(current.user_id, |user| {
+            user.username = username.to_owned();
+            Ok(())
+        });
+        match renamed {
+            Ok(()) => {
+                log::info!("username changed {}", current.user_id);
+                return Ok(HttpResponse::Found()
+                    .header("location", "/settings/credentials?username_changed")
+                    .finish());
+            }
+            Err(DbError::Conflict(_)) => {
+                errors.add("username", "This name is already taken");
+                response = HttpResponse::Conflict();
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut ctx = credentials_context(&**db, &current, &csrf)?;
+    ctx.insert("username", username);
+    ctx.insert("errors", &errors);
+    render(&tera, "credentials.html", &ctx, response)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisplayNameParams {
+    pub display_name: String,
+}
+
+/// Sets the name shown next to the username. An empty name removes it.
+pub async fn display_name_post<D: DbExt>(
+    params: web::Form<DisplayNameParams>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let display_name = params.display_name.trim();
+    let mut errors = validation::FieldErrors::default();
+    validation::validate_display_name(display_name, &mut errors);
+    if !errors.is_empty() {
+        let mut ctx = credentials_context(&**db, &current, &csrf)?;
+        ctx.insert("display_name", display_name);
+        ctx.insert("errors", &errors);
+        return render(
+            &tera,
+            "credentials.html",
+            &ctx,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    db.modify_user(current.user_id, |user| {
+        user.display_name = if display_name.is_empty() {
+            None
+        } else {
+            Some(display_name.to_owned())
+        };
+        Ok(())
+    })?;
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/credentials?display_name_changed")
+        .finish())
+}
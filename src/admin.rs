@@ -0,0 +1,300 @@
+//! Admin area for managing users and movies.
+//!
+//! Only users with [`Role::Admin`] can use it. The first admin is appointed with
+//! `nextflix grant-admin <username>`, further admins can be appointed in the admin area. Admins
+//! can't disable, delete or demote themselves, so there is always at least one admin left.
+
+use crate::{
+    account::render_message, config::Config, csrf::CsrfToken, database::*, model::*, password,
+    render, session, token, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{error, web, HttpResponse};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+const USERS_PER_PAGE: usize = 50;
+const MOVIES_PER_PAGE: usize = 50;
+/// Length of the passwords that are generated when an admin resets a password
+const TEMPORARY_PASSWORD_LENGTH: usize = 16;
+
+/// Like [`session::require_user`], but the user also has to be an admin
+pub fn require_admin<D: DbExt>(id: &Identity, db: &D) -> actix_web::Result<session::CurrentUser> {
+    let current = session::require_user(id, db)?;
+    if current.user.role != Role::Admin {
+        return Err(error::ErrorForbidden("Only admins can do this"));
+    }
+    Ok(current)
+}
+
+/// Makes `username` an admin. Returns whether the user was found.
+pub fn grant<D: DbExt>(db: &D, username: &str) -> DbResult<bool> {
+    let user_id = match db.get_user_by_username(username)? {
+        Some((user_id, _)) => user_id,
+        None => return Ok(false),
+    };
+    db.modify_user(user_id, |user| {
+        user.role = Role::Admin;
+        Ok(())
+    })?;
+    info!(target: "audit", "Made {} an admin", user_id);
+    Ok(true)
+}
+
+pub async fn dashboard<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("tree_sizes", &db.tree_sizes()?);
+    render(&tera, "admin.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListQuery {
+    /// Only show entries whose name contains this
+    pub q: Option<String>,
+    #[serde(default)]
+    pub page: usize,
+}
+
+#[derive(Serialize)]
+struct UserRow {
+    id: u64,
+    username: String,
+    email: Option<String>,
+    role: Role,
+    disabled: bool,
+    two_factor: bool,
+}
+
+pub async fn users<D: DbExt>(
+    query: web::Query<ListQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    let filter = query.q.as_deref().unwrap_or("").trim().to_lowercase();
+    let users = db
+        .get_users()?
+        .into_iter()
+        .filter(|(_, user)| user.username.to_lowercase().contains(&filter))
+        .collect::<Vec<_>>();
+    let rows = users
+        .iter()
+        .skip(query.page * USERS_PER_PAGE)
+        .take(USERS_PER_PAGE)
+        .map(|(user_id, user)| UserRow {
+            id: *user_id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            role: user.role,
+            disabled: user.disabled,
+            two_factor: crate::totp::is_enabled(user),
+        })
+        .collect::<Vec<_>>();
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("users", &rows);
+    ctx.insert("q", &filter);
+    ctx.insert("page", &query.page);
+    ctx.insert(
+        "has_next_page",
+        &(users.len() > (query.page + 1) * USERS_PER_PAGE),
+    );
+    ctx.insert("current_user_id", &current.user_id);
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "admin_users.html", &ctx, HttpResponse::Ok())
+}
+
+fn redirect_to_users() -> HttpResponse {
+    HttpResponse::Found()
+        .header("location", "/admin/users")
+        .finish()
+}
+
+/// Refuses changes admins make to their own account that could lock everybody out
+fn refuse_self(tera: &tera::Tera, action: &str) -> actix_web::Result<HttpResponse> {
+    render_message(
+        tera,
+        "Not allowed",
+        &format!("You can't {} yourself.", action),
+        HttpResponse::Conflict(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableParams {
+    pub user_id: u64,
+    pub disabled: bool,
+}
+
+pub async fn disable_post<D: DbExt>(
+    params: web::Form<DisableParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    if params.user_id == current.user_id {
+        return refuse_self(&tera, "disable");
+    }
+    db.modify_user(params.user_id, |user| {
+        user.disabled = params.disabled;
+        Ok(())
+    })?;
+    if params.disabled {
+        db.remove_sessions_by_user(params.user_id)?;
+    }
+    info!(
+        target: "audit",
+        "{} {} user {}",
+        current.user_id,
+        if params.disabled { "disabled" } else { "enabled" },
+        params.user_id
+    );
+    Ok(redirect_to_users())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleParams {
+    pub user_id: u64,
+    pub role: Role,
+}
+
+pub async fn role_post<D: DbExt>(
+    params: web::Form<RoleParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    if params.user_id == current.user_id {
+        return refuse_self(&tera, "change the role of");
+    }
+    db.modify_user(params.user_id, |user| {
+        user.role = params.role;
+        Ok(())
+    })?;
+    info!(
+        target: "audit",
+        "{} changed the role of user {} to {:?}",
+        current.user_id, params.user_id, params.role
+    );
+    Ok(redirect_to_users())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserParams {
+    pub user_id: u64,
+}
+
+/// Sets a random password, which is shown once to the admin, and logs the user out everywhere
+pub async fn reset_password_post<D: DbExt>(
+    params: web::Form<UserParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    let new_password = token::generate()[..TEMPORARY_PASSWORD_LENGTH].to_owned();
+    let password_hash = password::hash_blocking(&config.password, &new_password).await?;
+    let user = db.modify_user(params.user_id, |user| {
+        user.password_hash = password_hash.clone();
+        Ok(user.clone())
+    })?;
+    db.remove_sessions_by_user(params.user_id)?;
+    info!(
+        target: "audit",
+        "{} reset the password of user {}",
+        current.user_id, params.user_id
+    );
+    render_message(
+        &tera,
+        "Password reset",
+        &format!(
+            "The new password of {} is {}. It won't be shown again.",
+            user.username, new_password
+        ),
+        HttpResponse::Ok(),
+    )
+}
+
+pub async fn delete_post<D: DbExt>(
+    params: web::Form<UserParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    if params.user_id == current.user_id {
+        return refuse_self(&tera, "delete");
+    }
+    db.remove_user(params.user_id)?;
+    info!(
+        target: "audit",
+        "{} deleted user {}",
+        current.user_id, params.user_id
+    );
+    Ok(redirect_to_users())
+}
+
+pub async fn movies<D: DbExt>(
+    query: web::Query<ListQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    let q = query.q.as_deref().unwrap_or("").trim();
+    let results = if q.is_empty() {
+        Vec::new()
+    } else {
+        db.search_movie(q)?
+    };
+    let movies = results
+        .iter()
+        .skip(query.page * MOVIES_PER_PAGE)
+        .take(MOVIES_PER_PAGE)
+        .map(|(movie_id, movie, _)| (movie_id, movie))
+        .collect::<Vec<_>>();
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("movies", &movies);
+    ctx.insert("q", q);
+    ctx.insert("page", &query.page);
+    ctx.insert(
+        "has_next_page",
+        &(results.len() > (query.page + 1) * MOVIES_PER_PAGE),
+    );
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "admin_movies.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MovieParams {
+    pub movie_id: u64,
+}
+
+pub async fn delete_movie_post<D: DbExt>(
+    params: web::Form<MovieParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_admin(&id, &**db)?;
+    db.remove_movie(params.movie_id)?;
+    info!(
+        target: "audit",
+        "{} deleted movie {}",
+        current.user_id, params.movie_id
+    );
+    Ok(HttpResponse::Found()
+        .header("location", "/admin/movies")
+        .finish())
+}
@@ -0,0 +1,397 @@
+//! JSON API under `/api/v1`.
+//!
+//! Scripts authenticate with a personal access token (see [`api_token`]). The API also accepts
+//! the session cookie of the web pages, in which case requests that change state need the CSRF
+//! token in the `X-CSRF-Token` header. Every error has a body of the form
+//! `{"error": {"code": "not_found", "message": "Not found"}}`.
+
+use crate::{api_token, csrf, database::*, model::*, session, watchlist};
+use actix_identity::Identity;
+use actix_web::{
+    dev::Payload, error, http::StatusCode, web, FromRequest, HttpRequest, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::marker::PhantomData;
+
+pub const PREFIX: &str = "/api/v1";
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    /// Machine readable error code
+    code: &'static str,
+    message: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: StatusCode, code: &'static str, message: S) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", "Not logged in")
+    }
+
+    pub fn forbidden<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", "Not found")
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl error::ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            }
+        }))
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        match err {
+            DbError::Storage(_) | DbError::Corruption(_) => {
+                debug!("{:?}", err);
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "Database error",
+                )
+            }
+            DbError::Conflict(msg) => ApiError::new(StatusCode::CONFLICT, "conflict", msg),
+            DbError::NotFound => ApiError::not_found(),
+            DbError::Validation(msg) => {
+                ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid", msg)
+            }
+        }
+    }
+}
+
+/// The user a request was made by, authenticated with a bearer token or the session cookie.
+///
+/// Requests that change something need a token with the [`Scope::Write`] scope, all others need
+/// [`Scope::Read`]. Sessions have all scopes.
+pub struct ApiUser<D> {
+    pub user_id: u64,
+    pub user: User,
+    _db: PhantomData<fn() -> D>,
+}
+
+impl<D: DbExt + 'static> ApiUser<D> {
+    async fn authenticate(req: HttpRequest) -> ApiResult<Self> {
+        let db = req
+            .app_data::<web::Data<D>>()
+            .map(web::Data::get_ref)
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "Database is not configured",
+                )
+            })?;
+        let (user_id, user) = match api_token::bearer_token(req.headers()) {
+            Some(token) => {
+                let token = api_token::authenticate(db, token)?.ok_or_else(|| {
+                    ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "unauthorized",
+                        "Invalid or expired token",
+                    )
+                })?;
+                let required = if csrf::is_safe(req.method()) {
+                    Scope::Read
+                } else {
+                    Scope::Write
+                };
+                if !token.scopes.contains(&required) {
+                    return Err(ApiError::forbidden(format!(
+                        "Token doesn't have the {:?} scope",
+                        required
+                    )));
+                }
+                let user = db
+                    .get_user(token.user_id)?
+                    .filter(|user| !user.disabled)
+                    .ok_or_else(ApiError::unauthorized)?;
+                (token.user_id, user)
+            }
+            None => {
+                let id = Identity::from_request(&req, &mut Payload::None)
+                    .await
+                    .map_err(|_| ApiError::unauthorized())?;
+                let current = session::current_user(&id, db)?.ok_or_else(ApiError::unauthorized)?;
+                (current.user_id, current.user)
+            }
+        };
+        Ok(ApiUser {
+            user_id,
+            user,
+            _db: PhantomData,
+        })
+    }
+}
+
+impl<D: DbExt + 'static> FromRequest for ApiUser<D> {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, ApiResult<Self>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        Box::pin(Self::authenticate(req.clone()))
+    }
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    id: u64,
+    username: String,
+}
+
+impl UserInfo {
+    /// Skips users that were removed but are still in a friend list
+    fn load_all<D: DbExt>(db: &D, ids: impl IntoIterator<Item = u64>) -> ApiResult<Vec<UserInfo>> {
+        let mut users = Vec::new();
+        for id in ids {
+            if let Some(user) = db.get_user(id)? {
+                users.push(UserInfo {
+                    id,
+                    username: user.username,
+                });
+            }
+        }
+        Ok(users)
+    }
+}
+
+#[derive(Serialize)]
+struct MovieInfo {
+    id: u64,
+    #[serde(flatten)]
+    movie: Movie,
+}
+
+async fn me<D: DbExt + 'static>(current: ApiUser<D>) -> ApiResult<HttpResponse> {
+    let user = current.user;
+    Ok(HttpResponse::Ok().json(json!({
+        "id": current.user_id,
+        "username": user.username,
+        "email": user.email,
+        "email_verified": user.email_verified,
+        "notifications": user.notifications,
+    })))
+}
+
+async fn get_user<D: DbExt + 'static>(
+    path: web::Path<String>,
+    _: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let (user_id, user) = db
+        .get_user_by_username(&path)?
+        .ok_or_else(ApiError::not_found)?;
+    Ok(HttpResponse::Ok().json(UserInfo {
+        id: user_id,
+        username: user.username,
+    }))
+}
+
+async fn friends<D: DbExt + 'static>(
+    current: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let mut friend_ids = current.user.friends.keys().copied().collect::<Vec<_>>();
+    friend_ids.sort_unstable();
+    let friends = UserInfo::load_all(&**db, friend_ids)?;
+    Ok(HttpResponse::Ok().json(friends))
+}
+
+async fn add_friend<D: DbExt + 'static>(
+    params: web::Json<watchlist::AddFriendParams>,
+    current: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let (friend_id, friend) = db
+        .get_user_by_username(params.username.trim())?
+        .ok_or_else(ApiError::not_found)?;
+    let added = watchlist::add_friend(&**db, current.user_id, friend_id)?;
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(HttpResponse::build(status).json(UserInfo {
+        id: friend_id,
+        username: friend.username,
+    }))
+}
+
+async fn get_movie<D: DbExt + 'static>(
+    path: web::Path<u64>,
+    _: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let movie_id = path.into_inner();
+    let movie = db.get_movie(movie_id)?.ok_or_else(ApiError::not_found)?;
+    Ok(HttpResponse::Ok().json(MovieInfo {
+        id: movie_id,
+        movie,
+    }))
+}
+
+async fn add_movie<D: DbExt + 'static>(
+    movie: web::Json<Movie>,
+    _: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let movie = movie.into_inner();
+    let movie_id = db.add_movie(&movie)?;
+    Ok(HttpResponse::Created().json(MovieInfo {
+        id: movie_id,
+        movie,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    movie: MovieInfo,
+    score: f32,
+}
+
+async fn search_movies<D: DbExt + 'static>(
+    query: web::Query<SearchQuery>,
+    _: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let results = db.search_movie(&query.q)?;
+    let total = results.len();
+    let results = results
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|(movie_id, movie, score)| SearchResult {
+            movie: MovieInfo {
+                id: movie_id,
+                movie,
+            },
+            score,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({
+        "total": total,
+        "results": results,
+    })))
+}
+
+#[derive(Serialize)]
+struct WatchlistEntryInfo {
+    #[serde(flatten)]
+    movie: MovieInfo,
+    recommended_by: Vec<UserInfo>,
+}
+
+async fn get_watchlist<D: DbExt + 'static>(
+    filter: web::Query<watchlist::WatchlistFilter>,
+    current: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let entries = watchlist::watchlist(&**db, &current.user, &filter)?
+        .into_iter()
+        .map(|entry| {
+            Ok(WatchlistEntryInfo {
+                movie: MovieInfo {
+                    id: entry.movie_id,
+                    movie: entry.movie,
+                },
+                recommended_by: UserInfo::load_all(&**db, entry.recommended_by)?,
+            })
+        })
+        .collect::<ApiResult<Vec<_>>>()?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn recommend<D: DbExt + 'static>(
+    params: web::Json<watchlist::RecommendParams>,
+    current: ApiUser<D>,
+    db: web::Data<D>,
+) -> ApiResult<HttpResponse> {
+    let (friend_id, _) = db
+        .get_user_by_username(&params.friend)?
+        .ok_or_else(ApiError::not_found)?;
+    let added = watchlist::recommend(&**db, current.user_id, friend_id, params.movie_id)?;
+    let status = if added {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(HttpResponse::build(status).json(json!({
+        "friend": params.friend,
+        "movie_id": params.movie_id,
+    })))
+}
+
+async fn not_found() -> ApiResult<HttpResponse> {
+    Err(ApiError::not_found())
+}
+
+fn bad_request<E: std::fmt::Display>(err: E, _: &HttpRequest) -> error::Error {
+    ApiError::bad_request(err.to_string()).into()
+}
+
+pub fn routes<D: DbExt + 'static>(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope(PREFIX)
+            .app_data(web::JsonConfig::default().error_handler(bad_request))
+            .app_data(web::QueryConfig::default().error_handler(bad_request))
+            .app_data(web::PathConfig::default().error_handler(bad_request))
+            .route("/me", web::get().to(me::<D>))
+            .route("/users/{username}", web::get().to(get_user::<D>))
+            .route("/friends", web::get().to(friends::<D>))
+            .route("/friends", web::post().to(add_friend::<D>))
+            .route("/movies", web::post().to(add_movie::<D>))
+            .route("/movies/search", web::get().to(search_movies::<D>))
+            .route("/movies/{id}", web::get().to(get_movie::<D>))
+            .route("/watchlist", web::get().to(get_watchlist::<D>))
+            .route("/recommendations", web::post().to(recommend::<D>))
+            .default_service(web::route().to(not_found)),
+    );
+}
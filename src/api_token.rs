@@ -0,0 +1,249 @@
+//! Personal access tokens for scripts that use the JSON API.
+//!
+//! Tokens are sent as `Authorization: Bearer <token>` and, like the tokens sent by email, only
+//! their SHA-256 hash is stored. A token can be limited to reading and can expire.
+
+use crate::{csrf::CsrfToken, database::*, model::*, render, session, token, validation, Tera};
+use actix_identity::Identity;
+use actix_web::{dev::HttpResponseBuilder, http::header, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of every token, so that leaked tokens are easy to recognize
+const PREFIX: &str = "nfx_";
+const MAX_NAME_LENGTH: usize = 64;
+const DAY: u64 = 24 * 60 * 60;
+
+/// `last_used` is only updated if it is older than this many seconds
+const TOUCH_INTERVAL: u64 = 60;
+
+/// Token from the `Authorization` header, if the request uses bearer authentication
+pub fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_at(value.find(' ')?);
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Creates a token and returns it. It can't be shown again later.
+pub fn issue<D: DbExt>(
+    db: &D,
+    user_id: u64,
+    name: &str,
+    scopes: Vec<Scope>,
+    expires: Option<u64>,
+) -> DbResult<String> {
+    let token = format!("{}{}", PREFIX, token::generate());
+    db.add_api_token(
+        &token::hash(&token),
+        &ApiToken {
+            user_id,
+            name: name.to_owned(),
+            scopes,
+            created: unix_time(),
+            expires,
+            last_used: None,
+        },
+    )?;
+    Ok(token)
+}
+
+fn is_expired(token: &ApiToken, now: u64) -> bool {
+    token.expires.is_some_and(|expires| expires <= now)
+}
+
+/// Returns the stored token if it exists and hasn't expired, and records that it was used
+pub fn authenticate<D: DbExt>(db: &D, token: &str) -> DbResult<Option<ApiToken>> {
+    let token_hash = token::hash(token);
+    let api_token = match db.get_api_token(&token_hash)? {
+        Some(api_token) => api_token,
+        None => return Ok(None),
+    };
+    let now = unix_time();
+    if is_expired(&api_token, now) {
+        return Ok(None);
+    }
+    if api_token
+        .last_used
+        .is_none_or(|last_used| now >= last_used + TOUCH_INTERVAL)
+    {
+        db.touch_api_token(&token_hash, now)?;
+    }
+    Ok(Some(api_token))
+}
+
+#[derive(Serialize)]
+struct TokenInfo {
+    id: String,
+    expired: bool,
+    #[serde(flatten)]
+    token: ApiToken,
+}
+
+fn render_tokens<D: DbExt>(
+    tera: &tera::Tera,
+    db: &D,
+    current: &session::CurrentUser,
+    csrf: &CsrfToken,
+    new_token: Option<&str>,
+    errors: &validation::FieldErrors,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let now = unix_time();
+    let mut tokens = db
+        .get_api_tokens_by_user(current.user_id)?
+        .into_iter()
+        .map(|(id, token)| TokenInfo {
+            id,
+            expired: is_expired(&token, now),
+            token,
+        })
+        .collect::<Vec<_>>();
+    tokens.sort_by_key(|t| std::cmp::Reverse(t.token.created));
+    let mut ctx = session::user_context(db, current)?;
+    ctx.insert("tokens", &tokens);
+    ctx.insert("new_token", &new_token);
+    ctx.insert("errors", errors);
+    ctx.insert("csrf_token", &csrf.0);
+    render(tera, "tokens.html", &ctx, response)
+}
+
+pub async fn tokens<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    render_tokens(
+        &tera,
+        &**db,
+        &current,
+        &csrf,
+        None,
+        &validation::FieldErrors::default(),
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateTokenParams {
+    pub name: String,
+    /// Checkboxes, only sent if checked
+    pub read: Option<String>,
+    pub write: Option<String>,
+    /// 0 for tokens that don't expire
+    pub expires_in_days: u64,
+}
+
+pub async fn create_token<D: DbExt>(
+    params: web::Form<CreateTokenParams>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let name = params.name.trim();
+    let mut scopes = Vec::new();
+    if params.read.is_some() {
+        scopes.push(Scope::Read);
+    }
+    if params.write.is_some() {
+        scopes.push(Scope::Write);
+    }
+    let mut errors = validation::FieldErrors::default();
+    if name.is_empty() {
+        errors.add("name", "Name is required");
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        errors.add(
+            "name",
+            format!("Name must be at most {} characters", MAX_NAME_LENGTH),
+        );
+    }
+    if scopes.is_empty() {
+        errors.add("scopes", "Choose at least one permission");
+    }
+    if !errors.is_empty() {
+        return render_tokens(
+            &tera,
+            &**db,
+            &current,
+            &csrf,
+            None,
+            &errors,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    let expires = match params.expires_in_days {
+        0 => None,
+        days => Some(unix_time() + days.saturating_mul(DAY)),
+    };
+    let token = issue(&**db, current.user_id, name, scopes, expires)?;
+    render_tokens(
+        &tera,
+        &**db,
+        &current,
+        &csrf,
+        Some(&token),
+        &errors,
+        HttpResponse::Ok(),
+    )
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeTokenParams {
+    pub token_id: String,
+}
+
+pub async fn revoke_token<D: DbExt>(
+    params: web::Form<RevokeTokenParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    // Only allow revoking own tokens
+    match db.get_api_token(&params.token_id)? {
+        Some(token) if token.user_id == current.user_id => db.remove_api_token(&params.token_id)?,
+        _ => return Err(DbError::NotFound.into()),
+    }
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/tokens")
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+    use actix_web::http::HeaderValue;
+
+    #[test]
+    fn bearer() {
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("bearer nfx_a"),
+        );
+        assert_eq!(bearer_token(&headers), Some("nfx_a"));
+    }
+
+    #[test]
+    fn expiry() {
+        let db = MemoryDb::new();
+        let token = issue(&db, 1, "script", vec![Scope::Read], None).unwrap();
+        assert!(token.starts_with(PREFIX));
+        assert_eq!(authenticate(&db, &token).unwrap().unwrap().user_id, 1);
+        let (_, stored) = db.get_api_tokens_by_user(1).unwrap().remove(0);
+        assert!(stored.last_used.is_some());
+        assert!(authenticate(&db, "nfx_wrong").unwrap().is_none());
+
+        let expired = issue(&db, 1, "old", vec![Scope::Read], Some(unix_time() - 1)).unwrap();
+        assert!(authenticate(&db, &expired).unwrap().is_none());
+    }
+}
@@ -0,0 +1,608 @@
+//! Server configuration.
+//!
+//! Settings are read from a TOML file (`nextflix.toml` or the path given with `--config`),
+//! then overridden by `NEXTFLIX_*` environment variables and finally by command line flags.
+
+use serde::Deserialize;
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_CONFIG_FILE: &str = "nextflix.toml";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    /// Address the HTTP server listens on
+    pub bind: String,
+    pub template_dir: PathBuf,
+    /// Filter for `env_logger`, e.g. `nextflix=debug,actix_web=info`
+    pub log_level: String,
+    pub cookie: CookieConfig,
+    /// URL under which users reach the site, used for links in emails
+    pub public_url: String,
+    /// Addresses of reverse proxies whose `X-Forwarded-For` header is trusted
+    pub trusted_proxies: Vec<IpAddr>,
+    pub mail: MailConfig,
+    pub oidc: OidcConfig,
+    pub two_factor: TwoFactorConfig,
+    pub password: PasswordConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+    /// Maximum size of the page cache in bytes
+    pub cache_capacity: u64,
+    /// Don't persist anything and fill the database with demo data
+    pub temporary: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    pub name: String,
+    /// Only send the cookie over HTTPS
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// Lifetime of the cookie in seconds, the cookie expires with the browser session if unset
+    pub max_age: Option<i64>,
+    /// File containing the base64 encoded signing key, created on first run
+    pub key_file: PathBuf,
+    /// Base64 encoded signing key, takes precedence over `key_file`
+    pub key: Option<String>,
+    /// Previous signing keys that are still accepted
+    pub old_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    /// Sender address of all emails
+    pub from: String,
+    pub transport: MailTransport,
+    /// Directory that emails are written to by the `file` transport
+    pub outbox_dir: PathBuf,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+}
+
+/// Login through an OpenID Connect provider
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Issuer URL of the provider, OpenID Connect login is disabled if unset
+    pub issuer: Option<String>,
+    pub client_id: String,
+    /// Secret of confidential clients, leave unset for public clients
+    pub client_secret: Option<String>,
+    /// Name of the provider shown on the login button
+    pub name: String,
+    /// Create an account when somebody logs in with an identity that isn't linked to a user yet
+    pub allow_registration: bool,
+}
+
+/// Two-factor authentication with authenticator apps
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// Name of the site shown in authenticator apps
+    pub issuer: String,
+    /// File containing the base64 encoded key that TOTP secrets are encrypted with, created on
+    /// first run
+    pub key_file: PathBuf,
+    /// Base64 encoded key, takes precedence over `key_file`
+    pub key: Option<String>,
+}
+
+/// How passwords are hashed. Hashes made with another algorithm or other parameters are
+/// replaced when the user logs in the next time.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub algorithm: PasswordAlgorithm,
+    /// Cost of bcrypt, the hash takes 2^cost rounds
+    pub bcrypt_cost: u32,
+    /// Memory argon2id uses in KiB
+    pub argon2_memory: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+impl std::str::FromStr for PasswordAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bcrypt" => Ok(PasswordAlgorithm::Bcrypt),
+            "argon2id" => Ok(PasswordAlgorithm::Argon2id),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Write every email to a file in `outbox_dir`
+    File,
+    /// Deliver emails to an SMTP relay
+    Smtp,
+}
+
+impl std::str::FromStr for MailTransport {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(MailTransport::File),
+            "smtp" => Ok(MailTransport::Smtp),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<SameSite> for actix_web::cookie::SameSite {
+    fn from(same_site: SameSite) -> Self {
+        match same_site {
+            SameSite::Strict => actix_web::cookie::SameSite::Strict,
+            SameSite::Lax => actix_web::cookie::SameSite::Lax,
+            SameSite::None => actix_web::cookie::SameSite::None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database: DatabaseConfig::default(),
+            bind: "127.0.0.1:8080".to_owned(),
+            template_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("templates"),
+            log_level: "nextflix=debug,actix_web=info".to_owned(),
+            cookie: CookieConfig::default(),
+            public_url: "http://127.0.0.1:8080".to_owned(),
+            trusted_proxies: Vec::new(),
+            mail: MailConfig::default(),
+            oidc: OidcConfig::default(),
+            two_factor: TwoFactorConfig::default(),
+            password: PasswordConfig::default(),
+        }
+    }
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        // Recommendations of the OWASP password storage cheat sheet
+        PasswordConfig {
+            algorithm: PasswordAlgorithm::Argon2id,
+            bcrypt_cost: 12,
+            argon2_memory: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+        }
+    }
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            issuer: "nextflix".to_owned(),
+            key_file: PathBuf::from("nextflix-2fa.key"),
+            key: None,
+        }
+    }
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: None,
+            client_id: "nextflix".to_owned(),
+            client_secret: None,
+            name: "Single sign-on".to_owned(),
+            allow_registration: true,
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            from: "nextflix@localhost".to_owned(),
+            transport: MailTransport::File,
+            outbox_dir: PathBuf::from("outbox"),
+            smtp_host: "localhost".to_owned(),
+            smtp_port: 25,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: PathBuf::from("nextflix.db"),
+            cache_capacity: 1024 * 1024 * 1024,
+            temporary: false,
+        }
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "auth-cookie".to_owned(),
+            secure: true,
+            same_site: SameSite::Lax,
+            max_age: None,
+            key_file: PathBuf::from("nextflix.key"),
+            key: None,
+            old_keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Can't read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "Can't parse {}: {}", path.display(), err),
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub const USAGE: &str =
+    "Usage: nextflix [options] [import <imdb|tmdb> <file> | disable-2fa <username> | grant-admin <username>]
+
+Options:
+    --config <file>          Configuration file (default: nextflix.toml)
+    --db <path>              Database directory
+    --cache-capacity <bytes> Database cache size
+    --temporary              Use a temporary database with demo data
+    --bind <addr>            Address to listen on
+    --templates <dir>        Template directory
+    --log-level <filter>     Log filter, e.g. nextflix=debug
+    --cookie-name <name>     Name of the authentication cookie
+    --cookie-secure <bool>   Only send cookies over HTTPS
+    --cookie-same-site <strict|lax|none>
+    --cookie-max-age <secs>  Cookie lifetime
+    --cookie-key-file <file> File containing the cookie signing key
+    --cookie-key <base64>    Cookie signing key
+    --cookie-old-keys <base64,...>
+                             Previous signing keys that are still accepted
+    --public-url <url>       URL of the site, used for links in emails
+    --trusted-proxies <ip,...>
+                             Reverse proxies whose X-Forwarded-For is trusted
+    --mail-from <address>    Sender address of emails
+    --mail-transport <file|smtp>
+    --mail-outbox <dir>      Directory for emails of the file transport
+    --smtp-host <host>       SMTP relay
+    --smtp-port <port>
+    --oidc-issuer <url>      OpenID Connect provider to log in with
+    --oidc-client-id <id>
+    --oidc-client-secret <secret>
+    --oidc-name <name>       Name of the provider shown on the login page
+    --oidc-allow-registration <bool>
+                             Create accounts for unknown identities
+    --2fa-issuer <name>      Name of the site in authenticator apps
+    --2fa-key-file <file>    File containing the key for TOTP secrets
+    --2fa-key <base64>       Key for TOTP secrets
+    --password-algorithm <bcrypt|argon2id>
+                             How new passwords are hashed
+    --bcrypt-cost <cost>
+    --argon2-memory <KiB>
+    --argon2-iterations <n>
+    --argon2-parallelism <n>";
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Invalid(format!("Invalid value for {}: {}", name, value)))
+}
+
+impl Config {
+    /// Loads the configuration for the command line `args` (without the program name).
+    /// Returns the configuration and the remaining positional arguments.
+    pub fn load(args: &[String]) -> Result<(Config, Vec<String>), ConfigError> {
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+        let mut config_file = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(flag) = arg.strip_prefix("--") {
+                if flag == "temporary" {
+                    flags.push(("temporary".to_owned(), "true".to_owned()));
+                    continue;
+                }
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::Invalid(format!("Missing value for --{}", flag)))?;
+                if flag == "config" {
+                    config_file = Some(PathBuf::from(value));
+                } else {
+                    flags.push((flag.to_owned(), value.clone()));
+                }
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+
+        let mut config = match config_file {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        for (flag, value) in flags {
+            config.set(&flag, &value)?;
+        }
+        config.normalize();
+        Ok((config, positional))
+    }
+
+    /// Brings values that can be written in several ways into one form, once all sources are
+    /// merged
+    fn normalize(&mut self) {
+        // Links are built by appending paths that start with a slash
+        self.public_url = self.public_url.trim_end_matches('/').to_owned();
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    /// Applies `NEXTFLIX_<SETTING>` overrides, e.g. `NEXTFLIX_BIND` or `NEXTFLIX_DB`
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        for setting in SETTINGS {
+            let name = format!("NEXTFLIX_{}", setting.to_uppercase().replace('-', "_"));
+            if let Some(value) = var(&name) {
+                self.set(setting, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a single setting by its command line flag name
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), ConfigError> {
+        match setting {
+            "db" => self.database.path = PathBuf::from(value),
+            "cache-capacity" => self.database.cache_capacity = parse(setting, value)?,
+            "temporary" => self.database.temporary = parse(setting, value)?,
+            "bind" => self.bind = value.to_owned(),
+            "templates" => self.template_dir = PathBuf::from(value),
+            "log-level" => self.log_level = value.to_owned(),
+            "cookie-name" => self.cookie.name = value.to_owned(),
+            "cookie-secure" => self.cookie.secure = parse(setting, value)?,
+            "cookie-same-site" => self.cookie.same_site = parse(setting, value)?,
+            "cookie-max-age" => self.cookie.max_age = Some(parse(setting, value)?),
+            "cookie-key-file" => self.cookie.key_file = PathBuf::from(value),
+            "cookie-key" => self.cookie.key = Some(value.to_owned()),
+            "cookie-old-keys" => {
+                self.cookie.old_keys = value
+                    .split(',')
+                    .filter(|k| !k.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            "public-url" => self.public_url = value.to_owned(),
+            "trusted-proxies" => {
+                self.trusted_proxies = value
+                    .split(',')
+                    .filter(|ip| !ip.is_empty())
+                    .map(|ip| parse(setting, ip))
+                    .collect::<Result<_, _>>()?
+            }
+            "mail-from" => self.mail.from = value.to_owned(),
+            "mail-transport" => self.mail.transport = parse(setting, value)?,
+            "mail-outbox" => self.mail.outbox_dir = PathBuf::from(value),
+            "smtp-host" => self.mail.smtp_host = value.to_owned(),
+            "smtp-port" => self.mail.smtp_port = parse(setting, value)?,
+            "oidc-issuer" => self.oidc.issuer = Some(value.to_owned()),
+            "oidc-client-id" => self.oidc.client_id = value.to_owned(),
+            "oidc-client-secret" => self.oidc.client_secret = Some(value.to_owned()),
+            "oidc-name" => self.oidc.name = value.to_owned(),
+            "oidc-allow-registration" => self.oidc.allow_registration = parse(setting, value)?,
+            "2fa-issuer" => self.two_factor.issuer = value.to_owned(),
+            "2fa-key-file" => self.two_factor.key_file = PathBuf::from(value),
+            "2fa-key" => self.two_factor.key = Some(value.to_owned()),
+            "password-algorithm" => self.password.algorithm = parse(setting, value)?,
+            "bcrypt-cost" => self.password.bcrypt_cost = parse(setting, value)?,
+            "argon2-memory" => self.password.argon2_memory = parse(setting, value)?,
+            "argon2-iterations" => self.password.argon2_iterations = parse(setting, value)?,
+            "argon2-parallelism" => self.password.argon2_parallelism = parse(setting, value)?,
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "Unknown option --{}\n\n{}",
+                    setting, USAGE
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn open_db(&self) -> sled::Result<sled::Db> {
+        sled::Config::new()
+            .path(&self.database.path)
+            .cache_capacity(self.database.cache_capacity)
+            .temporary(self.database.temporary)
+            .open()
+    }
+}
+
+const SETTINGS: &[&str] = &[
+    "db",
+    "cache-capacity",
+    "temporary",
+    "bind",
+    "templates",
+    "log-level",
+    "cookie-name",
+    "cookie-secure",
+    "cookie-same-site",
+    "cookie-max-age",
+    "cookie-key-file",
+    "cookie-key",
+    "cookie-old-keys",
+    "public-url",
+    "trusted-proxies",
+    "mail-from",
+    "mail-transport",
+    "mail-outbox",
+    "smtp-host",
+    "smtp-port",
+    "oidc-issuer",
+    "oidc-client-id",
+    "oidc-client-secret",
+    "oidc-name",
+    "oidc-allow-registration",
+    "2fa-issuer",
+    "2fa-key-file",
+    "2fa-key",
+    "password-algorithm",
+    "bcrypt-cost",
+    "argon2-memory",
+    "argon2-iterations",
+    "argon2-parallelism",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind = "0.0.0.0:80"
+            log_level = "warn"
+
+            [database]
+            path = "/var/lib/nextflix"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.database.path, PathBuf::from("/var/lib/nextflix"));
+        assert_eq!(config.cookie, CookieConfig::default());
+
+        config
+            .apply_env(|name| match name {
+                "NEXTFLIX_BIND" => Some("0.0.0.0:8080".to_owned()),
+                "NEXTFLIX_COOKIE_SECURE" => Some("false".to_owned()),
+                "NEXTFLIX_COOKIE_OLD_KEYS" => Some("a2V5MQ==,a2V5Mg==".to_owned()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.bind, "0.0.0.0:8080");
+        assert!(!config.cookie.secure);
+        assert_eq!(config.cookie.old_keys, vec!["a2V5MQ==", "a2V5Mg=="]);
+
+        config.set("log-level", "debug").unwrap();
+        assert_eq!(config.log_level, "debug");
+        assert!(config.set("cache-capacity", "lots").is_err());
+        assert!(config.set("colour", "blue").is_err());
+        config.set("cookie-same-site", "strict").unwrap();
+        assert_eq!(config.cookie.same_site, SameSite::Strict);
+        assert!(config.set("cookie-same-site", "sometimes").is_err());
+        config.set("mail-transport", "smtp").unwrap();
+        assert_eq!(config.mail.transport, MailTransport::Smtp);
+        config.set("trusted-proxies", "127.0.0.1,::1").unwrap();
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert!(config.set("trusted-proxies", "localhost").is_err());
+        // Has to match the `iss` claim exactly
+        config.set("oidc-issuer", "https://id.example/").unwrap();
+        assert_eq!(config.oidc.issuer.as_deref(), Some("https://id.example/"));
+        config.set("2fa-key-file", "/etc/nextflix/2fa.key").unwrap();
+        assert_eq!(
+            config.two_factor.key_file,
+            PathBuf::from("/etc/nextflix/2fa.key")
+        );
+        config.set("password-algorithm", "bcrypt").unwrap();
+        assert_eq!(config.password.algorithm, PasswordAlgorithm::Bcrypt);
+        assert!(config.set("password-algorithm", "md5").is_err());
+    }
+
+    #[test]
+    fn command_line() {
+        let args = [
+            "--bind",
+            "[::1]:8000",
+            "--temporary",
+            "import",
+            "imdb",
+            "x.tsv",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        let (config, positional) = Config::load(&args).unwrap();
+        assert_eq!(config.bind, "[::1]:8000");
+        assert_eq!(config.public_url, "http://127.0.0.1:8080");
+        assert!(config.database.temporary);
+        assert_eq!(positional, vec!["import", "imdb", "x.tsv"]);
+        assert!(Config::load(&["--bind".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn public_url() {
+        let dir = std::env::temp_dir().join(format!("nextflix-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nextflix.toml");
+        std::fs::write(&path, "public_url = \"https://nextflix.example/\"\n").unwrap();
+        let load = |args: &[&str]| {
+            let mut all = vec!["--config".to_owned(), path.to_string_lossy().into_owned()];
+            all.extend(args.iter().map(|s| s.to_string()));
+            Config::load(&all).unwrap().0.public_url
+        };
+        assert_eq!(load(&[]), "https://nextflix.example");
+        assert_eq!(
+            load(&["--public-url", "https://other.example//"]),
+            "https://other.example"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+//! Protection against cross site request forgery.
+//!
+//! Every browser gets a random token in the `csrf_token` cookie. Handlers put the token into
+//! their forms as a hidden `csrf_token` field (see [`CsrfToken`]), and requests that change state
+//! are only let through if the submitted token matches the cookie. Scripts can send the token in
+//! the `X-CSRF-Token` header instead. API requests that are authenticated with a valid bearer
+//! token don't need a CSRF token, since browsers don't send those automatically like cookies.
+
+use crate::{api, api_token, database::*};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::{self, PayloadError},
+    http::{header, Method, StatusCode},
+    web::{Bytes, BytesMut},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures::{
+    future::{ok, LocalBoxFuture, Ready},
+    StreamExt,
+};
+use rand::RngCore;
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+pub const COOKIE_NAME: &str = "csrf_token";
+pub const FIELD_NAME: &str = "csrf_token";
+pub const HEADER_NAME: &str = "x-csrf-token";
+
+/// Form bodies larger than this are rejected
+const MAX_FORM_SIZE: usize = 256 * 1024;
+
+/// The CSRF token of the current request, to be included in rendered forms
+#[derive(Clone, Debug)]
+pub struct CsrfToken(pub String);
+
+impl FromRequest for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<CsrfToken>() {
+            Some(token) => ok(token.clone()),
+            None => futures::future::err(error::ErrorInternalServerError(
+                "CSRF middleware is not installed",
+            )),
+        }
+    }
+}
+
+fn generate_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Middleware that checks the CSRF token of every state changing request. `D` is the database
+/// that bearer tokens are looked up in.
+pub struct Csrf<D> {
+    secure: bool,
+    _db: PhantomData<fn() -> D>,
+}
+
+impl<D> Csrf<D> {
+    /// `secure` sets the `Secure` attribute of the token cookie
+    pub fn new(secure: bool) -> Self {
+        Csrf {
+            secure,
+            _db: PhantomData,
+        }
+    }
+}
+
+impl<S, B, D> Transform<S> for Csrf<D>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    D: DbExt + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S, D>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            secure: self.secure,
+            _db: PhantomData,
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S, D> {
+    service: Rc<RefCell<S>>,
+    secure: bool,
+    _db: PhantomData<fn() -> D>,
+}
+
+/// Whether `req` is an API request with a bearer token that is valid. The API doesn't fall back
+/// to the session cookie for requests with a bearer token, so these can't be forged.
+fn is_bearer_authenticated<D: DbExt + 'static>(req: &ServiceRequest) -> DbResult<bool> {
+    if !req.path().starts_with(api::PREFIX) {
+        return Ok(false);
+    }
+    let token = match api_token::bearer_token(req.headers()) {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+    match req.app_data::<D>() {
+        Some(db) => Ok(api_token::authenticate(&**db, token)?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Reads the whole body of `req` and puts it back, so handlers can still extract it
+async fn read_body(req: &mut ServiceRequest) -> Result<Bytes, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_FORM_SIZE {
+            return Err(error::ErrorPayloadTooLarge("Form too large"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let replay = body.clone();
+    req.set_payload(Payload::Stream(Box::pin(futures::stream::once(
+        async move { Ok::<_, PayloadError>(replay) },
+    ))));
+    Ok(body)
+}
+
+/// Returns the token submitted with the request, either as header or as form field
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if let Some(token) = req.headers().get(HEADER_NAME) {
+        return Ok(token.to_str().ok().map(str::to_owned));
+    }
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(|ct| ct.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+    if !is_form {
+        return Ok(None);
+    }
+    let body = read_body(req).await?;
+    Ok(serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .ok()
+        .and_then(|fields| {
+            fields
+                .into_iter()
+                .find(|(name, _)| name == FIELD_NAME)
+                .map(|(_, value)| value)
+        }))
+}
+
+impl<S, B, D> Service for CsrfMiddleware<S, D>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    D: DbExt + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let secure = self.secure;
+        Box::pin(async move {
+            let cookie_token = req.cookie(COOKIE_NAME).map(|c| c.value().to_owned());
+            let bearer = match is_bearer_authenticated::<D>(&req) {
+                Ok(bearer) => bearer,
+                Err(err) => return Ok(req.error_response(err)),
+            };
+            if !is_safe(req.method()) && !bearer {
+                let submitted = match submitted_token(&mut req).await {
+                    Ok(submitted) => submitted,
+                    Err(err) => return Ok(req.error_response(err)),
+                };
+                let valid = match (&cookie_token, &submitted) {
+                    (Some(expected), Some(submitted)) => {
+                        constant_time_eq(expected.as_bytes(), submitted.as_bytes())
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    return Ok(if req.path().starts_with(api::PREFIX) {
+                        req.error_response(api::ApiError::new(
+                            StatusCode::FORBIDDEN,
+                            "csrf",
+                            "Invalid CSRF token",
+                        ))
+                    } else {
+                        req.error_response(error::ErrorForbidden("Invalid CSRF token"))
+                    });
+                }
+            }
+
+            let new_token = if cookie_token.is_none() {
+                Some(generate_token())
+            } else {
+                None
+            };
+            let token = cookie_token.or_else(|| new_token.clone()).unwrap();
+            req.extensions_mut().insert(CsrfToken(token));
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            if let Some(token) = new_token {
+                let cookie = Cookie::build(COOKIE_NAME, token)
+                    .path("/")
+                    .http_only(true)
+                    .secure(secure)
+                    .same_site(SameSite::Strict)
+                    .finish();
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    async fn form(token: CsrfToken, body: String) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{} {}", token.0, body))
+    }
+
+    #[actix_rt::test]
+    async fn csrf() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf::<MemoryDb>::new(false))
+                .route("/", web::get().to(form))
+                .route("/", web::post().to(form)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == COOKIE_NAME)
+            .unwrap()
+            .into_owned();
+        assert_eq!(
+            test::read_body(resp).await,
+            format!("{} ", cookie.value()).as_bytes()
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .set_form(&[("a", "b")])
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_form(&[("csrf_token", cookie.value())])
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let body = format!("csrf_token={}&a=b", cookie.value());
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .set_payload(body.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            test::read_body(resp).await,
+            format!("{} {}", cookie.value(), body).as_bytes()
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .header(HEADER_NAME, cookie.value())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
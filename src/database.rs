@@ -1,103 +1,2088 @@
-use crate::{fts_tree::*, model::*};
-use sled::transaction::{TransactionError, Transactional};
+use crate::{fts_tree::*, model::*, schema};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+};
+use std::{collections::BTreeMap, convert::TryInto, slice::SliceIndex};
 
 fn serialize_id(id: u64) -> [u8; 8] {
     id.to_le_bytes()
 }
 
-fn deserialize_id<V: AsRef<[u8]>>(id: V) -> u64 {
-    use std::convert::TryInto;
-    u64::from_le_bytes(id.as_ref().try_into().unwrap())
+fn deserialize_id<V: AsRef<[u8]>>(id: V) -> DbResult<u64> {
+    let id = id
+        .as_ref()
+        .try_into()
+        .map_err(|_| DbError::Corruption("Invalid id".to_owned()))?;
+    Ok(u64::from_le_bytes(id))
+}
+
+/// Part of an index key, fails instead of panicking if the key is too short
+fn key_part<I: SliceIndex<[u8], Output = [u8]>>(key: &[u8], range: I) -> DbResult<&[u8]> {
+    key.get(range)
+        .ok_or_else(|| DbError::Corruption("Index key is too short".to_owned()))
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    /// The underlying storage failed
+    Storage(sled::Error),
+    /// A record or index entry could not be read
+    Corruption(String),
+    /// The operation violates a uniqueness constraint
+    Conflict(String),
+    NotFound,
+    /// The record to be stored is invalid
+    Validation(String),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Storage(err) => write!(f, "Storage error: {}", err),
+            DbError::Corruption(msg) => write!(f, "Corrupted data: {}", msg),
+            DbError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            DbError::NotFound => write!(f, "Not found"),
+            DbError::Validation(msg) => write!(f, "Invalid data: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sled::Error> for DbError {
+    fn from(err: sled::Error) -> Self {
+        DbError::Storage(err)
+    }
+}
+
+impl From<bincode::Error> for DbError {
+    fn from(err: bincode::Error) -> Self {
+        DbError::Corruption(err.to_string())
+    }
+}
+
+impl From<TransactionError<DbError>> for DbError {
+    fn from(err: TransactionError<DbError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => DbError::Storage(err),
+        }
+    }
+}
+
+/// Decodes a record inside of a transaction, aborting it if the record is corrupted
+fn decode_tx<T: schema::Record>(data: &[u8]) -> Result<T, ConflictableTransactionError<DbError>> {
+    schema::decode(data).map_err(|err| ConflictableTransactionError::Abort(err.into()))
+}
+
+/// The movies tree and its indexes except for the full text index, in a transaction
+type MovieTrees = (
+    TransactionalTree,
+    TransactionalTree,
+    TransactionalTree,
+    TransactionalTree,
+    TransactionalTree,
+);
+
+/// Inserts the movie and its indexes. Aborts with `Conflict` if a movie with the same IMDb or
+/// TMDb id already exists.
+fn insert_movie_tx(
+    (movies, movies_genre, movies_year, movies_imdb, movies_tmdb): &MovieTrees,
+    id: u64,
+    movie: &Movie,
+) -> Result<(), ConflictableTransactionError<DbError>> {
+    movies.insert(&serialize_id(id), schema::encode(movie))?;
+    for genre in &movie.genres {
+        movies_genre.insert(genre_key(genre, id), &[])?;
+    }
+    if let Some(year) = movie.year {
+        movies_year.insert(year_key(year, id), &[])?;
+    }
+    if let Some(imdb_id) = &movie.imdb_id {
+        if movies_imdb
+            .insert(imdb_id.as_bytes(), &serialize_id(id))?
+            .is_some()
+        {
+            sled::transaction::abort(DbError::Conflict(format!(
+                "Movie {} already exists",
+                imdb_id
+            )))?;
+        }
+    }
+    if let Some(tmdb_id) = movie.tmdb_id {
+        if movies_tmdb
+            .insert(&serialize_id(tmdb_id), &serialize_id(id))?
+            .is_some()
+        {
+            sled::transaction::abort(DbError::Conflict(format!(
+                "Movie with TMDb id {} already exists",
+                tmdb_id
+            )))?;
+        }
+    }
+    Ok(())
 }
 
 pub trait DbExt {
-    type Error;
-    fn add_user(&self, user: &User) -> Result<Option<u64>, Self::Error>;
-    fn get_user(&self, id: u64) -> Result<Option<User>, Self::Error>;
-    fn get_user_by_username(&self, username: &str) -> Result<Option<(u64, User)>, Self::Error>;
-    fn add_movie(&self, movie: &Movie) -> Result<Option<u64>, Self::Error>;
-    fn get_movie(&self, id: u64) -> Result<Option<Movie>, Self::Error>;
-    fn search_movie(&self, query: &str) -> Result<Vec<(Movie, f32)>, Self::Error>;
+    fn add_user(&self, user: &User) -> DbResult<u64>;
+    fn get_user(&self, id: u64) -> DbResult<Option<User>>;
+    fn get_user_by_username(&self, username: &str) -> DbResult<Option<(u64, User)>>;
+    /// Only finds users whose email address is verified
+    fn get_user_by_email(&self, email: &str) -> DbResult<Option<(u64, User)>>;
+    fn get_user_by_identity(&self, issuer: &str, subject: &str) -> DbResult<Option<(u64, User)>>;
+    /// Replaces the user record and updates the username, email and identity indexes. Only for
+    /// tests, handlers use [`DbExt::modify_user`] so that they don't undo concurrent changes.
+    #[cfg(test)]
+    fn update_user(&self, id: u64, user: &User) -> DbResult<()> {
+        self.modify_user(id, |stored| {
+            *stored = user.clone();
+            Ok(())
+        })
+    }
+    /// Changes the stored user with `f` inside of a transaction and updates the indexes. Unlike
+    /// reading the user and writing it back, this doesn't undo changes other requests made in
+    /// between, e.g. added friends. `f` may be called more than once.
+    fn modify_user<T, F: Fn(&mut User) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T>;
+    /// All users, ordered by id
+    fn get_users(&self) -> DbResult<Vec<(u64, User)>>;
+    /// Removes the user together with their sessions, tokens, API tokens, notifications and
+    /// pending emails, and removes them from the friends of all other users and from their
+    /// groups
+    fn remove_user(&self, id: u64) -> DbResult<()>;
+    /// Users whose username or display name matches `query`, best match first. Matching ignores
+    /// case.
+    fn search_users(&self, query: &str) -> DbResult<Vec<(u64, User, f32)>>;
+    /// Adds the movie to the watchlist of `to`, which has to have `from` as a friend. Returns
+    /// false if the friend already recommended the movie.
+    fn recommend_movie(&self, from: u64, to: u64, movie_id: u64) -> DbResult<bool>;
+    /// Lets `friend_id` recommend movies to the user. Returns false if they already were friends.
+    fn add_friend(&self, user_id: u64, friend_id: u64) -> DbResult<bool>;
+    /// Fails with `Conflict` if a movie with the same IMDb or TMDb id already exists
+    fn add_movie(&self, movie: &Movie) -> DbResult<u64>;
+    /// Adds all movies whose IMDb and TMDb ids aren't known yet in one transaction, returns the
+    /// number of movies added
+    fn add_movies(&self, movies: &[Movie]) -> DbResult<u64>;
+    fn get_movie(&self, id: u64) -> DbResult<Option<Movie>>;
+    /// Movies whose name matches `query`, best match first
+    fn search_movie(&self, query: &str) -> DbResult<Vec<(u64, Movie, f32)>>;
+    fn get_movies_by_genre(&self, genre: &str) -> DbResult<Vec<u64>>;
+    fn get_movies_by_year(&self, from: u16, to: u16) -> DbResult<Vec<u64>>;
+    /// Only for tests, the importer relies on [`DbExt::add_movies`] skipping known ids
+    #[cfg(test)]
+    fn get_movie_by_imdb_id(&self, imdb_id: &str) -> DbResult<Option<u64>>;
+    #[cfg(test)]
+    fn get_movie_by_tmdb_id(&self, tmdb_id: u64) -> DbResult<Option<u64>>;
+    /// Removes the movie, also from the watchlists of all users and groups
+    fn remove_movie(&self, id: u64) -> DbResult<()>;
+    fn add_group(&self, group: &Group) -> DbResult<u64>;
+    fn get_group(&self, id: u64) -> DbResult<Option<Group>>;
+    fn get_group_by_invite_code(&self, invite_code: &str) -> DbResult<Option<(u64, Group)>>;
+    /// Replaces the group record and updates the invite code and member indexes. Only for tests,
+    /// handlers use [`DbExt::modify_group`].
+    #[cfg(test)]
+    fn update_group(&self, id: u64, group: &Group) -> DbResult<()> {
+        self.modify_group(id, |stored| {
+            *stored = group.clone();
+            Ok(())
+        })
+    }
+    /// Changes the stored group with `f` inside of a transaction and updates the indexes, like
+    /// [`DbExt::modify_user`]. A group without members afterwards is removed. `f` may be called
+    /// more than once.
+    fn modify_group<T, F: Fn(&mut Group) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T>;
+    /// Groups the user is a member of, ordered by id
+    fn get_groups_by_user(&self, user_id: u64) -> DbResult<Vec<(u64, Group)>>;
+    fn add_session(&self, session_id: &str, session: &Session) -> DbResult<()>;
+    fn get_session(&self, session_id: &str) -> DbResult<Option<Session>>;
+    /// Sets `last_seen` of the session, does nothing if the session doesn't exist
+    fn touch_session(&self, session_id: &str, last_seen: u64) -> DbResult<()>;
+    fn remove_session(&self, session_id: &str) -> DbResult<()>;
+    fn get_sessions_by_user(&self, user_id: u64) -> DbResult<Vec<(String, Session)>>;
+    fn remove_sessions_by_user(&self, user_id: u64) -> DbResult<()>;
+    /// Removes all sessions created before `created_before` or last seen before `seen_before`
+    fn remove_expired_sessions(&self, created_before: u64, seen_before: u64) -> DbResult<()>;
+    fn get_login_attempts(&self, key: &str) -> DbResult<Option<LoginAttempts>>;
+    /// Replaces the entry with the result of `f` in one atomic step and returns it. `None`
+    /// removes the entry. `f` may be called more than once.
+    fn update_login_attempts<F: Fn(Option<LoginAttempts>) -> Option<LoginAttempts>>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> DbResult<Option<LoginAttempts>>;
+    fn remove_login_attempts(&self, key: &str) -> DbResult<()>;
+    /// Removes all entries whose last failure was before `before` and that aren't locked
+    fn remove_expired_login_attempts(&self, before: u64) -> DbResult<()>;
+    fn add_token(&self, token_hash: &str, token: &Token) -> DbResult<()>;
+    fn get_token(&self, token_hash: &str) -> DbResult<Option<Token>>;
+    /// Removes the token and returns it, so that it can only be used once
+    fn take_token(&self, token_hash: &str) -> DbResult<Option<Token>>;
+    fn remove_expired_tokens(&self, before: u64) -> DbResult<()>;
+    fn add_oidc_login(&self, state_hash: &str, login: &OidcLogin) -> DbResult<()>;
+    /// Removes the login and returns it, so that every `state` can only be used once
+    fn take_oidc_login(&self, state_hash: &str) -> DbResult<Option<OidcLogin>>;
+    fn remove_expired_oidc_logins(&self, before: u64) -> DbResult<()>;
+    fn add_api_token(&self, token_hash: &str, token: &ApiToken) -> DbResult<()>;
+    fn get_api_token(&self, token_hash: &str) -> DbResult<Option<ApiToken>>;
+    /// Sets `last_used` of the token, does nothing if the token doesn't exist
+    fn touch_api_token(&self, token_hash: &str, last_used: u64) -> DbResult<()>;
+    fn remove_api_token(&self, token_hash: &str) -> DbResult<()>;
+    fn get_api_tokens_by_user(&self, user_id: u64) -> DbResult<Vec<(String, ApiToken)>>;
+    fn add_to_outbox(&self, entry: &OutboxEntry) -> DbResult<u64>;
+    /// All entries in the order they were added
+    fn get_outbox(&self) -> DbResult<Vec<(u64, OutboxEntry)>>;
+    fn update_outbox(&self, id: u64, entry: &OutboxEntry) -> DbResult<()>;
+    fn remove_from_outbox(&self, id: u64) -> DbResult<()>;
+    fn add_notification(&self, user_id: u64, notification: &Notification) -> DbResult<u64>;
+    /// All notifications of the user, newest first
+    fn get_notifications(&self, user_id: u64) -> DbResult<Vec<(u64, Notification)>>;
+    fn count_unread_notifications(&self, user_id: u64) -> DbResult<usize>;
+    fn mark_notification_read(&self, user_id: u64, id: u64) -> DbResult<()>;
+    fn mark_all_notifications_read(&self, user_id: u64) -> DbResult<()>;
+    /// Number of entries of every tree by name, the trees of all users' notifications are counted
+    /// together
+    fn tree_sizes(&self) -> DbResult<BTreeMap<String, usize>>;
 }
 
-const USERS: &'static [u8] = b"users";
-const USERS_USERNAME: &'static [u8] = b"users_username";
-const MOVIES: &'static [u8] = b"movies";
-const MOVIES_NAME: &'static [u8] = b"movies_name";
+const USERS: &[u8] = b"users";
+const USERS_USERNAME: &[u8] = b"users_username";
+const USERS_EMAIL: &[u8] = b"users_email";
+const USERS_IDENTITY: &[u8] = b"users_identity";
+const USERS_NAME: &[u8] = b"users_name";
+const MOVIES: &[u8] = b"movies";
+const MOVIES_NAME: &[u8] = b"movies_name";
+const MOVIES_GENRE: &[u8] = b"movies_genre";
+const MOVIES_YEAR: &[u8] = b"movies_year";
+const MOVIES_IMDB: &[u8] = b"movies_imdb";
+const MOVIES_TMDB: &[u8] = b"movies_tmdb";
+const GROUPS: &[u8] = b"groups";
+const GROUPS_INVITE: &[u8] = b"groups_invite";
+const GROUPS_MEMBER: &[u8] = b"groups_member";
+const SESSIONS: &[u8] = b"sessions";
+const SESSIONS_USER: &[u8] = b"sessions_user";
+const LOGIN_ATTEMPTS: &[u8] = b"login_attempts";
+const TOKENS: &[u8] = b"tokens";
+const OIDC_LOGINS: &[u8] = b"oidc_logins";
+const API_TOKENS: &[u8] = b"api_tokens";
+const API_TOKENS_USER: &[u8] = b"api_tokens_user";
+const OUTBOX: &[u8] = b"outbox";
 
-impl DbExt for sled::Db {
-    type Error = sled::Error;
+const NOTIFICATIONS_PREFIX: &str = "notifications_";
+
+/// Every user has their own tree of notifications, keyed by big endian id so that they are sorted
+fn notifications_tree(user_id: u64) -> String {
+    format!("{}{}", NOTIFICATIONS_PREFIX, user_id)
+}
+
+/// Index key for `sessions_user` and `api_tokens_user`: user id, then the session id or token
+/// hash
+fn user_session_key(user_id: u64, session_id: &str) -> Vec<u8> {
+    let mut key = serialize_id(user_id).to_vec();
+    key.extend_from_slice(session_id.as_bytes());
+    key
+}
 
-    fn add_user(&self, user: &User) -> sled::Result<Option<u64>> {
+/// Index key for `groups_member`: user id, then group id
+fn group_member_key(user_id: u64, group_id: u64) -> Vec<u8> {
+    let mut key = serialize_id(user_id).to_vec();
+    key.extend_from_slice(&serialize_id(group_id));
+    key
+}
+
+/// Index key for `movies_genre`: lowercased genre, a zero byte, then the movie id
+fn genre_key(genre: &str, id: u64) -> Vec<u8> {
+    let mut key = genre_prefix(genre);
+    key.extend_from_slice(&serialize_id(id));
+    key
+}
+
+fn genre_prefix(genre: &str) -> Vec<u8> {
+    let mut key = genre.to_lowercase().into_bytes();
+    key.push(0);
+    key
+}
+
+/// Index key for `movies_year`: big endian year (so that keys sort by year), then the movie id
+fn year_key(year: u16, id: u64) -> Vec<u8> {
+    let mut key = year.to_be_bytes().to_vec();
+    key.extend_from_slice(&serialize_id(id));
+    key
+}
+
+/// Key of a user in `users_username`, usernames are unique regardless of case
+pub(crate) fn username_key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// Key of a user in `users_email`, only verified addresses are indexed
+pub(crate) fn email_key(user: &User) -> Option<String> {
+    match &user.email {
+        Some(email) if user.email_verified => Some(email.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Key of an identity in `users_identity`: the issuer, a zero byte, then the subject
+fn identity_key(issuer: &str, subject: &str) -> Vec<u8> {
+    let mut key = issuer.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(subject.as_bytes());
+    key
+}
+
+/// Text of a user in the `users_name` search index
+pub(crate) fn user_search_text(user: &User) -> String {
+    match &user.display_name {
+        Some(display_name) => format!("{} {}", user.username, display_name),
+        None => user.username.clone(),
+    }
+    .to_lowercase()
+}
+
+/// Indexes all users in `users_name` again
+pub fn rebuild_user_index(db: &sled::Db) -> sled::Result<()> {
+    let users_name = db.open_fts(USERS_NAME)?;
+    users_name.clear()?;
+    for entry in db.open_tree(USERS)?.iter() {
+        let (key, data) = entry?;
+        let user: User = schema::decode(&data)
+            .map_err(|err| sled::Error::Unsupported(format!("Can't decode user: {}", err)))?;
+        users_name.insert(key, &user_search_text(&user))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_user(user: &User) -> DbResult<()> {
+    if user.username.is_empty() {
+        return Err(DbError::Validation("Username must not be empty".to_owned()));
+    }
+    Ok(())
+}
+
+/// Adds `movie_id` to the movies recommended by `from` to `user`
+pub(crate) fn add_recommendation(user: &mut User, from: u64, movie_id: u64) -> DbResult<bool> {
+    let username = &user.username;
+    let friend_data = match user.friends.get_mut(&from) {
+        Some(friend_data) => friend_data,
+        None => {
+            return Err(DbError::Validation(format!(
+                "{} hasn't added you as a friend",
+                username
+            )))
+        }
+    };
+    if friend_data.movies.contains(&movie_id) {
+        return Ok(false);
+    }
+    friend_data.movies.push(movie_id);
+    Ok(true)
+}
+
+pub(crate) fn validate_group(group: &Group) -> DbResult<()> {
+    if group.name.trim().is_empty() {
+        return Err(DbError::Validation(
+            "Group name must not be empty".to_owned(),
+        ));
+    }
+    if !group.members.contains(&group.owner) {
+        return Err(DbError::Validation(
+            "The owner has to be a member of the group".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the user and their votes from the group. If they owned it, the member that joined
+/// next becomes the owner.
+pub(crate) fn remove_member(group: &mut Group, user_id: u64) {
+    group.members.retain(|member| *member != user_id);
+    for movie in &mut group.movies {
+        movie.votes.retain(|member| *member != user_id);
+    }
+    if group.owner == user_id {
+        if let Some(first) = group.members.first() {
+            group.owner = *first;
+        }
+    }
+}
+
+pub(crate) fn validate_movie(movie: &Movie) -> DbResult<()> {
+    if movie.name.trim().is_empty() {
+        return Err(DbError::Validation(
+            "Movie name must not be empty".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+impl DbExt for sled::Db {
+    fn add_user(&self, user: &User) -> DbResult<u64> {
+        validate_user(user)?;
         let users = self.open_tree(USERS)?;
         let users_username = self.open_tree(USERS_USERNAME)?;
+        let users_email = self.open_tree(USERS_EMAIL)?;
+        let users_identity = self.open_tree(USERS_IDENTITY)?;
         let id = self.generate_id()?;
-        if let Err(err) = (&users, &users_username).transaction(|(users, users_username)| {
-            users.insert(&serialize_id(id), bincode::serialize(user).unwrap())?;
-            if let Some(_) = users_username.insert(user.username.as_bytes(), &serialize_id(id))? {
-                sled::transaction::abort(())?;
-            }
-            Ok(())
-        }) {
-            match err {
-                TransactionError::Storage(e) => return Err(e),
-                TransactionError::Abort(_) => return Ok(None),
-            };
-        }
-        Ok(Some(id))
+        (&users, &users_username, &users_email, &users_identity).transaction(
+            |(users, users_username, users_email, users_identity)| {
+                users.insert(&serialize_id(id), schema::encode(user))?;
+                if users_username
+                    .insert(username_key(&user.username).as_bytes(), &serialize_id(id))?
+                    .is_some()
+                {
+                    sled::transaction::abort(DbError::Conflict(format!(
+                        "Username {} is taken",
+                        user.username
+                    )))?;
+                }
+                if let Some(email) = email_key(user) {
+                    if users_email
+                        .insert(email.as_bytes(), &serialize_id(id))?
+                        .is_some()
+                    {
+                        sled::transaction::abort(DbError::Conflict(
+                            "Email address is already in use".to_owned(),
+                        ))?;
+                    }
+                }
+                for identity in &user.identities {
+                    let key = identity_key(&identity.issuer, &identity.subject);
+                    if users_identity.insert(key, &serialize_id(id))?.is_some() {
+                        sled::transaction::abort(DbError::Conflict(
+                            "Identity is already linked to another user".to_owned(),
+                        ))?;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        self.open_fts(USERS_NAME)?
+            .insert(serialize_id(id), &user_search_text(user))?;
+        Ok(id)
     }
 
-    fn get_user(&self, id: u64) -> sled::Result<Option<User>> {
+    fn get_user(&self, id: u64) -> DbResult<Option<User>> {
         let users = self.open_tree(USERS)?;
-        Ok(users
-            .get(serialize_id(id))?
-            .map(|d| bincode::deserialize(&d).unwrap()))
+        Ok(match users.get(serialize_id(id))? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
     }
 
-    fn get_user_by_username(&self, username: &str) -> sled::Result<Option<(u64, User)>> {
+    fn get_user_by_username(&self, username: &str) -> DbResult<Option<(u64, User)>> {
         let users_username = self.open_tree(USERS_USERNAME)?;
         let users = self.open_tree(USERS)?;
-        if let Some(id) = users_username.get(&username)? {
-            let user =
-                bincode::deserialize(&users.get(&id)?.expect("Bad index users_username")).unwrap();
-            Ok(Some((deserialize_id(id), user)))
+        if let Some(id) = users_username.get(username_key(username))? {
+            let data = users
+                .get(&id)?
+                .ok_or_else(|| DbError::Corruption("Bad index users_username".to_owned()))?;
+            Ok(Some((deserialize_id(id)?, schema::decode(&data)?)))
         } else {
             Ok(None)
         }
     }
 
-    fn add_movie(&self, movie: &Movie) -> sled::Result<Option<u64>> {
+    fn get_user_by_email(&self, email: &str) -> DbResult<Option<(u64, User)>> {
+        let users_email = self.open_tree(USERS_EMAIL)?;
+        let users = self.open_tree(USERS)?;
+        if let Some(id) = users_email.get(email.to_lowercase())? {
+            let data = users
+                .get(&id)?
+                .ok_or_else(|| DbError::Corruption("Bad index users_email".to_owned()))?;
+            Ok(Some((deserialize_id(id)?, schema::decode(&data)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_user_by_identity(&self, issuer: &str, subject: &str) -> DbResult<Option<(u64, User)>> {
+        let users_identity = self.open_tree(USERS_IDENTITY)?;
+        let users = self.open_tree(USERS)?;
+        if let Some(id) = users_identity.get(identity_key(issuer, subject))? {
+            let data = users
+                .get(&id)?
+                .ok_or_else(|| DbError::Corruption("Bad index users_identity".to_owned()))?;
+            Ok(Some((deserialize_id(id)?, schema::decode(&data)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn modify_user<T, F: Fn(&mut User) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T> {
+        let users = self.open_tree(USERS)?;
+        let users_username = self.open_tree(USERS_USERNAME)?;
+        let users_email = self.open_tree(USERS_EMAIL)?;
+        let users_identity = self.open_tree(USERS_IDENTITY)?;
+        let (old, user, result) = (&users, &users_username, &users_email, &users_identity)
+            .transaction(|(users, users_username, users_email, users_identity)| {
+                let old: User = match users.get(serialize_id(id))? {
+                    Some(data) => decode_tx(&data)?,
+                    None => return sled::transaction::abort(DbError::NotFound),
+                };
+                let mut user = old.clone();
+                let result = f(&mut user)
+                    .and_then(|result| validate_user(&user).map(|()| result))
+                    .map_err(ConflictableTransactionError::Abort)?;
+                let user = &user;
+                let (old_username, new_username) =
+                    (username_key(&old.username), username_key(&user.username));
+                if old_username != new_username {
+                    if users_username.get(new_username.as_bytes())?.is_some() {
+                        sled::transaction::abort(DbError::Conflict(format!(
+                            "Username {} is taken",
+                            user.username
+                        )))?;
+                    }
+                    users_username.remove(old_username.as_bytes())?;
+                    users_username.insert(new_username.as_bytes(), &serialize_id(id))?;
+                }
+                let (old_email, new_email) = (email_key(&old), email_key(user));
+                if old_email != new_email {
+                    if let Some(email) = &new_email {
+                        if users_email.get(email.as_bytes())?.is_some() {
+                            sled::transaction::abort(DbError::Conflict(
+                                "Email address is already in use".to_owned(),
+                            ))?;
+                        }
+                    }
+                    if let Some(email) = &old_email {
+                        users_email.remove(email.as_bytes())?;
+                    }
+                    if let Some(email) = &new_email {
+                        users_email.insert(email.as_bytes(), &serialize_id(id))?;
+                    }
+                }
+                for identity in &old.identities {
+                    if !user.identities.contains(identity) {
+                        users_identity.remove(identity_key(&identity.issuer, &identity.subject))?;
+                    }
+                }
+                for identity in &user.identities {
+                    let key = identity_key(&identity.issuer, &identity.subject);
+                    match users_identity.insert(key, &serialize_id(id))? {
+                        Some(other) if other.as_ref() != serialize_id(id).as_ref() => {
+                            sled::transaction::abort(DbError::Conflict(
+                                "Identity is already linked to another user".to_owned(),
+                            ))?
+                        }
+                        _ => {}
+                    }
+                }
+                users.insert(&serialize_id(id), schema::encode(user))?;
+                Ok((old, user.clone(), result))
+            })?;
+        let (old_text, new_text) = (user_search_text(&old), user_search_text(&user));
+        if old_text != new_text {
+            let users_name = self.open_fts(USERS_NAME)?;
+            users_name.remove(serialize_id(id), &old_text)?;
+            users_name.insert(serialize_id(id), &new_text)?;
+        }
+        Ok(result)
+    }
+
+    fn get_users(&self) -> DbResult<Vec<(u64, User)>> {
+        let users = self.open_tree(USERS)?;
+        let mut users = users
+            .iter()
+            .map(|entry| {
+                let (key, data) = entry?;
+                Ok((deserialize_id(key)?, schema::decode(&data)?))
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        // Keys are little endian, so the tree isn't ordered by id
+        users.sort_by_key(|(id, _)| *id);
+        Ok(users)
+    }
+
+    fn remove_user(&self, id: u64) -> DbResult<()> {
+        let users = self.open_tree(USERS)?;
+        let users_username = self.open_tree(USERS_USERNAME)?;
+        let users_email = self.open_tree(USERS_EMAIL)?;
+        let users_identity = self.open_tree(USERS_IDENTITY)?;
+        let user: User = (&users, &users_username, &users_email, &users_identity).transaction(
+            |(users, users_username, users_email, users_identity)| {
+                let user: User = match users.remove(&serialize_id(id))? {
+                    Some(data) => decode_tx(&data)?,
+                    None => return sled::transaction::abort(DbError::NotFound),
+                };
+                users_username.remove(username_key(&user.username).as_bytes())?;
+                if let Some(email) = email_key(&user) {
+                    users_email.remove(email.as_bytes())?;
+                }
+                for identity in &user.identities {
+                    users_identity.remove(identity_key(&identity.issuer, &identity.subject))?;
+                }
+                Ok(user)
+            },
+        )?;
+        // Transactions can't iterate, so the user is removed from friend lists afterwards. That
+        // can't miss anybody, because `add_friend` fails for users that don't exist anymore.
+        // Until then, readers skip friends that are missing.
+        for (other_id, other) in self.get_users()? {
+            if other.friends.contains_key(&id) {
+                match self.modify_user(other_id, |other| Ok(other.friends.remove(&id))) {
+                    Ok(_) | Err(DbError::NotFound) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        self.open_fts(USERS_NAME)?
+            .remove(serialize_id(id), &user_search_text(&user))?;
+        self.remove_sessions_by_user(id)?;
+        for (token_hash, _) in self.get_api_tokens_by_user(id)? {
+            self.remove_api_token(&token_hash)?;
+        }
+        self.drop_tree(notifications_tree(id))?;
+        for (entry_id, entry) in self.get_outbox()? {
+            if entry.user_id == id {
+                self.remove_from_outbox(entry_id)?;
+            }
+        }
+        let tokens = self.open_tree(TOKENS)?;
+        for entry in tokens.iter() {
+            let (key, data) = entry?;
+            let token: Token = schema::decode(&data)?;
+            if token.user_id == id {
+                tokens.remove(key)?;
+            }
+        }
+        for (group_id, _) in self.get_groups_by_user(id)? {
+            self.modify_group(group_id, |group| {
+                remove_member(group, id);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn search_users(&self, query: &str) -> DbResult<Vec<(u64, User, f32)>> {
+        let users = self.open_tree(USERS)?;
+        let users_name = self.open_fts(USERS_NAME)?;
+        // The search index is updated after the transaction that changes the user, so it can
+        // briefly contain users that were just removed
+        let mut results = users_name
+            .query(&query.to_lowercase())?
+            .into_iter()
+            .filter_map(|(d, rank)| match users.get(&d) {
+                Ok(Some(data)) => {
+                    Some(deserialize_id(d).and_then(|id| Ok((id, schema::decode(&data)?, rank))))
+                }
+                Ok(None) => None,
+                Err(err) => Some(Err(err.into())),
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        results.sort_by(|(a_id, _, a), (b_id, _, b)| {
+            b.partial_cmp(a)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a_id.cmp(b_id))
+        });
+        Ok(results)
+    }
+
+    fn recommend_movie(&self, from: u64, to: u64, movie_id: u64) -> DbResult<bool> {
+        let users = self.open_tree(USERS)?;
+        let movies = self.open_tree(MOVIES)?;
+        Ok((&users, &movies).transaction(|(users, movies)| {
+            if movies.get(serialize_id(movie_id))?.is_none() {
+                return sled::transaction::abort(DbError::NotFound);
+            }
+            let mut user: User = match users.get(serialize_id(to))? {
+                Some(data) => decode_tx(&data)?,
+                None => return sled::transaction::abort(DbError::NotFound),
+            };
+            let added = add_recommendation(&mut user, from, movie_id)
+                .map_err(ConflictableTransactionError::Abort)?;
+            if added {
+                users.insert(&serialize_id(to), schema::encode(&user))?;
+            }
+            Ok(added)
+        })?)
+    }
+
+    fn add_friend(&self, user_id: u64, friend_id: u64) -> DbResult<bool> {
+        let users = self.open_tree(USERS)?;
+        Ok(users.transaction(|users| {
+            if user_id == friend_id || users.get(serialize_id(friend_id))?.is_none() {
+                return sled::transaction::abort(DbError::NotFound);
+            }
+            let mut user: User = match users.get(serialize_id(user_id))? {
+                Some(data) => decode_tx(&data)?,
+                None => return sled::transaction::abort(DbError::NotFound),
+            };
+            if user.friends.contains_key(&friend_id) {
+                return Ok(false);
+            }
+            user.friends
+                .insert(friend_id, FriendData { movies: Vec::new() });
+            users.insert(&serialize_id(user_id), schema::encode(&user))?;
+            Ok(true)
+        })?)
+    }
+
+    fn add_movie(&self, movie: &Movie) -> DbResult<u64> {
+        validate_movie(movie)?;
         let movies = self.open_tree(MOVIES)?;
+        let movies_genre = self.open_tree(MOVIES_GENRE)?;
+        let movies_year = self.open_tree(MOVIES_YEAR)?;
+        let movies_imdb = self.open_tree(MOVIES_IMDB)?;
+        let movies_tmdb = self.open_tree(MOVIES_TMDB)?;
         let movies_name = self.open_fts(MOVIES_NAME)?;
         let id = self.generate_id()?;
-        movies.insert(serialize_id(id), bincode::serialize(movie).unwrap())?;
+        (
+            &movies,
+            &movies_genre,
+            &movies_year,
+            &movies_imdb,
+            &movies_tmdb,
+        )
+            .transaction(|trees| insert_movie_tx(trees, id, movie))?;
         movies_name.insert(serialize_id(id), &movie.name)?;
-        // TODO: test if name is unique
-        Ok(Some(id))
+        Ok(id)
     }
 
-    fn get_movie(&self, id: u64) -> sled::Result<Option<Movie>> {
+    fn add_movies(&self, movies: &[Movie]) -> DbResult<u64> {
+        for movie in movies {
+            validate_movie(movie)?;
+        }
+        let new_movies = movies
+            .iter()
+            .map(|movie| Ok((self.generate_id()?, movie)))
+            .collect::<DbResult<Vec<_>>>()?;
         let movies = self.open_tree(MOVIES)?;
-        Ok(movies
-            .get(serialize_id(id))?
-            .map(|d| bincode::deserialize(&d).unwrap()))
+        let movies_genre = self.open_tree(MOVIES_GENRE)?;
+        let movies_year = self.open_tree(MOVIES_YEAR)?;
+        let movies_imdb = self.open_tree(MOVIES_IMDB)?;
+        let movies_tmdb = self.open_tree(MOVIES_TMDB)?;
+        let movies_name = self.open_fts(MOVIES_NAME)?;
+        let added = (
+            &movies,
+            &movies_genre,
+            &movies_year,
+            &movies_imdb,
+            &movies_tmdb,
+        )
+            .transaction(|trees| {
+                let (_, _, _, movies_imdb, movies_tmdb) = trees;
+                let mut added = Vec::new();
+                for (id, movie) in &new_movies {
+                    if let Some(imdb_id) = &movie.imdb_id {
+                        if movies_imdb.get(imdb_id.as_bytes())?.is_some() {
+                            continue;
+                        }
+                    }
+                    if let Some(tmdb_id) = movie.tmdb_id {
+                        if movies_tmdb.get(serialize_id(tmdb_id))?.is_some() {
+                            continue;
+                        }
+                    }
+                    insert_movie_tx(trees, *id, movie)?;
+                    added.push((*id, *movie));
+                }
+                Ok(added)
+            })?;
+        // The full text index has its own transactions
+        for (id, movie) in &added {
+            movies_name.insert(serialize_id(*id), &movie.name)?;
+        }
+        Ok(added.len() as u64)
     }
 
-    fn search_movie(&self, query: &str) -> sled::Result<Vec<(Movie, f32)>> {
+    fn get_movie(&self, id: u64) -> DbResult<Option<Movie>> {
+        let movies = self.open_tree(MOVIES)?;
+        Ok(match movies.get(serialize_id(id))? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn search_movie(&self, query: &str) -> DbResult<Vec<(u64, Movie, f32)>> {
         // TODO: don't rebuild HashMap
         let movies = self.open_tree(MOVIES)?;
         let movies_name = self.open_fts(MOVIES_NAME)?;
 
-        movies_name
+        let mut results = movies_name
             .query(query)?
             .into_iter()
             .map(|(d, rank)| {
+                let data = movies
+                    .get(&d)?
+                    .ok_or_else(|| DbError::Corruption("Bad fts index movies_name".to_owned()))?;
+                Ok((deserialize_id(d)?, schema::decode(&data)?, rank))
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        results.sort_by(|(a_id, _, a), (b_id, _, b)| {
+            b.partial_cmp(a)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a_id.cmp(b_id))
+        });
+        Ok(results)
+    }
+
+    fn get_movies_by_genre(&self, genre: &str) -> DbResult<Vec<u64>> {
+        let movies_genre = self.open_tree(MOVIES_GENRE)?;
+        let prefix = genre_prefix(genre);
+        movies_genre
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| deserialize_id(key_part(&key?, prefix.len()..)?))
+            .collect()
+    }
+
+    fn get_movies_by_year(&self, from: u16, to: u16) -> DbResult<Vec<u64>> {
+        let movies_year = self.open_tree(MOVIES_YEAR)?;
+        movies_year
+            .range(from.to_be_bytes()..)
+            .keys()
+            .map(|key| -> DbResult<(u16, u64)> {
+                let key = key?;
+                let year = key_part(&key, ..2)?;
+                let id = deserialize_id(key_part(&key, 2..)?)?;
+                Ok((u16::from_be_bytes([year[0], year[1]]), id))
+            })
+            .take_while(|res| res.as_ref().map(|(year, _)| *year <= to).unwrap_or(true))
+            .map(|res| res.map(|(_, id)| id))
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn get_movie_by_imdb_id(&self, imdb_id: &str) -> DbResult<Option<u64>> {
+        let movies_imdb = self.open_tree(MOVIES_IMDB)?;
+        movies_imdb.get(imdb_id)?.map(deserialize_id).transpose()
+    }
+
+    #[cfg(test)]
+    fn get_movie_by_tmdb_id(&self, tmdb_id: u64) -> DbResult<Option<u64>> {
+        let movies_tmdb = self.open_tree(MOVIES_TMDB)?;
+        movies_tmdb
+            .get(serialize_id(tmdb_id))?
+            .map(deserialize_id)
+            .transpose()
+    }
+
+    fn remove_movie(&self, id: u64) -> DbResult<()> {
+        let users = self.open_tree(USERS)?;
+        let movies = self.open_tree(MOVIES)?;
+        let movies_name = self.open_fts(MOVIES_NAME)?;
+        let movies_genre = self.open_tree(MOVIES_GENRE)?;
+        let movies_year = self.open_tree(MOVIES_YEAR)?;
+        let movies_imdb = self.open_tree(MOVIES_IMDB)?;
+        let movies_tmdb = self.open_tree(MOVIES_TMDB)?;
+        // Transactions can't iterate, so the users and groups are collected before and after it.
+        // A recommendation or group entry added in between stays, readers skip movies that are
+        // missing.
+        let recommended_to = self
+            .get_users()?
+            .into_iter()
+            .filter(|(_, user)| {
+                user.friends
+                    .values()
+                    .any(|friend_data| friend_data.movies.contains(&id))
+            })
+            .map(|(user_id, _)| user_id)
+            .collect::<Vec<_>>();
+        let movie: Movie = (
+            &users,
+            &movies,
+            &movies_genre,
+            &movies_year,
+            &movies_imdb,
+            &movies_tmdb,
+        )
+            .transaction(
+                |(users, movies, movies_genre, movies_year, movies_imdb, movies_tmdb)| {
+                    let movie: Movie = match movies.remove(&serialize_id(id))? {
+                        Some(data) => decode_tx(&data)?,
+                        None => return sled::transaction::abort(DbError::NotFound),
+                    };
+                    for genre in &movie.genres {
+                        movies_genre.remove(genre_key(genre, id))?;
+                    }
+                    if let Some(year) = movie.year {
+                        movies_year.remove(year_key(year, id))?;
+                    }
+                    if let Some(imdb_id) = &movie.imdb_id {
+                        movies_imdb.remove(imdb_id.as_bytes())?;
+                    }
+                    if let Some(tmdb_id) = movie.tmdb_id {
+                        movies_tmdb.remove(&serialize_id(tmdb_id))?;
+                    }
+                    for user_id in &recommended_to {
+                        if let Some(data) = users.get(serialize_id(*user_id))? {
+                            let mut user: User = decode_tx(&data)?;
+                            for friend_data in user.friends.values_mut() {
+                                friend_data.movies.retain(|movie_id| *movie_id != id);
+                            }
+                            users.insert(&serialize_id(*user_id), schema::encode(&user))?;
+                        }
+                    }
+                    Ok(movie)
+                },
+            )?;
+        movies_name.remove(serialize_id(id), &movie.name)?;
+        let groups = self.open_tree(GROUPS)?;
+        for entry in groups.iter() {
+            let (key, data) = entry?;
+            let group: Group = schema::decode(&data)?;
+            if group.movies.iter().any(|movie| movie.movie_id == id) {
+                let result = self.modify_group(deserialize_id(key)?, |group| {
+                    group.movies.retain(|movie| movie.movie_id != id);
+                    Ok(())
+                });
+                match result {
+                    Ok(()) | Err(DbError::NotFound) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_group(&self, group: &Group) -> DbResult<u64> {
+        validate_group(group)?;
+        let groups = self.open_tree(GROUPS)?;
+        let groups_invite = self.open_tree(GROUPS_INVITE)?;
+        let groups_member = self.open_tree(GROUPS_MEMBER)?;
+        let id = self.generate_id()?;
+        (&groups, &groups_invite, &groups_member).transaction(
+            |(groups, groups_invite, groups_member)| {
+                groups.insert(&serialize_id(id), schema::encode(group))?;
+                if groups_invite
+                    .insert(group.invite_code.as_bytes(), &serialize_id(id))?
+                    .is_some()
+                {
+                    sled::transaction::abort(DbError::Conflict(
+                        "Duplicate invite code".to_owned(),
+                    ))?;
+                }
+                for member in &group.members {
+                    groups_member.insert(group_member_key(*member, id), &[])?;
+                }
+                Ok(())
+            },
+        )?;
+        Ok(id)
+    }
+
+    fn get_group(&self, id: u64) -> DbResult<Option<Group>> {
+        let groups = self.open_tree(GROUPS)?;
+        Ok(match groups.get(serialize_id(id))? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn get_group_by_invite_code(&self, invite_code: &str) -> DbResult<Option<(u64, Group)>> {
+        let groups_invite = self.open_tree(GROUPS_INVITE)?;
+        let groups = self.open_tree(GROUPS)?;
+        if let Some(id) = groups_invite.get(invite_code)? {
+            let data = groups
+                .get(&id)?
+                .ok_or_else(|| DbError::Corruption("Bad index groups_invite".to_owned()))?;
+            Ok(Some((deserialize_id(id)?, schema::decode(&data)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn modify_group<T, F: Fn(&mut Group) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T> {
+        let groups = self.open_tree(GROUPS)?;
+        let groups_invite = self.open_tree(GROUPS_INVITE)?;
+        let groups_member = self.open_tree(GROUPS_MEMBER)?;
+        Ok((&groups, &groups_invite, &groups_member).transaction(
+            |(groups, groups_invite, groups_member)| {
+                let old: Group = match groups.get(serialize_id(id))? {
+                    Some(data) => decode_tx(&data)?,
+                    None => return sled::transaction::abort(DbError::NotFound),
+                };
+                let mut group = old.clone();
+                let result = f(&mut group).map_err(ConflictableTransactionError::Abort)?;
+                if group.members.is_empty() {
+                    groups.remove(&serialize_id(id))?;
+                    groups_invite.remove(old.invite_code.as_bytes())?;
+                    for member in &old.members {
+                        groups_member.remove(group_member_key(*member, id))?;
+                    }
+                    return Ok(result);
+                }
+                validate_group(&group).map_err(ConflictableTransactionError::Abort)?;
+                if old.invite_code != group.invite_code {
+                    if groups_invite.get(group.invite_code.as_bytes())?.is_some() {
+                        sled::transaction::abort(DbError::Conflict(
+                            "Duplicate invite code".to_owned(),
+                        ))?;
+                    }
+                    groups_invite.remove(old.invite_code.as_bytes())?;
+                    groups_invite.insert(group.invite_code.as_bytes(), &serialize_id(id))?;
+                }
+                for member in &old.members {
+                    if !group.members.contains(member) {
+                        groups_member.remove(group_member_key(*member, id))?;
+                    }
+                }
+                for member in &group.members {
+                    groups_member.insert(group_member_key(*member, id), &[])?;
+                }
+                groups.insert(&serialize_id(id), schema::encode(&group))?;
+                Ok(result)
+            },
+        )?)
+    }
+
+    fn get_groups_by_user(&self, user_id: u64) -> DbResult<Vec<(u64, Group)>> {
+        let groups_member = self.open_tree(GROUPS_MEMBER)?;
+        let groups = self.open_tree(GROUPS)?;
+        let mut result = groups_member
+            .scan_prefix(serialize_id(user_id))
+            .keys()
+            .map(|key| {
+                let key = key?;
+                let id = key_part(&key, 8..)?;
+                let data = groups
+                    .get(id)?
+                    .ok_or_else(|| DbError::Corruption("Bad index groups_member".to_owned()))?;
+                Ok((deserialize_id(id)?, schema::decode(&data)?))
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        result.sort_by_key(|(id, _)| *id);
+        Ok(result)
+    }
+
+    fn add_session(&self, session_id: &str, session: &Session) -> DbResult<()> {
+        let sessions = self.open_tree(SESSIONS)?;
+        let sessions_user = self.open_tree(SESSIONS_USER)?;
+        (&sessions, &sessions_user).transaction(|(sessions, sessions_user)| {
+            if sessions
+                .insert(session_id.as_bytes(), schema::encode(session))?
+                .is_some()
+            {
+                sled::transaction::abort(DbError::Conflict("Duplicate session id".to_owned()))?;
+            }
+            sessions_user.insert(user_session_key(session.user_id, session_id), &[])?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &str) -> DbResult<Option<Session>> {
+        let sessions = self.open_tree(SESSIONS)?;
+        Ok(match sessions.get(session_id)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn touch_session(&self, session_id: &str, last_seen: u64) -> DbResult<()> {
+        let sessions = self.open_tree(SESSIONS)?;
+        sessions.transaction(|sessions| {
+            if let Some(data) = sessions.get(session_id)? {
+                let mut session: Session = decode_tx(&data)?;
+                session.last_seen = last_seen;
+                sessions.insert(session_id.as_bytes(), schema::encode(&session))?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn remove_session(&self, session_id: &str) -> DbResult<()> {
+        let sessions = self.open_tree(SESSIONS)?;
+        let sessions_user = self.open_tree(SESSIONS_USER)?;
+        (&sessions, &sessions_user).transaction(|(sessions, sessions_user)| {
+            if let Some(data) = sessions.remove(session_id.as_bytes())? {
+                let session: Session = decode_tx(&data)?;
+                sessions_user.remove(user_session_key(session.user_id, session_id))?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn get_sessions_by_user(&self, user_id: u64) -> DbResult<Vec<(String, Session)>> {
+        let sessions = self.open_tree(SESSIONS)?;
+        let sessions_user = self.open_tree(SESSIONS_USER)?;
+        sessions_user
+            .scan_prefix(serialize_id(user_id))
+            .keys()
+            .map(|key| {
+                let key = key?;
+                let session_id = key_part(&key, 8..)?;
+                let data = sessions
+                    .get(session_id)?
+                    .ok_or_else(|| DbError::Corruption("Bad index sessions_user".to_owned()))?;
                 Ok((
-                    bincode::deserialize(&movies.get(d)?.expect("Bad fts index movies_name"))
-                        .unwrap(),
-                    rank,
+                    String::from_utf8_lossy(session_id).into_owned(),
+                    schema::decode(&data)?,
                 ))
             })
             .collect()
     }
+
+    fn remove_sessions_by_user(&self, user_id: u64) -> DbResult<()> {
+        for (session_id, _) in self.get_sessions_by_user(user_id)? {
+            self.remove_session(&session_id)?;
+        }
+        Ok(())
+    }
+
+    fn remove_expired_sessions(&self, created_before: u64, seen_before: u64) -> DbResult<()> {
+        let sessions = self.open_tree(SESSIONS)?;
+        for entry in sessions.iter() {
+            let (key, data) = entry?;
+            let session: Session = schema::decode(&data)?;
+            if session.created < created_before || session.last_seen < seen_before {
+                self.remove_session(&String::from_utf8_lossy(&key))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_login_attempts(&self, key: &str) -> DbResult<Option<LoginAttempts>> {
+        let login_attempts = self.open_tree(LOGIN_ATTEMPTS)?;
+        Ok(match login_attempts.get(key)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn update_login_attempts<F: Fn(Option<LoginAttempts>) -> Option<LoginAttempts>>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> DbResult<Option<LoginAttempts>> {
+        let login_attempts = self.open_tree(LOGIN_ATTEMPTS)?;
+        Ok(login_attempts.transaction(|login_attempts| {
+            let attempts = match login_attempts.get(key)? {
+                Some(data) => Some(decode_tx(&data)?),
+                None => None,
+            };
+            let attempts = f(attempts);
+            match &attempts {
+                Some(attempts) => login_attempts.insert(key, schema::encode(attempts))?,
+                None => login_attempts.remove(key)?,
+            };
+            Ok(attempts)
+        })?)
+    }
+
+    fn remove_login_attempts(&self, key: &str) -> DbResult<()> {
+        let login_attempts = self.open_tree(LOGIN_ATTEMPTS)?;
+        login_attempts.remove(key)?;
+        Ok(())
+    }
+
+    fn remove_expired_login_attempts(&self, before: u64) -> DbResult<()> {
+        let login_attempts = self.open_tree(LOGIN_ATTEMPTS)?;
+        for entry in login_attempts.iter() {
+            let (key, data) = entry?;
+            let attempts: LoginAttempts = schema::decode(&data)?;
+            if attempts.last_failure < before && attempts.locked_until < before {
+                login_attempts.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_token(&self, token_hash: &str, token: &Token) -> DbResult<()> {
+        let tokens = self.open_tree(TOKENS)?;
+        tokens.insert(token_hash, schema::encode(token))?;
+        Ok(())
+    }
+
+    fn get_token(&self, token_hash: &str) -> DbResult<Option<Token>> {
+        let tokens = self.open_tree(TOKENS)?;
+        Ok(match tokens.get(token_hash)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn take_token(&self, token_hash: &str) -> DbResult<Option<Token>> {
+        let tokens = self.open_tree(TOKENS)?;
+        Ok(match tokens.remove(token_hash)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn remove_expired_tokens(&self, before: u64) -> DbResult<()> {
+        let tokens = self.open_tree(TOKENS)?;
+        for entry in tokens.iter() {
+            let (key, data) = entry?;
+            let token: Token = schema::decode(&data)?;
+            if token.expires < before {
+                tokens.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_oidc_login(&self, state_hash: &str, login: &OidcLogin) -> DbResult<()> {
+        let oidc_logins = self.open_tree(OIDC_LOGINS)?;
+        oidc_logins.insert(state_hash, schema::encode(login))?;
+        Ok(())
+    }
+
+    fn take_oidc_login(&self, state_hash: &str) -> DbResult<Option<OidcLogin>> {
+        let oidc_logins = self.open_tree(OIDC_LOGINS)?;
+        Ok(match oidc_logins.remove(state_hash)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn remove_expired_oidc_logins(&self, before: u64) -> DbResult<()> {
+        let oidc_logins = self.open_tree(OIDC_LOGINS)?;
+        for entry in oidc_logins.iter() {
+            let (key, data) = entry?;
+            let login: OidcLogin = schema::decode(&data)?;
+            if login.expires < before {
+                oidc_logins.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_api_token(&self, token_hash: &str, token: &ApiToken) -> DbResult<()> {
+        let api_tokens = self.open_tree(API_TOKENS)?;
+        let api_tokens_user = self.open_tree(API_TOKENS_USER)?;
+        (&api_tokens, &api_tokens_user).transaction(|(api_tokens, api_tokens_user)| {
+            if api_tokens
+                .insert(token_hash.as_bytes(), schema::encode(token))?
+                .is_some()
+            {
+                sled::transaction::abort(DbError::Conflict("Duplicate token".to_owned()))?;
+            }
+            api_tokens_user.insert(user_session_key(token.user_id, token_hash), &[])?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn get_api_token(&self, token_hash: &str) -> DbResult<Option<ApiToken>> {
+        let api_tokens = self.open_tree(API_TOKENS)?;
+        Ok(match api_tokens.get(token_hash)? {
+            Some(d) => Some(schema::decode(&d)?),
+            None => None,
+        })
+    }
+
+    fn touch_api_token(&self, token_hash: &str, last_used: u64) -> DbResult<()> {
+        let api_tokens = self.open_tree(API_TOKENS)?;
+        api_tokens.transaction(|api_tokens| {
+            if let Some(data) = api_tokens.get(token_hash)? {
+                let mut token: ApiToken = decode_tx(&data)?;
+                token.last_used = Some(last_used);
+                api_tokens.insert(token_hash.as_bytes(), schema::encode(&token))?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn remove_api_token(&self, token_hash: &str) -> DbResult<()> {
+        let api_tokens = self.open_tree(API_TOKENS)?;
+        let api_tokens_user = self.open_tree(API_TOKENS_USER)?;
+        (&api_tokens, &api_tokens_user).transaction(|(api_tokens, api_tokens_user)| {
+            if let Some(data) = api_tokens.remove(token_hash.as_bytes())? {
+                let token: ApiToken = decode_tx(&data)?;
+                api_tokens_user.remove(user_session_key(token.user_id, token_hash))?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn get_api_tokens_by_user(&self, user_id: u64) -> DbResult<Vec<(String, ApiToken)>> {
+        let api_tokens = self.open_tree(API_TOKENS)?;
+        let api_tokens_user = self.open_tree(API_TOKENS_USER)?;
+        api_tokens_user
+            .scan_prefix(serialize_id(user_id))
+            .keys()
+            .map(|key| {
+                let key = key?;
+                let token_hash = key_part(&key, 8..)?;
+                let data = api_tokens
+                    .get(token_hash)?
+                    .ok_or_else(|| DbError::Corruption("Bad index api_tokens_user".to_owned()))?;
+                Ok((
+                    String::from_utf8_lossy(token_hash).into_owned(),
+                    schema::decode(&data)?,
+                ))
+            })
+            .collect()
+    }
+
+    fn add_to_outbox(&self, entry: &OutboxEntry) -> DbResult<u64> {
+        let outbox = self.open_tree(OUTBOX)?;
+        let id = self.generate_id()?;
+        outbox.insert(serialize_id(id), schema::encode(entry))?;
+        Ok(id)
+    }
+
+    fn get_outbox(&self) -> DbResult<Vec<(u64, OutboxEntry)>> {
+        let outbox = self.open_tree(OUTBOX)?;
+        let mut entries = outbox
+            .iter()
+            .map(|entry| {
+                let (key, data) = entry?;
+                Ok((deserialize_id(key)?, schema::decode(&data)?))
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        // Keys are little endian, so the tree isn't ordered by id
+        entries.sort_by_key(|(id, _)| *id);
+        Ok(entries)
+    }
+
+    fn update_outbox(&self, id: u64, entry: &OutboxEntry) -> DbResult<()> {
+        let outbox = self.open_tree(OUTBOX)?;
+        outbox.insert(serialize_id(id), schema::encode(entry))?;
+        Ok(())
+    }
+
+    fn remove_from_outbox(&self, id: u64) -> DbResult<()> {
+        let outbox = self.open_tree(OUTBOX)?;
+        outbox.remove(serialize_id(id))?;
+        Ok(())
+    }
+
+    fn add_notification(&self, user_id: u64, notification: &Notification) -> DbResult<u64> {
+        let notifications = self.open_tree(notifications_tree(user_id))?;
+        let id = self.generate_id()?;
+        notifications.insert(id.to_be_bytes(), schema::encode(notification))?;
+        Ok(id)
+    }
+
+    fn get_notifications(&self, user_id: u64) -> DbResult<Vec<(u64, Notification)>> {
+        let notifications = self.open_tree(notifications_tree(user_id))?;
+        notifications
+            .iter()
+            .rev()
+            .map(|entry| {
+                let (key, data) = entry?;
+                let id = u64::from_be_bytes(key.as_ref().try_into().map_err(|_| {
+                    DbError::Corruption("Bad key in notifications tree".to_owned())
+                })?);
+                Ok((id, schema::decode(&data)?))
+            })
+            .collect()
+    }
+
+    fn count_unread_notifications(&self, user_id: u64) -> DbResult<usize> {
+        let notifications = self.open_tree(notifications_tree(user_id))?;
+        let mut count = 0;
+        for data in notifications.iter().values() {
+            let notification: Notification = schema::decode(&data?)?;
+            count += !notification.read as usize;
+        }
+        Ok(count)
+    }
+
+    fn mark_notification_read(&self, user_id: u64, id: u64) -> DbResult<()> {
+        let notifications = self.open_tree(notifications_tree(user_id))?;
+        notifications.transaction(|notifications| {
+            let mut notification: Notification = match notifications.get(id.to_be_bytes())? {
+                Some(data) => decode_tx(&data)?,
+                None => return sled::transaction::abort(DbError::NotFound),
+            };
+            notification.read = true;
+            notifications.insert(&id.to_be_bytes(), schema::encode(&notification))?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn mark_all_notifications_read(&self, user_id: u64) -> DbResult<()> {
+        for (id, notification) in self.get_notifications(user_id)? {
+            if !notification.read {
+                self.mark_notification_read(user_id, id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tree_sizes(&self) -> DbResult<BTreeMap<String, usize>> {
+        let mut sizes = BTreeMap::new();
+        for name in self.tree_names() {
+            // The default tree only holds the schema version and the id counter
+            if name == self.name() {
+                continue;
+            }
+            let len = self.open_tree(&name)?.len();
+            let name = String::from_utf8_lossy(&name);
+            let name = if name.starts_with(NOTIFICATIONS_PREFIX) {
+                "notifications".to_owned()
+            } else {
+                name.into_owned()
+            };
+            *sizes.entry(name).or_default() += len;
+        }
+        Ok(sizes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movie_indexes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let pulp_fiction = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                year: Some(1994),
+                genres: vec!["Crime".to_owned(), "Drama".to_owned()],
+                ..Default::default()
+            })
+            .unwrap();
+        let jackie_brown = db
+            .add_movie(&Movie {
+                name: "Jackie Brown".to_owned(),
+                year: Some(1997),
+                genres: vec!["Crime".to_owned()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            db.get_movies_by_genre("crime").unwrap(),
+            vec![pulp_fiction, jackie_brown]
+        );
+        assert_eq!(db.get_movies_by_genre("Drama").unwrap(), vec![pulp_fiction]);
+        assert_eq!(db.get_movies_by_genre("Dram").unwrap(), Vec::<u64>::new());
+        assert_eq!(
+            db.get_movies_by_year(1990, 1995).unwrap(),
+            vec![pulp_fiction]
+        );
+        assert_eq!(
+            db.get_movies_by_year(1997, 1997).unwrap(),
+            vec![jackie_brown]
+        );
+        assert_eq!(
+            db.get_movies_by_year(2000, 2010).unwrap(),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn duplicate_movie() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                imdb_id: Some("tt0110912".to_owned()),
+                tmdb_id: Some(680),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(matches!(
+            db.add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                imdb_id: Some("tt0110912".to_owned()),
+                ..Default::default()
+            }),
+            Err(DbError::Conflict(_))
+        ));
+        assert!(matches!(
+            db.add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                tmdb_id: Some(680),
+                ..Default::default()
+            }),
+            Err(DbError::Conflict(_))
+        ));
+        assert_eq!(db.get_movie_by_imdb_id("tt0110912").unwrap(), Some(id));
+        assert_eq!(db.get_movie_by_tmdb_id(680).unwrap(), Some(id));
+        assert_eq!(db.open_tree(MOVIES).unwrap().len(), 1);
+
+        // Bulk inserts skip known ids, also the ones earlier in the same batch
+        let movie = |name: &str, imdb_id: &str| Movie {
+            name: name.to_owned(),
+            imdb_id: Some(imdb_id.to_owned()),
+            ..Default::default()
+        };
+        let added = db
+            .add_movies(&[
+                movie("Pulp Fiction", "tt0110912"),
+                movie("Jackie Brown", "tt0119396"),
+                movie("Jackie Brown", "tt0119396"),
+            ])
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(db.open_tree(MOVIES).unwrap().len(), 2);
+        assert_eq!(db.search_movie("Jackie").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn user_errors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let user = |username: &str| User {
+            username: username.to_owned(),
+            ..Default::default()
+        };
+        let id = db.add_user(&user("foo")).unwrap();
+        assert!(matches!(
+            db.add_user(&user("foo")),
+            Err(DbError::Conflict(_))
+        ));
+        assert!(matches!(
+            db.add_user(&user("")),
+            Err(DbError::Validation(_))
+        ));
+        assert!(matches!(
+            db.add_user(&user("FOO")),
+            Err(DbError::Conflict(_))
+        ));
+        assert_eq!(db.get_user_by_username("fOo").unwrap().unwrap().0, id);
+
+        db.open_tree(USERS)
+            .unwrap()
+            .insert(serialize_id(id), b"garbage".as_ref())
+            .unwrap();
+        assert!(matches!(db.get_user(id), Err(DbError::Corruption(_))));
+    }
+
+    #[test]
+    fn corrupt_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree(MOVIES_IMDB)
+            .unwrap()
+            .insert("tt0110912", b"short".as_ref())
+            .unwrap();
+        assert!(matches!(
+            db.get_movie_by_imdb_id("tt0110912"),
+            Err(DbError::Corruption(_))
+        ));
+        db.open_tree(MOVIES_YEAR)
+            .unwrap()
+            .insert(b"\x07".as_ref(), b"".as_ref())
+            .unwrap();
+        assert!(matches!(
+            db.get_movies_by_year(0, 3000),
+            Err(DbError::Corruption(_))
+        ));
+        db.open_tree(GROUPS_MEMBER)
+            .unwrap()
+            .insert(serialize_id(1), b"".as_ref())
+            .unwrap();
+        assert!(matches!(
+            db.get_groups_by_user(1),
+            Err(DbError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn update_user_indexes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                email: Some("Alice@example.com".to_owned()),
+                email_verified: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let bob = db
+            .add_user(&User {
+                username: "bob".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            db.get_user_by_email("alice@EXAMPLE.com")
+                .unwrap()
+                .unwrap()
+                .0,
+            alice
+        );
+
+        let mut user = db.get_user(bob).unwrap().unwrap();
+        user.email = Some("alice@example.com".to_owned());
+        db.update_user(bob, &user).unwrap();
+        user.email_verified = true;
+        assert!(matches!(
+            db.update_user(bob, &user),
+            Err(DbError::Conflict(_))
+        ));
+        user.email = Some("bob@example.com".to_owned());
+        user.username = "Robert".to_owned();
+        db.update_user(bob, &user).unwrap();
+        assert!(db.get_user_by_username("bob").unwrap().is_none());
+        assert_eq!(db.get_user_by_username("robert").unwrap().unwrap().0, bob);
+        assert_eq!(
+            db.get_user_by_email("bob@example.com").unwrap().unwrap().0,
+            bob
+        );
+        user.username = "ALICE".to_owned();
+        assert!(matches!(
+            db.update_user(bob, &user),
+            Err(DbError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn recommendations() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let bob = db
+            .add_user(&User {
+                username: "bob".to_owned(),
+                friends: vec![(alice, FriendData { movies: vec![] })]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(db.recommend_movie(alice, bob, movie_id).unwrap());
+        assert!(!db.recommend_movie(alice, bob, movie_id).unwrap());
+        assert_eq!(
+            db.get_user(bob).unwrap().unwrap().friends[&alice].movies,
+            vec![movie_id]
+        );
+        assert!(matches!(
+            db.recommend_movie(bob, alice, movie_id),
+            Err(DbError::Validation(_))
+        ));
+        assert!(matches!(
+            db.recommend_movie(alice, bob, 1000),
+            Err(DbError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn notifications() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let notification = |from| Notification {
+            event: Event::FriendRequest { from },
+            created: 1,
+            read: false,
+        };
+        let first = db.add_notification(1, &notification(2)).unwrap();
+        let second = db.add_notification(1, &notification(3)).unwrap();
+        db.add_notification(2, &notification(1)).unwrap();
+        let ids = db
+            .get_notifications(1)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![second, first]);
+        db.mark_notification_read(1, first).unwrap();
+        assert_eq!(db.count_unread_notifications(1).unwrap(), 1);
+        assert!(matches!(
+            db.mark_notification_read(2, first),
+            Err(DbError::NotFound)
+        ));
+        db.mark_all_notifications_read(1).unwrap();
+        assert_eq!(db.count_unread_notifications(1).unwrap(), 0);
+        assert_eq!(db.count_unread_notifications(2).unwrap(), 1);
+    }
+
+    #[test]
+    fn sessions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let session = |user_id| Session {
+            user_id,
+            created: 1,
+            last_seen: 1,
+            user_agent: None,
+        };
+        db.add_session("a", &session(1)).unwrap();
+        db.add_session("b", &session(1)).unwrap();
+        db.add_session("c", &session(2)).unwrap();
+        assert!(matches!(
+            db.add_session("a", &session(2)),
+            Err(DbError::Conflict(_))
+        ));
+        db.touch_session("a", 5).unwrap();
+        assert_eq!(db.get_session("a").unwrap().unwrap().last_seen, 5);
+        let ids = |user_id| {
+            db.get_sessions_by_user(user_id)
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(1), vec!["a", "b"]);
+        db.remove_session("a").unwrap();
+        assert_eq!(ids(1), vec!["b"]);
+        db.remove_sessions_by_user(1).unwrap();
+        assert_eq!(ids(1), Vec::<String>::new());
+        assert_eq!(ids(2), vec!["c"]);
+
+        db.add_session("d", &session(2)).unwrap();
+        db.touch_session("d", 10).unwrap();
+        db.remove_expired_sessions(0, 5).unwrap();
+        assert_eq!(ids(2), vec!["d"]);
+        db.remove_expired_sessions(2, 0).unwrap();
+        assert_eq!(ids(2), Vec::<String>::new());
+    }
+
+    #[test]
+    fn remove_user() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                email: Some("alice@example.com".to_owned()),
+                email_verified: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let bob = db
+            .add_user(&User {
+                username: "bob".to_owned(),
+                friends: vec![(alice, FriendData { movies: vec![] })]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        let token = |user_id| Token {
+            purpose: TokenPurpose::PasswordReset,
+            user_id,
+            expires: u64::MAX,
+            email: String::new(),
+        };
+        db.add_token("a", &token(alice)).unwrap();
+        db.add_token("b", &token(bob)).unwrap();
+        let entry = |user_id| OutboxEntry {
+            user_id,
+            event: Event::FriendRequest { from: 0 },
+            created: 0,
+            attempts: 0,
+            next_attempt: 0,
+        };
+        db.add_to_outbox(&entry(alice)).unwrap();
+        let kept = db.add_to_outbox(&entry(bob)).unwrap();
+        db.remove_user(alice).unwrap();
+        assert!(db.get_user(alice).unwrap().is_none());
+        assert!(db.get_user_by_username("alice").unwrap().is_none());
+        assert!(db.get_user_by_email("alice@example.com").unwrap().is_none());
+        assert!(db.get_user(bob).unwrap().unwrap().friends.is_empty());
+        assert!(db.get_token("a").unwrap().is_none());
+        assert!(db.get_token("b").unwrap().is_some());
+        let outbox = db.get_outbox().unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].0, kept);
+        assert!(matches!(db.remove_user(alice), Err(DbError::NotFound)));
+        // The name can be taken again
+        db.add_user(&User {
+            username: "alice".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn modify_user() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let bob = db
+            .add_user(&User {
+                username: "bob".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        // A friend added after the user was read isn't lost
+        let stale = db.get_user(alice).unwrap().unwrap();
+        db.add_friend(alice, bob).unwrap();
+        let username = db
+            .modify_user(alice, |user| {
+                user.role = Role::Admin;
+                Ok(user.username.clone())
+            })
+            .unwrap();
+        assert_eq!(username, stale.username);
+        let user = db.get_user(alice).unwrap().unwrap();
+        assert_eq!(user.role, Role::Admin);
+        assert!(user.friends.contains_key(&bob));
+
+        // Errors of the closure and index conflicts abort without changing anything
+        assert!(matches!(
+            db.modify_user(alice, |_| Err::<(), _>(DbError::NotFound)),
+            Err(DbError::NotFound)
+        ));
+        assert!(matches!(
+            db.modify_user(alice, |user| {
+                user.username = "BOB".to_owned();
+                user.role = Role::User;
+                Ok(())
+            }),
+            Err(DbError::Conflict(_))
+        ));
+        assert_eq!(db.get_user(alice).unwrap().unwrap().role, Role::Admin);
+    }
+
+    #[test]
+    fn user_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                display_name: Some("Alice Smith".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        let ids = |query: &str| {
+            db.search_users(query)
+                .unwrap()
+                .into_iter()
+                .map(|(id, _, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("smith"), vec![alice]);
+        assert_eq!(ids("ALICE"), vec![alice]);
+        assert_eq!(ids("smi*"), vec![alice]);
+        let mut user = db.get_user(alice).unwrap().unwrap();
+        user.username = "ally".to_owned();
+        user.display_name = Some("Alice Jones".to_owned());
+        db.update_user(alice, &user).unwrap();
+        assert_eq!(ids("smith"), Vec::<u64>::new());
+        assert_eq!(ids("jones"), vec![alice]);
+        assert_eq!(ids("ally"), vec![alice]);
+        db.remove_user(alice).unwrap();
+        assert_eq!(ids("jones"), Vec::<u64>::new());
+
+        // Entries of users that are already gone are skipped
+        db.open_fts(USERS_NAME)
+            .unwrap()
+            .insert(serialize_id(alice), "ally")
+            .unwrap();
+        assert_eq!(ids("ally"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn groups() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut group = Group {
+            name: "Movie night".to_owned(),
+            owner: 1,
+            members: vec![1, 2],
+            invite_code: "a".to_owned(),
+            movies: vec![GroupMovie {
+                movie_id,
+                added_by: 1,
+                votes: vec![1, 2],
+            }],
+        };
+        let group_id = db.add_group(&group).unwrap();
+        let other_id = db
+            .add_group(&Group {
+                owner: 2,
+                members: vec![2],
+                invite_code: "b".to_owned(),
+                movies: vec![],
+                ..group.clone()
+            })
+            .unwrap();
+        assert!(matches!(
+            db.add_group(&Group {
+                invite_code: "a".to_owned(),
+                ..group.clone()
+            }),
+            Err(DbError::Conflict(_))
+        ));
+        let ids = |user_id| {
+            db.get_groups_by_user(user_id)
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(1), vec![group_id]);
+        assert_eq!(ids(2), vec![group_id, other_id]);
+
+        group.members.push(3);
+        group.invite_code = "c".to_owned();
+        db.update_group(group_id, &group).unwrap();
+        assert_eq!(ids(3), vec![group_id]);
+        assert!(db.get_group_by_invite_code("a").unwrap().is_none());
+        assert_eq!(
+            db.get_group_by_invite_code("c").unwrap().unwrap().0,
+            group_id
+        );
+
+        db.remove_movie(movie_id).unwrap();
+        assert!(db.get_group(group_id).unwrap().unwrap().movies.is_empty());
+
+        // Removing the last member removes the group
+        db.modify_group(other_id, |group| {
+            group.members.clear();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ids(2), vec![group_id]);
+        assert!(db.get_group_by_invite_code("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn remove_group_members() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let add = |username: &str| {
+            db.add_user(&User {
+                username: username.to_owned(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let (alice, bob) = (add("alice"), add("bob"));
+        let shared = db
+            .add_group(&Group {
+                name: "Movie night".to_owned(),
+                owner: alice,
+                members: vec![alice, bob],
+                invite_code: "a".to_owned(),
+                movies: vec![GroupMovie {
+                    movie_id: 0,
+                    added_by: alice,
+                    votes: vec![alice, bob],
+                }],
+            })
+            .unwrap();
+        let alone = db
+            .add_group(&Group {
+                name: "Alone".to_owned(),
+                owner: alice,
+                members: vec![alice],
+                invite_code: "b".to_owned(),
+                movies: vec![],
+            })
+            .unwrap();
+        db.remove_user(alice).unwrap();
+        let group = db.get_group(shared).unwrap().unwrap();
+        assert_eq!(group.owner, bob);
+        assert_eq!(group.members, vec![bob]);
+        assert_eq!(group.movies[0].votes, vec![bob]);
+        assert!(db.get_group(alone).unwrap().is_none());
+        assert!(db.get_group_by_invite_code("b").unwrap().is_none());
+    }
+
+    #[test]
+    fn modify_group() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let group = Group {
+            name: "Movie night".to_owned(),
+            owner: 1,
+            members: vec![1],
+            invite_code: "a".to_owned(),
+            movies: vec![],
+        };
+        let group_id = db.add_group(&group).unwrap();
+        // A stale copy of the group
+        let stale = db.get_group(group_id).unwrap().unwrap();
+        let join = |user_id| {
+            db.modify_group(group_id, |group| {
+                group.members.push(user_id);
+                Ok(())
+            })
+        };
+        join(2).unwrap();
+        db.modify_group(group_id, |group| {
+            group.movies.push(GroupMovie {
+                movie_id: 0,
+                added_by: 1,
+                votes: vec![1],
+            });
+            Ok(())
+        })
+        .unwrap();
+        let stored = db.get_group(group_id).unwrap().unwrap();
+        assert_eq!(stored.members, vec![1, 2]);
+        assert_eq!(stored.movies.len(), 1);
+        assert_ne!(stored, stale);
+
+        // Errors and invalid groups abort the transaction
+        assert!(matches!(
+            db.modify_group(group_id, |group| {
+                group.members.push(3);
+                Err::<(), _>(DbError::NotFound)
+            }),
+            Err(DbError::NotFound)
+        ));
+        assert!(matches!(
+            db.modify_group(group_id, |group| {
+                group.owner = 3;
+                Ok(())
+            }),
+            Err(DbError::Validation(_))
+        ));
+        assert_eq!(db.get_group(group_id).unwrap().unwrap(), stored);
+        assert_eq!(db.get_groups_by_user(3).unwrap(), vec![]);
+
+        // The group is removed together with its indexes once nobody is left
+        db.modify_group(group_id, |group| {
+            group.members.clear();
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.get_group(group_id).unwrap().is_none());
+        assert!(db.get_group_by_invite_code("a").unwrap().is_none());
+        assert_eq!(db.get_groups_by_user(1).unwrap(), vec![]);
+        assert!(matches!(join(3), Err(DbError::NotFound)));
+    }
+
+    #[test]
+    fn remove_movie() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                year: Some(1994),
+                genres: vec!["Crime".to_owned()],
+                ..Default::default()
+            })
+            .unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        db.add_user(&User {
+            username: "bob".to_owned(),
+            friends: vec![(
+                alice,
+                FriendData {
+                    movies: vec![movie_id],
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        })
+        .unwrap();
+        db.remove_movie(movie_id).unwrap();
+        assert!(db.get_movie(movie_id).unwrap().is_none());
+        assert_eq!(db.get_movies_by_genre("crime").unwrap(), Vec::<u64>::new());
+        assert_eq!(
+            db.get_movies_by_year(1994, 1994).unwrap(),
+            Vec::<u64>::new()
+        );
+        assert!(db.search_movie("pulp").unwrap().is_empty());
+        let (_, bob) = db.get_user_by_username("bob").unwrap().unwrap();
+        assert!(bob.friends[&alice].movies.is_empty());
+    }
 }
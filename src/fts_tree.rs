@@ -54,6 +54,13 @@ impl FTSExt for sled::Db {
 }
 
 impl FTSTree {
+    /// Removes all documents
+    pub fn clear(&self) -> sled::Result<()> {
+        self.frequency.clear()?;
+        self.tokens.clear()?;
+        self.doclen.clear()
+    }
+
     pub fn insert<K: AsRef<[u8]>>(&self, key: K, value: &str) -> sled::Result<()> {
         assert_ne!(key.as_ref().len(), 0);
         use sled::Transactional;
@@ -77,7 +84,7 @@ impl FTSTree {
                     .into());
                 }
                 let old_total_dl = doclen
-                    .get(&[])?
+                    .get([])?
                     .map(|dl| u32::from_le_bytes(TryFrom::try_from(dl.as_ref()).unwrap()))
                     .unwrap_or(0);
                 doclen.insert(&[], (old_total_dl + total_count).to_le_bytes().as_ref())?;
@@ -92,8 +99,9 @@ impl FTSTree {
                     };
                     let mut frequency_key = id.to_le_bytes().as_ref().to_vec();
                     frequency_key.extend_from_slice(key.as_ref());
-                    if let Some(_) =
-                        frequency.insert(frequency_key, count.to_le_bytes().as_ref())?
+                    if frequency
+                        .insert(frequency_key, count.to_le_bytes().as_ref())?
+                        .is_some()
                     {
                         unreachable!();
                     }
@@ -132,7 +140,7 @@ impl FTSTree {
                     .into());
                 }
                 let old_total_dl = doclen
-                    .get(&[])?
+                    .get([])?
                     .map(|dl| u32::from_le_bytes(TryFrom::try_from(dl.as_ref()).unwrap()))
                     .unwrap_or(0);
                 doclen.insert(&[], (old_total_dl - total_count).to_le_bytes().as_ref())?;
@@ -190,7 +198,7 @@ impl FTSTree {
 
         let total_dl = self
             .doclen
-            .get(&[])?
+            .get([])?
             .map(|dl| u32::from_le_bytes(TryFrom::try_from(dl.as_ref()).unwrap()))
             .unwrap_or(0);
         let avgdl = total_dl as f32 / num_documents as f32;
@@ -227,7 +235,7 @@ impl FTSTree {
                         + 1.0)
                         .ln();
                     let bm25 = idf * frequency as f32 * (k1 + 1.0)
-                        / (frequency as f32 + k1 * (1.0 - b + b * dl as f32 / avgdl as f32));
+                        / (frequency as f32 + k1 * (1.0 - b + b * dl as f32 / avgdl));
                     *ret.entry(key).or_insert(0.0) += bm25.max(0.0) * count as f32;
                 }
             } else {
@@ -254,7 +262,7 @@ impl FTSTree {
                             .map(|dl| u32::from_le_bytes(TryFrom::try_from(dl.as_ref()).unwrap()))
                             .unwrap_or(0);
                         let bm25 = idf * frequency as f32 * (k1 + 1.0)
-                            / (frequency as f32 + k1 * (1.0 - b + b * dl as f32 / avgdl as f32));
+                            / (frequency as f32 + k1 * (1.0 - b + b * dl as f32 / avgdl));
                         *ret.entry(key).or_insert(0.0) += bm25 * count as f32;
                     }
                 }
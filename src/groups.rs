@@ -0,0 +1,543 @@
+//! Groups of friends with a shared watchlist.
+//!
+//! Members add movies to the watchlist of the group and vote for the ones they want to watch
+//! next. "Pick tonight's movie" draws one of them at random, weighted by the number of votes, so
+//! popular movies win more often without the others never getting a chance. New members join
+//! through an invite link, which the owner can reset to stop it from working.
+
+use crate::{
+    account::render_message, config::Config, csrf::CsrfToken, database::*, model::*, render,
+    session, token, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{error, web, HttpResponse};
+use log::info;
+use rand::{distributions::WeightedIndex, Rng};
+use serde::{Deserialize, Serialize};
+
+const GROUP_NAME_MAX_LENGTH: usize = 64;
+
+/// Draws a movie that got at least one vote, with a probability proportional to its votes
+pub fn pick<R: Rng + ?Sized>(movies: &[GroupMovie], rng: &mut R) -> Option<u64> {
+    // Fails if nobody voted
+    let weights = WeightedIndex::new(movies.iter().map(|movie| movie.votes.len())).ok()?;
+    Some(movies[rng.sample(&weights)].movie_id)
+}
+
+fn redirect_to_group(group_id: u64) -> HttpResponse {
+    HttpResponse::Found()
+        .header("location", format!("/groups/{}", group_id))
+        .finish()
+}
+
+const NOT_A_MEMBER: &str = "You aren't a member of this group";
+
+/// Loads the group if the user is a member
+fn require_member<D: DbExt>(db: &D, group_id: u64, user_id: u64) -> actix_web::Result<Group> {
+    let group = db.get_group(group_id)?.ok_or(DbError::NotFound)?;
+    if !group.members.contains(&user_id) {
+        return Err(error::ErrorForbidden(NOT_A_MEMBER));
+    }
+    Ok(group)
+}
+
+/// Changes the group with `f` if `allowed` holds for it. Both happen inside of the same
+/// transaction, so that requests of other members in between aren't undone.
+fn modify_if<D: DbExt, T>(
+    db: &D,
+    group_id: u64,
+    allowed: impl Fn(&Group) -> bool,
+    forbidden: &'static str,
+    f: impl Fn(&mut Group) -> DbResult<T>,
+) -> actix_web::Result<T> {
+    db.modify_group(group_id, |group| {
+        if !allowed(group) {
+            return Ok(None);
+        }
+        f(group).map(Some)
+    })?
+    .ok_or_else(|| error::ErrorForbidden(forbidden))
+}
+
+fn modify_as_member<D: DbExt, T>(
+    db: &D,
+    group_id: u64,
+    user_id: u64,
+    f: impl Fn(&mut Group) -> DbResult<T>,
+) -> actix_web::Result<T> {
+    modify_if(
+        db,
+        group_id,
+        |group| group.members.contains(&user_id),
+        NOT_A_MEMBER,
+        f,
+    )
+}
+
+fn modify_as_owner<D: DbExt, T>(
+    db: &D,
+    group_id: u64,
+    user_id: u64,
+    f: impl Fn(&mut Group) -> DbResult<T>,
+) -> actix_web::Result<T> {
+    modify_if(
+        db,
+        group_id,
+        |group| group.owner == user_id,
+        "Only the owner of the group can do this",
+        f,
+    )
+}
+
+fn invite_link(config: &Config, group: &Group) -> String {
+    format!("{}/groups/join/{}", config.public_url, group.invite_code)
+}
+
+#[derive(Serialize)]
+struct GroupSummary {
+    id: u64,
+    name: String,
+    members: usize,
+    is_owner: bool,
+}
+
+pub async fn groups<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let groups = db
+        .get_groups_by_user(current.user_id)?
+        .into_iter()
+        .map(|(group_id, group)| GroupSummary {
+            id: group_id,
+            name: group.name,
+            members: group.members.len(),
+            is_owner: group.owner == current.user_id,
+        })
+        .collect::<Vec<_>>();
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("groups", &groups);
+    render(&tera, "groups.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateParams {
+    pub name: String,
+}
+
+pub async fn create_post<D: DbExt>(
+    params: web::Form<CreateParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let name = params.name.trim();
+    if name.chars().count() > GROUP_NAME_MAX_LENGTH {
+        return Err(DbError::Validation(format!(
+            "Group names must not be longer than {} characters",
+            GROUP_NAME_MAX_LENGTH
+        ))
+        .into());
+    }
+    let group_id = db.add_group(&Group {
+        name: name.to_owned(),
+        owner: current.user_id,
+        members: vec![current.user_id],
+        invite_code: token::generate(),
+        movies: Vec::new(),
+    })?;
+    info!("{} created group {}", current.user_id, group_id);
+    Ok(redirect_to_group(group_id))
+}
+
+#[derive(Serialize)]
+struct MemberEntry {
+    id: u64,
+    username: String,
+    display_name: Option<String>,
+    is_owner: bool,
+}
+
+#[derive(Serialize)]
+struct MovieEntry {
+    id: u64,
+    name: String,
+    year: Option<u16>,
+    /// Username of the member that added the movie, unless they left
+    added_by: Option<String>,
+    votes: usize,
+    voted: bool,
+    /// Whether the current user may remove the movie
+    removable: bool,
+}
+
+#[derive(Deserialize)]
+pub struct GroupQuery {
+    /// Movie chosen by "Pick tonight's movie"
+    picked: Option<u64>,
+}
+
+pub async fn group<D: DbExt>(
+    path: web::Path<u64>,
+    query: web::Query<GroupQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    let group = require_member(&**db, group_id, current.user_id)?;
+    let mut members = Vec::new();
+    for member_id in &group.members {
+        if let Some(member) = db.get_user(*member_id)? {
+            members.push(MemberEntry {
+                id: *member_id,
+                username: member.username,
+                display_name: member.display_name,
+                is_owner: *member_id == group.owner,
+            });
+        }
+    }
+    let mut movies = Vec::new();
+    for entry in &group.movies {
+        // Removed movies can stay in groups if they were added while `remove_movie` ran
+        let movie = match db.get_movie(entry.movie_id)? {
+            Some(movie) => movie,
+            None => continue,
+        };
+        movies.push(MovieEntry {
+            id: entry.movie_id,
+            name: movie.name,
+            year: movie.year,
+            added_by: members
+                .iter()
+                .find(|member| member.id == entry.added_by)
+                .map(|member| member.username.clone()),
+            votes: entry.votes.len(),
+            voted: entry.votes.contains(&current.user_id),
+            removable: entry.added_by == current.user_id || group.owner == current.user_id,
+        });
+    }
+    // Stable, so movies with the same number of votes stay in the order they were added
+    movies.sort_by_key(|movie| std::cmp::Reverse(movie.votes));
+    let picked = query
+        .picked
+        .and_then(|movie_id| movies.iter().find(|movie| movie.id == movie_id))
+        .map(|movie| movie.name.clone());
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("group_id", &group_id);
+    ctx.insert("name", &group.name);
+    ctx.insert("is_owner", &(group.owner == current.user_id));
+    ctx.insert("current_user_id", &current.user_id);
+    ctx.insert("invite_link", &invite_link(&config, &group));
+    ctx.insert("members", &members);
+    ctx.insert("movies", &movies);
+    ctx.insert("picked", &picked);
+    render(&tera, "group.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddMovieParams {
+    pub group_id: u64,
+    pub movie_id: u64,
+}
+
+/// Adds a movie to the watchlist of the group, used by the movie search
+pub async fn add_movie_post<D: DbExt>(
+    params: web::Form<AddMovieParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    db.get_movie(params.movie_id)?.ok_or(DbError::NotFound)?;
+    modify_as_member(&**db, params.group_id, current.user_id, |group| {
+        if !group
+            .movies
+            .iter()
+            .any(|movie| movie.movie_id == params.movie_id)
+        {
+            group.movies.push(GroupMovie {
+                movie_id: params.movie_id,
+                added_by: current.user_id,
+                votes: Vec::new(),
+            });
+        }
+        Ok(())
+    })?;
+    Ok(redirect_to_group(params.group_id))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MovieParams {
+    pub movie_id: u64,
+}
+
+/// Votes for the movie, or takes the vote back if the user already voted for it
+pub async fn vote_post<D: DbExt>(
+    path: web::Path<u64>,
+    params: web::Form<MovieParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    modify_as_member(&**db, group_id, current.user_id, |group| {
+        let movie = group
+            .movies
+            .iter_mut()
+            .find(|movie| movie.movie_id == params.movie_id)
+            .ok_or(DbError::NotFound)?;
+        if movie.votes.contains(&current.user_id) {
+            movie.votes.retain(|member| *member != current.user_id);
+        } else {
+            movie.votes.push(current.user_id);
+        }
+        Ok(())
+    })?;
+    Ok(redirect_to_group(group_id))
+}
+
+/// Removes a movie from the watchlist, which only the member that added it and the owner can do
+pub async fn remove_movie_post<D: DbExt>(
+    path: web::Path<u64>,
+    params: web::Form<MovieParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    let removed = modify_as_member(&**db, group_id, current.user_id, |group| {
+        let movie = group
+            .movies
+            .iter()
+            .find(|movie| movie.movie_id == params.movie_id)
+            .ok_or(DbError::NotFound)?;
+        if movie.added_by != current.user_id && group.owner != current.user_id {
+            return Ok(false);
+        }
+        group
+            .movies
+            .retain(|movie| movie.movie_id != params.movie_id);
+        Ok(true)
+    })?;
+    if !removed {
+        return Err(error::ErrorForbidden(
+            "Only the member that added the movie and the owner can remove it",
+        ));
+    }
+    Ok(redirect_to_group(group_id))
+}
+
+/// "Pick tonight's movie"
+pub async fn pick_post<D: DbExt>(
+    path: web::Path<u64>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    let group = require_member(&**db, group_id, current.user_id)?;
+    match pick(&group.movies, &mut rand::thread_rng()) {
+        Some(movie_id) => Ok(HttpResponse::Found()
+            .header(
+                "location",
+                format!("/groups/{}?picked={}", group_id, movie_id),
+            )
+            .finish()),
+        None => render_message(
+            &tera,
+            "Nothing to pick",
+            "Vote for the movies you want to watch first.",
+            HttpResponse::Conflict(),
+        ),
+    }
+}
+
+fn invalid_invite(tera: &tera::Tera) -> actix_web::Result<HttpResponse> {
+    render_message(
+        tera,
+        "Invalid invite link",
+        "This invite link doesn't work anymore. Ask a member of the group for a new one.",
+        HttpResponse::NotFound(),
+    )
+}
+
+/// Landing page of an invite link
+pub async fn join<D: DbExt>(
+    path: web::Path<String>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let (group_id, group) = match db.get_group_by_invite_code(&path)? {
+        Some(group) => group,
+        None => return invalid_invite(&tera),
+    };
+    if group.members.contains(&current.user_id) {
+        return Ok(redirect_to_group(group_id));
+    }
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("invite_code", &group.invite_code);
+    ctx.insert("name", &group.name);
+    ctx.insert("members", &group.members.len());
+    render(&tera, "group_join.html", &ctx, HttpResponse::Ok())
+}
+
+pub async fn join_post<D: DbExt>(
+    path: web::Path<String>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = match db.get_group_by_invite_code(&path)? {
+        Some((group_id, _)) => group_id,
+        None => return invalid_invite(&tera),
+    };
+    let joined = db.modify_group(group_id, |group| {
+        // The owner may have reset the invite code in the meantime
+        if group.invite_code != *path {
+            return Ok(None);
+        }
+        if group.members.contains(&current.user_id) {
+            return Ok(Some(false));
+        }
+        group.members.push(current.user_id);
+        Ok(Some(true))
+    });
+    match joined {
+        Ok(Some(true)) => info!("{} joined group {}", current.user_id, group_id),
+        Ok(Some(false)) => {}
+        Ok(None) | Err(DbError::NotFound) => return invalid_invite(&tera),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(redirect_to_group(group_id))
+}
+
+/// Replaces the invite code, so that the old link stops working
+pub async fn reset_invite_post<D: DbExt>(
+    path: web::Path<u64>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    let invite_code = token::generate();
+    modify_as_owner(&**db, group_id, current.user_id, |group| {
+        group.invite_code = invite_code.clone();
+        Ok(())
+    })?;
+    Ok(redirect_to_group(group_id))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemberParams {
+    pub user_id: u64,
+}
+
+pub async fn remove_member_post<D: DbExt>(
+    path: web::Path<u64>,
+    params: web::Form<MemberParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    if params.user_id == current.user_id {
+        return render_message(
+            &tera,
+            "Not allowed",
+            "Leave the group instead of removing yourself.",
+            HttpResponse::Conflict(),
+        );
+    }
+    modify_as_owner(&**db, group_id, current.user_id, |group| {
+        if !group.members.contains(&params.user_id) {
+            return Err(DbError::NotFound);
+        }
+        remove_member(group, params.user_id);
+        Ok(())
+    })?;
+    info!(
+        "{} removed {} from group {}",
+        current.user_id, params.user_id, group_id
+    );
+    Ok(redirect_to_group(group_id))
+}
+
+/// Leaves the group. If the owner leaves, the member that joined next takes over, and the group
+/// is deleted when the last member leaves.
+pub async fn leave_post<D: DbExt>(
+    path: web::Path<u64>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    modify_as_member(&**db, group_id, current.user_id, |group| {
+        remove_member(group, current.user_id);
+        Ok(())
+    })?;
+    info!("{} left group {}", current.user_id, group_id);
+    Ok(HttpResponse::Found().header("location", "/groups").finish())
+}
+
+pub async fn delete_post<D: DbExt>(
+    path: web::Path<u64>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let group_id = path.into_inner();
+    // Groups without members are removed
+    modify_as_owner(&**db, group_id, current.user_id, |group| {
+        group.members.clear();
+        Ok(())
+    })?;
+    info!("{} deleted group {}", current.user_id, group_id);
+    Ok(HttpResponse::Found().header("location", "/groups").finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn movie(movie_id: u64, votes: usize) -> GroupMovie {
+        GroupMovie {
+            movie_id,
+            added_by: 0,
+            votes: (0..votes as u64).collect(),
+        }
+    }
+
+    #[test]
+    fn pick_weighted_by_votes() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(pick(&[], &mut rng), None);
+        assert_eq!(pick(&[movie(1, 0), movie(2, 0)], &mut rng), None);
+        assert_eq!(pick(&[movie(1, 0), movie(2, 1)], &mut rng), Some(2));
+
+        let movies = [movie(1, 1), movie(2, 3), movie(3, 0)];
+        let mut counts = [0; 4];
+        for _ in 0..4000 {
+            counts[pick(&movies, &mut rng).unwrap() as usize] += 1;
+        }
+        assert_eq!(counts[3], 0);
+        // Expected are 1000 and 3000
+        assert!((800..1200).contains(&counts[1]), "{:?}", counts);
+        assert!((2800..3200).contains(&counts[2]), "{:?}", counts);
+    }
+}
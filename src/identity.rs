@@ -0,0 +1,226 @@
+//! Signing keys for the identity cookie.
+//!
+//! The current key is read from the configuration or from the key file, which is created with a
+//! random key on first run. Cookies signed with one of the configured old keys are still
+//! accepted and transparently re-issued with the current key, which allows rotating keys without
+//! logging everybody out.
+
+use crate::config::CookieConfig;
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    HttpMessage,
+};
+use rand::RngCore;
+use std::{future::Ready, io, path::Path};
+
+/// Minimum key length required by `CookieIdentityPolicy`
+const KEY_LENGTH: usize = 32;
+
+#[derive(Clone)]
+pub struct Keys {
+    pub current: Vec<u8>,
+    pub old: Vec<Vec<u8>>,
+}
+
+pub fn decode_key(key: &str) -> io::Result<Vec<u8>> {
+    let key = base64::decode(key.trim())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if key.len() < KEY_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Keys must be at least {} bytes long", KEY_LENGTH),
+        ));
+    }
+    Ok(key)
+}
+
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0u8; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Reads the key from `path` or writes a new random key to it if it doesn't exist
+pub fn load_or_create_key_file(path: &Path) -> io::Result<Vec<u8>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => decode_key(&content),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let key = generate_key();
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            io::Write::write_all(&mut options.open(path)?, base64::encode(&key).as_bytes())?;
+            log::info!("Generated new key in {}", path.display());
+            Ok(key)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+impl Keys {
+    /// Loads the keys for `config`. With `temporary` set, a random key is used if none is
+    /// configured instead of creating the key file.
+    pub fn load(config: &CookieConfig, temporary: bool) -> io::Result<Keys> {
+        let current = match &config.key {
+            Some(key) => decode_key(key)?,
+            None if temporary => generate_key(),
+            None => load_or_create_key_file(&config.key_file)?,
+        };
+        let old = config
+            .old_keys
+            .iter()
+            .map(|key| decode_key(key))
+            .collect::<io::Result<_>>()?;
+        Ok(Keys { current, old })
+    }
+}
+
+/// Marker for requests whose identity cookie was signed with an old key
+struct OldKey;
+
+pub struct RotatingCookiePolicy {
+    current: CookieIdentityPolicy,
+    old: Vec<CookieIdentityPolicy>,
+}
+
+fn cookie_policy(key: &[u8], config: &CookieConfig) -> CookieIdentityPolicy {
+    let mut policy = CookieIdentityPolicy::new(key)
+        .name(&config.name)
+        .secure(config.secure)
+        .same_site(config.same_site.into());
+    if let Some(max_age) = config.max_age {
+        policy = policy.max_age(max_age);
+    }
+    policy
+}
+
+impl RotatingCookiePolicy {
+    pub fn new(keys: &Keys, config: &CookieConfig) -> Self {
+        RotatingCookiePolicy {
+            current: cookie_policy(&keys.current, config),
+            old: keys
+                .old
+                .iter()
+                .map(|key| cookie_policy(key, config))
+                .collect(),
+        }
+    }
+}
+
+impl RotatingCookiePolicy {
+    fn load(&self, req: &mut ServiceRequest) -> actix_web::Result<Option<String>> {
+        let identity = self.current.from_request(req).into_inner()?;
+        if identity.is_some() {
+            return Ok(identity);
+        }
+        for policy in &self.old {
+            let identity = policy.from_request(req).into_inner()?;
+            if identity.is_some() {
+                req.extensions_mut().insert(OldKey);
+                return Ok(identity);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl IdentityPolicy for RotatingCookiePolicy {
+    type Future = Ready<actix_web::Result<Option<String>>>;
+    type ResponseFuture = Ready<actix_web::Result<()>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        std::future::ready(self.load(req))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let old_key = res.request().extensions_mut().remove::<OldKey>().is_some();
+        std::future::ready(
+            self.current
+                .to_response(identity, changed || old_key, res)
+                .into_inner(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_identity::{Identity, IdentityService};
+    use actix_web::{http::Cookie, test, web, App, HttpResponse};
+
+    async fn whoami(id: Identity) -> HttpResponse {
+        HttpResponse::Ok().body(id.identity().unwrap_or_default())
+    }
+
+    async fn login(id: Identity) -> HttpResponse {
+        id.remember("foo".to_owned());
+        HttpResponse::Ok().finish()
+    }
+
+    fn auth_cookie<B>(resp: &ServiceResponse<B>) -> Option<Cookie<'static>> {
+        resp.response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .map(|c| c.into_owned())
+    }
+
+    #[actix_rt::test]
+    async fn rotation() {
+        let config = CookieConfig::default();
+        let old = generate_key();
+        let new = generate_key();
+        let app = |keys: Keys| {
+            App::new()
+                .wrap(IdentityService::new(RotatingCookiePolicy::new(
+                    &keys, &config,
+                )))
+                .route("/", web::get().to(whoami))
+                .route("/login", web::get().to(login))
+        };
+
+        let mut old_app = test::init_service(app(Keys {
+            current: old.clone(),
+            old: vec![],
+        }))
+        .await;
+        let req = test::TestRequest::get().uri("/login").to_request();
+        let resp = test::call_service(&mut old_app, req).await;
+        let old_cookie = auth_cookie(&resp).unwrap();
+
+        let mut new_app = test::init_service(app(Keys {
+            current: new.clone(),
+            old: vec![old],
+        }))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(old_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut new_app, req).await;
+        let new_cookie = auth_cookie(&resp).expect("cookie is re-issued with the new key");
+        assert_eq!(test::read_body(resp).await, "foo");
+
+        let mut rotated_app = test::init_service(app(Keys {
+            current: new,
+            old: vec![],
+        }))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(old_cookie)
+            .to_request();
+        assert_eq!(test::read_response(&mut rotated_app, req).await, "");
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(new_cookie)
+            .to_request();
+        assert_eq!(test::read_response(&mut rotated_app, req).await, "foo");
+    }
+}
@@ -0,0 +1,247 @@
+//! Bulk import of movies from the IMDb datasets and TMDb exports.
+//!
+//! Records are parsed line by line and inserted in chunks of [`CHECKPOINT_INTERVAL`] lines, each
+//! chunk in one transaction. The progress is checkpointed after every chunk, so an interrupted
+//! import can be resumed.
+
+use crate::{database::*, model::*};
+use log::info;
+use serde::Deserialize;
+use std::{
+    convert::TryInto,
+    error::Error,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+const IMPORTS: &[u8] = b"imports";
+
+/// Number of lines that are inserted together, after which progress is reported and
+/// checkpointed
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `title.basics.tsv` from the IMDb datasets
+    Imdb,
+    /// Newline delimited JSON objects as returned by the TMDb movie API
+    Tmdb,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "imdb" => Ok(Format::Imdb),
+            "tmdb" => Ok(Format::Tmdb),
+            _ => Err(format!("Unknown import format: {}", s)),
+        }
+    }
+}
+
+fn imdb_field(field: &str) -> Option<&str> {
+    if field == "\\N" {
+        None
+    } else {
+        Some(field)
+    }
+}
+
+/// Parses a line of `title.basics.tsv`. Returns `None` for the header, malformed lines and
+/// titles that aren't movies.
+pub fn parse_imdb_line(line: &str) -> Option<Movie> {
+    let fields = line.split('\t').collect::<Vec<_>>();
+    if fields.len() < 9 || fields[1] != "movie" {
+        return None;
+    }
+    Some(Movie {
+        name: fields[2].to_owned(),
+        year: imdb_field(fields[5]).and_then(|y| y.parse().ok()),
+        runtime: imdb_field(fields[7]).and_then(|r| r.parse().ok()),
+        genres: imdb_field(fields[8])
+            .map(|g| g.split(',').map(str::to_owned).collect())
+            .unwrap_or_default(),
+        poster_url: None,
+        imdb_id: Some(fields[0].to_owned()),
+        tmdb_id: None,
+    })
+}
+
+#[derive(Deserialize)]
+struct TmdbGenre {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct TmdbMovie {
+    id: u64,
+    title: Option<String>,
+    original_title: Option<String>,
+    release_date: Option<String>,
+    runtime: Option<u16>,
+    #[serde(default)]
+    genres: Vec<TmdbGenre>,
+    poster_path: Option<String>,
+    imdb_id: Option<String>,
+}
+
+/// Parses a line of a TMDb JSON export. Returns `None` for lines that can't be parsed.
+pub fn parse_tmdb_line(line: &str) -> Option<Movie> {
+    let movie: TmdbMovie = serde_json::from_str(line).ok()?;
+    Some(Movie {
+        name: movie.title.or(movie.original_title)?,
+        year: movie
+            .release_date
+            .and_then(|d| d.get(0..4).and_then(|y| y.parse().ok())),
+        runtime: movie.runtime.filter(|r| *r > 0),
+        genres: movie.genres.into_iter().map(|g| g.name).collect(),
+        poster_url: movie
+            .poster_path
+            .map(|p| format!("https://image.tmdb.org/t/p/w500{}", p)),
+        imdb_id: movie.imdb_id.filter(|i| !i.is_empty()),
+        tmdb_id: Some(movie.id),
+    })
+}
+
+/// Imports all movies from `path` into `db`.
+///
+/// The number of processed lines is checkpointed in the `imports` tree, so an interrupted
+/// import continues where it left off when run again with the same file. Movies whose IMDb or
+/// TMDb id is already known are skipped. Returns the number of movies inserted.
+pub fn import<P: AsRef<Path>>(
+    db: &sled::Db,
+    format: Format,
+    path: P,
+) -> Result<u64, Box<dyn Error>> {
+    let path = path.as_ref().canonicalize()?;
+    let progress_key = path.to_string_lossy().into_owned();
+    let imports = db.open_tree(IMPORTS)?;
+    let start = match imports.get(&progress_key)? {
+        Some(data) => u64::from_le_bytes(data.as_ref().try_into().map_err(|_| {
+            DbError::Corruption(format!("Invalid import checkpoint for {}", progress_key))
+        })?),
+        None => 0,
+    };
+    if start > 0 {
+        info!("Resuming import of {} at line {}", path.display(), start);
+    }
+
+    let file = File::open(&path)?;
+    let total_bytes = file.metadata()?.len().max(1);
+    let mut reader = BufReader::new(file);
+    let mut read_bytes = 0u64;
+    let mut line = String::new();
+    let mut line_number = 0u64;
+    let mut inserted = 0u64;
+    let mut batch = Vec::new();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        read_bytes += n as u64;
+        line_number += 1;
+        if line_number <= start {
+            continue;
+        }
+        let movie = match format {
+            Format::Imdb => parse_imdb_line(line.trim_end_matches(&['\r', '\n'][..])),
+            Format::Tmdb => parse_tmdb_line(&line),
+        };
+        batch.extend(movie);
+        if line_number.is_multiple_of(CHECKPOINT_INTERVAL) {
+            inserted += db.add_movies(&batch)?;
+            batch.clear();
+            // A crash before the checkpoint is harmless, the chunk's movies are skipped as known
+            // ones when it is imported again
+            imports.insert(&progress_key, &line_number.to_le_bytes())?;
+            info!(
+                "{:.1}% ({} lines, {} movies inserted)",
+                read_bytes as f64 * 100.0 / total_bytes as f64,
+                line_number,
+                inserted
+            );
+        }
+    }
+    inserted += db.add_movies(&batch)?;
+    imports.insert(&progress_key, &line_number.to_le_bytes())?;
+    db.flush()?;
+    info!(
+        "Finished import of {}: {} lines, {} movies inserted",
+        path.display(),
+        line_number,
+        inserted
+    );
+    Ok(inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_imdb() {
+        let movie = parse_imdb_line(
+            "tt0110912\tmovie\tPulp Fiction\tPulp Fiction\t0\t1994\t\\N\t154\tCrime,Drama",
+        )
+        .unwrap();
+        assert_eq!(movie.name, "Pulp Fiction");
+        assert_eq!(movie.year, Some(1994));
+        assert_eq!(movie.runtime, Some(154));
+        assert_eq!(movie.genres, vec!["Crime", "Drama"]);
+        assert_eq!(movie.imdb_id.as_deref(), Some("tt0110912"));
+        assert!(parse_imdb_line(
+            "tt0000001\tshort\tCarmencita\tCarmencita\t0\t1894\t\\N\t1\tShort"
+        )
+        .is_none());
+        assert!(parse_imdb_line("tconst\ttitleType\tprimaryTitle").is_none());
+    }
+
+    #[test]
+    fn parse_tmdb() {
+        let movie = parse_tmdb_line(
+            r#"{"id":680,"title":"Pulp Fiction","release_date":"1994-09-10","runtime":154,"genres":[{"id":80,"name":"Crime"}],"poster_path":"/x.jpg","imdb_id":"tt0110912"}"#,
+        )
+        .unwrap();
+        assert_eq!(movie.name, "Pulp Fiction");
+        assert_eq!(movie.year, Some(1994));
+        assert_eq!(movie.genres, vec!["Crime"]);
+        assert_eq!(
+            movie.poster_url.as_deref(),
+            Some("https://image.tmdb.org/t/p/w500/x.jpg")
+        );
+        assert_eq!(movie.tmdb_id, Some(680));
+        let movie =
+            parse_tmdb_line(r#"{"adult":false,"id":3924,"original_title":"Blondie"}"#).unwrap();
+        assert_eq!(movie.name, "Blondie");
+        assert_eq!(movie.year, None);
+    }
+
+    #[test]
+    fn resume() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let path = std::env::temp_dir().join(format!("nextflix-import-{}.tsv", std::process::id()));
+        std::fs::write(
+            &path,
+            "tconst\ttitleType\tprimaryTitle\toriginalTitle\tisAdult\tstartYear\tendYear\truntimeMinutes\tgenres\n\
+             tt0110912\tmovie\tPulp Fiction\tPulp Fiction\t0\t1994\t\\N\t154\tCrime,Drama\n",
+        )
+        .unwrap();
+        assert_eq!(import(&db, Format::Imdb, &path).unwrap(), 1);
+        assert_eq!(import(&db, Format::Imdb, &path).unwrap(), 0);
+        db.drop_tree(IMPORTS).unwrap();
+        assert_eq!(import(&db, Format::Imdb, &path).unwrap(), 0);
+        // A broken checkpoint is reported instead of panicking
+        let progress_key = path.canonicalize().unwrap().to_string_lossy().into_owned();
+        db.open_tree(IMPORTS)
+            .unwrap()
+            .insert(progress_key, &[1, 2])
+            .unwrap();
+        assert!(import(&db, Format::Imdb, &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(db.get_movie_by_imdb_id("tt0110912").unwrap().is_some());
+    }
+}
@@ -0,0 +1,306 @@
+//! Sending emails.
+//!
+//! Emails are either handed to an SMTP relay (a local MTA, or a test server like MailHog during
+//! development) or written as `.eml` files to an outbox directory. Sending blocks, so handlers use
+//! [`Mailer::send`] which runs on the blocking thread pool.
+
+use crate::config::{MailConfig, MailTransport};
+use rand::RngCore;
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Clone)]
+enum Transport {
+    File(PathBuf),
+    Smtp(String, u16),
+    #[cfg(test)]
+    Memory(std::sync::Arc<std::sync::Mutex<Vec<Email>>>),
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    from: String,
+    transport: Transport,
+}
+
+/// Removes line breaks so that values can't inject headers
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+/// Encodes non ASCII header values as RFC 2047 encoded words
+fn encode_header(value: &str) -> String {
+    let value = header_value(value);
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?utf-8?B?{}?=", base64::encode(value))
+    }
+}
+
+/// The address part of `Name <address>`
+fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+fn message_id() -> String {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> Self {
+        let transport = match config.transport {
+            MailTransport::File => Transport::File(config.outbox_dir.clone()),
+            MailTransport::Smtp => Transport::Smtp(config.smtp_host.clone(), config.smtp_port),
+        };
+        Mailer {
+            from: config.from.clone(),
+            transport,
+        }
+    }
+
+    /// Mailer that collects all emails in a list instead of sending them
+    #[cfg(test)]
+    pub fn memory() -> (Self, std::sync::Arc<std::sync::Mutex<Vec<Email>>>) {
+        let sent = std::sync::Arc::default();
+        let mailer = Mailer {
+            from: "nextflix@localhost".to_owned(),
+            transport: Transport::Memory(std::sync::Arc::clone(&sent)),
+        };
+        (mailer, sent)
+    }
+
+    /// The complete message including headers, with CRLF line endings
+    fn format(&self, email: &Email) -> String {
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nMessage-ID: <{}@{}>\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n",
+            header_value(&self.from),
+            header_value(&email.to),
+            encode_header(&email.subject),
+            message_id(),
+            address(&self.from)
+                .rsplit('@')
+                .next()
+                .unwrap_or("localhost"),
+        );
+        for line in email.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+
+    /// Sends the email on the current thread
+    pub fn send_blocking(&self, email: &Email) -> io::Result<()> {
+        match &self.transport {
+            Transport::File(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let name = format!("{}-{}.eml", crate::model::unix_time(), message_id());
+                std::fs::write(dir.join(name), self.format(email))
+            }
+            Transport::Smtp(host, port) => smtp_send(
+                (host.as_str(), *port),
+                address(&self.from),
+                address(&email.to),
+                &self.format(email),
+            ),
+            #[cfg(test)]
+            Transport::Memory(sent) => {
+                sent.lock().unwrap().push(email.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the email on the blocking thread pool
+    pub async fn send(&self, email: Email) -> io::Result<()> {
+        let mailer = self.clone();
+        actix_web::web::block(move || mailer.send_blocking(&email))
+            .await
+            .map_err(|err| match err {
+                actix_web::error::BlockingError::Error(err) => err,
+                actix_web::error::BlockingError::Canceled => {
+                    io::Error::other("Sending was canceled")
+                }
+            })
+    }
+}
+
+/// Reads a possibly multi line reply and returns its status code
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<u16> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("Bad SMTP reply: {}", line.trim_end())))?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(code);
+        }
+    }
+}
+
+fn expect_reply<R: BufRead>(reader: &mut R, expected: u16) -> io::Result<()> {
+    let code = read_reply(reader)?;
+    if code / 100 == expected / 100 {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "SMTP server replied {}, expected {}",
+            code, expected
+        )))
+    }
+}
+
+/// Delivers `message` with a minimal SMTP dialog, without TLS or authentication
+fn smtp_send<A: std::net::ToSocketAddrs>(
+    server: A,
+    from: &str,
+    to: &str,
+    message: &str,
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect(server)?;
+    stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    expect_reply(&mut reader, 220)?;
+    for (command, expected) in &[
+        ("EHLO nextflix".to_owned(), 250),
+        (format!("MAIL FROM:<{}>", from), 250),
+        (format!("RCPT TO:<{}>", to), 250),
+        ("DATA".to_owned(), 354),
+    ] {
+        write!(stream, "{}\r\n", command)?;
+        expect_reply(&mut reader, *expected)?;
+    }
+    for line in message.split_terminator("\r\n") {
+        // Dot stuffing, a single dot would end the message
+        if line.starts_with('.') {
+            stream.write_all(b".")?;
+        }
+        write!(stream, "{}\r\n", line)?;
+    }
+    stream.write_all(b".\r\n")?;
+    expect_reply(&mut reader, 250)?;
+    stream.write_all(b"QUIT\r\n")?;
+    // The message is accepted at this point, a missing goodbye doesn't matter
+    let _ = read_reply(&mut reader);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn email() -> Email {
+        Email {
+            to: "Alice <alice@example.com>".to_owned(),
+            subject: "Grüße\r\nBcc: eve@example.com".to_owned(),
+            body: "Hello\n.\nBye".to_owned(),
+        }
+    }
+
+    #[test]
+    fn format() {
+        let (mailer, _) = Mailer::memory();
+        let message = mailer.format(&email());
+        assert!(message.contains("To: Alice <alice@example.com>\r\n"));
+        assert!(message.contains("Subject: =?utf-8?B?"));
+        assert!(!message.contains("\r\nBcc:"));
+        assert!(message.ends_with("\r\n\r\nHello\r\n.\r\nBye\r\n"));
+    }
+
+    #[test]
+    fn file_outbox() {
+        let dir = std::env::temp_dir().join(format!("nextflix-outbox-{}", message_id()));
+        let mailer = Mailer::new(&MailConfig {
+            outbox_dir: dir.clone(),
+            ..Default::default()
+        });
+        mailer.send_blocking(&email()).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut transcript = Vec::new();
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        transcript.push(line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    transcript.push(line);
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+            transcript
+        });
+
+        let mailer = Mailer::new(&MailConfig {
+            from: "Nextflix <nextflix@example.com>".to_owned(),
+            transport: MailTransport::Smtp,
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: port,
+            ..Default::default()
+        });
+        mailer.send_blocking(&email()).unwrap();
+        let transcript = server.join().unwrap();
+        assert_eq!(transcript[0], "MAIL FROM:<nextflix@example.com>\r\n");
+        assert_eq!(transcript[1], "RCPT TO:<alice@example.com>\r\n");
+        assert!(transcript.contains(&"..\r\n".to_owned()));
+        assert_eq!(transcript.last().unwrap(), "Bye\r\n");
+    }
+}
@@ -1,65 +1,127 @@
+mod account;
+mod admin;
+mod api;
+mod api_token;
+mod config;
+mod csrf;
 mod database;
 mod fts_tree;
+mod groups;
+mod identity;
+mod import;
+mod mail;
+#[cfg(test)]
+mod memory_db;
 mod model;
+mod notifications;
+mod notify;
+mod oidc;
+mod password;
+mod privacy;
+mod rate_limit;
+mod schema;
+mod search;
+mod session;
+mod token;
+mod totp;
+mod validation;
+mod watchlist;
 
-use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
-use actix_web::{error, middleware::Logger, web, App, HttpResponse, HttpServer};
+use actix_identity::{Identity, IdentityService};
+use actix_web::{
+    error, http::StatusCode, middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer,
+};
 use database::*;
 use log::debug;
 use model::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 type Tera = web::Data<tera::Tera>;
-type Db = web::Data<sled::Db>;
 
 fn log_error<E: std::fmt::Debug>(err: E, message: &'static str) -> error::Error {
     debug!("{:?}", err);
     error::ErrorInternalServerError(message)
 }
 
-async fn index(id: Identity, tera: Tera, db: Db) -> actix_web::Result<HttpResponse> {
+/// Renders `template` as the HTML body of `response`
+fn render(
+    tera: &tera::Tera,
+    template: &str,
+    ctx: &tera::Context,
+    mut response: actix_web::dev::HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let body = tera
+        .render(template, ctx)
+        .map_err(|err| log_error(err, "Template error"))?;
+    Ok(response.content_type("text/html").body(body))
+}
+
+/// Removes expired login attempts, tokens, OIDC logins and sessions
+fn remove_expired<D: DbExt>(db: &D, now: u64) -> DbResult<()> {
+    db.remove_expired_login_attempts(now.saturating_sub(rate_limit::ATTEMPTS_TTL))?;
+    db.remove_expired_tokens(now)?;
+    db.remove_expired_oidc_logins(now)?;
+    session::remove_expired(db, now)
+}
+
+impl error::ResponseError for DbError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DbError::Storage(_) | DbError::Corruption(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            DbError::Conflict(_) => StatusCode::CONFLICT,
+            DbError::NotFound => StatusCode::NOT_FOUND,
+            DbError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            debug!("{:?}", self);
+            HttpResponse::build(status).body("Database error")
+        } else {
+            HttpResponse::build(status).body(self.to_string())
+        }
+    }
+}
+
+async fn index<D: DbExt>(
+    filter: web::Query<watchlist::WatchlistFilter>,
+    id: Identity,
+    csrf: csrf::CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
     let mut ctx = tera::Context::new();
-    if let Some(username) = id.identity() {
-        let (_user_id, user) = db
-            .get_user_by_username(&username)
-            .map_err(|err| log_error(err, "Database error"))?
-            .ok_or_else(|| {
-                log_error(
-                    format!("User does not exist: {}", username),
-                    "Authentication error",
-                )
-            })?;
-        ctx.insert("user", &user);
-        let movie_ids = user
-            .friends
-            .values()
-            .flat_map(|friend_data| &friend_data.movies)
-            .collect::<HashSet<_>>();
-        let movies = movie_ids
-            .iter()
-            .map(|movie_id| -> sled::Result<(String, Movie)> {
-                Ok((movie_id.to_string(), db.get_movie(**movie_id)?.unwrap()))
-            })
-            .collect::<sled::Result<HashMap<_, _>>>()
-            .map_err(|err| log_error(err, "Database error"))?;
+    if let Some(current) = session::current_user(&id, &**db)? {
+        ctx = session::user_context(&**db, &current)?;
+        ctx.insert("csrf_token", &csrf.0);
+        let movies = watchlist::watchlist(&**db, &current.user, &filter)?
+            .into_iter()
+            .map(|entry| (entry.movie_id.to_string(), entry.movie))
+            .collect::<HashMap<_, _>>();
         ctx.insert("movies", &movies);
+        ctx.insert("filter", &filter.into_inner());
     }
-    let body = tera
-        .render("index.html", &ctx)
-        .map_err(|err| log_error(err, "Template error"))?;
-    Ok(HttpResponse::Ok().body(body))
+    render(&tera, "index.html", &ctx, HttpResponse::Ok())
 }
 
-async fn login(id: Identity, tera: Tera) -> actix_web::Result<HttpResponse> {
+async fn login(
+    id: Identity,
+    csrf: csrf::CsrfToken,
+    tera: Tera,
+    config: web::Data<config::Config>,
+) -> actix_web::Result<HttpResponse> {
     if id.identity().is_some() {
         return Ok(HttpResponse::Found().header("location", "/").finish());
     }
-    let ctx = tera::Context::new();
-    let body = tera
-        .render("login.html", &ctx)
-        .map_err(|err| log_error(err, "Template error"))?;
-    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf.0);
+    if config.oidc.issuer.is_some() {
+        ctx.insert("oidc_name", &config.oidc.name);
+    }
+    render(&tera, "login.html", &ctx, HttpResponse::Ok())
 }
 
 #[derive(Serialize, Deserialize)]
@@ -68,43 +130,88 @@ struct LoginParams {
     password: String,
 }
 
-async fn login_post(
+async fn login_post<D: DbExt>(
     params: web::Form<LoginParams>,
+    req: HttpRequest,
     id: Identity,
-    db: Db,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<config::Config>,
 ) -> actix_web::Result<HttpResponse> {
-    if let Some((_user_id, user)) = db
-        .get_user_by_username(&params.username)
-        .map_err(|err| log_error(err, "Database error"))?
-    {
-        if bcrypt::verify(&params.password, &user.password_hash)
-            .map_err(|err| log_error(err, "Verification error"))?
+    let ip = rate_limit::client_ip(&req);
+    if let Some(retry_after) = rate_limit::check(&**db, &params.username, &ip)? {
+        return Ok(rate_limit::too_many_attempts(retry_after));
+    }
+    if let Some((user_id, mut user)) = db.get_user_by_username(&params.username)? {
+        // Users that registered through OpenID Connect don't have a password, which never matches
+        if password::verify_and_upgrade(
+            &**db,
+            &config.password,
+            user_id,
+            &mut user,
+            &params.password,
+        )
+        .await?
         {
-            id.remember(user.username);
+            if user.disabled {
+                rate_limit::cancel(&**db, &params.username, &ip)?;
+                return account::render_disabled(&tera);
+            }
+            if totp::is_enabled(&user) {
+                // Counted again when the code is entered
+                rate_limit::cancel(&**db, &params.username, &ip)?;
+                return Ok(totp::start_login(&**db, &config, user_id)?);
+            }
+            rate_limit::record_success(&**db, &params.username, &ip)?;
+            session::start_session(&id, &**db, user_id, &req)?;
             return Ok(HttpResponse::Found().header("location", "/").finish());
         }
     }
+    rate_limit::record_failure(&**db, &params.username, &ip)?;
     Ok(HttpResponse::Found()
         .header("location", "/login?wrong_password")
         .finish())
 }
 
-async fn logout(id: Identity) -> actix_web::Result<HttpResponse> {
-    id.forget();
+async fn logout<D: DbExt>(id: Identity, db: web::Data<D>) -> actix_web::Result<HttpResponse> {
+    session::end_session(&id, &**db)?;
     Ok(HttpResponse::Found()
         .header("location", "/login?logout")
         .finish())
 }
 
-async fn register(id: Identity, tera: Tera) -> actix_web::Result<HttpResponse> {
+fn render_register(
+    tera: &tera::Tera,
+    csrf: &csrf::CsrfToken,
+    username: &str,
+    email: &str,
+    errors: &validation::FieldErrors,
+    response: actix_web::dev::HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("username", username);
+    ctx.insert("email", email);
+    ctx.insert("errors", errors);
+    render(tera, "register.html", &ctx, response)
+}
+
+async fn register(
+    id: Identity,
+    csrf: csrf::CsrfToken,
+    tera: Tera,
+) -> actix_web::Result<HttpResponse> {
     if id.identity().is_some() {
         return Ok(HttpResponse::Found().header("location", "/").finish());
     }
-    let ctx = tera::Context::new();
-    let body = tera
-        .render("register.html", &ctx)
-        .map_err(|err| log_error(err, "Template error"))?;
-    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+    render_register(
+        &tera,
+        &csrf,
+        "",
+        "",
+        &validation::FieldErrors::default(),
+        HttpResponse::Ok(),
+    )
 }
 
 #[derive(Serialize, Deserialize)]
@@ -112,93 +219,2040 @@ struct RegisterParams {
     username: String,
     password: String,
     password_repeat: String,
+    #[serde(default)]
+    email: String,
 }
 
-async fn register_post(
+async fn register_post<D: DbExt>(
     params: web::Form<RegisterParams>,
-    db: Db,
+    csrf: csrf::CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    mailer: web::Data<mail::Mailer>,
+    config: web::Data<config::Config>,
 ) -> actix_web::Result<HttpResponse> {
-    if params.password != params.password_repeat {
-        return Ok(HttpResponse::UnprocessableEntity().finish());
-    }
-    if params.password.len() < 8 {
-        return Ok(HttpResponse::UnprocessableEntity().finish());
-    }
     let RegisterParams {
-        username, password, ..
+        username,
+        password,
+        password_repeat,
+        email,
     } = params.into_inner();
-    if let Some(id) = db
-        .add_user(&User {
-            username: username,
-            password_hash: bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap(),
-            friends: HashMap::new(),
-        })
-        .map_err(|err| log_error(err, "Database error"))?
+    let username = username.trim().to_owned();
+    let email = email.trim().to_owned();
+    let mut errors = validation::FieldErrors::default();
+    validation::validate_username(&username, &mut errors);
+    if !email.is_empty() {
+        validation::validate_email(&email, &mut errors);
+    }
+    validation::validate_password(
+        &password,
+        &username,
+        password::max_bytes(&config.password),
+        &mut errors,
+    );
+    if password != password_repeat {
+        errors.add("password_repeat", "Passwords don't match");
+    }
+    if errors.is_empty() && db.get_user_by_username(&username)?.is_some() {
+        errors.add("username", "This name is already taken");
+        return render_register(
+            &tera,
+            &csrf,
+            &username,
+            &email,
+            &errors,
+            HttpResponse::Conflict(),
+        );
+    }
+    if !errors.is_empty() {
+        return render_register(
+            &tera,
+            &csrf,
+            &username,
+            &email,
+            &errors,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    let user = User {
+        username: username.clone(),
+        password_hash: password::hash_blocking(&config.password, &password).await?,
+        email: Some(email.clone()).filter(|email| !email.is_empty()),
+        ..Default::default()
+    };
+    let id = match db.add_user(&user) {
+        Ok(id) => id,
+        // Somebody else registered the name in the meantime
+        Err(DbError::Conflict(_)) => {
+            errors.add("username", "This name is already taken");
+            return render_register(
+                &tera,
+                &csrf,
+                &username,
+                &email,
+                &errors,
+                HttpResponse::Conflict(),
+            );
+        }
+        Err(err) => return Err(err.into()),
+    };
+    log::info!("register {}", id);
+    // The account exists already, so this mustn't look like the registration failed. A new link
+    // can be requested in the email settings.
+    if account::send_verification(&**db, &mailer, &config, id, &user)
+        .await
+        .is_err()
     {
-        log::info!("register {}", id);
-        Ok(HttpResponse::Found().header("location", "/").finish())
-    } else {
-        Ok(HttpResponse::Conflict().finish())
+        log::warn!("Can't send the verification email to user {}", id);
     }
+    Ok(HttpResponse::Found().header("location", "/").finish())
+}
+
+fn routes<D: DbExt + 'static>(cfg: &mut web::ServiceConfig) {
+    api::routes::<D>(cfg);
+    cfg.route("/", web::get().to(index::<D>))
+        .route("/login", web::get().to(login))
+        .route("/login", web::post().to(login_post::<D>))
+        .route("/login/oidc", web::get().to(oidc::login::<D>))
+        .route("/login/oidc/callback", web::get().to(oidc::callback::<D>))
+        .route(totp::LOGIN_PATH, web::get().to(totp::login))
+        .route(totp::LOGIN_PATH, web::post().to(totp::login_post::<D>))
+        .route("/logout", web::post().to(logout::<D>))
+        .route("/register", web::get().to(register))
+        .route("/register", web::post().to(register_post::<D>))
+        .route("/sessions", web::get().to(session::sessions::<D>))
+        .route(
+            "/sessions/revoke",
+            web::post().to(session::revoke_session::<D>),
+        )
+        .route(
+            "/sessions/logout_everywhere",
+            web::post().to(session::logout_everywhere::<D>),
+        )
+        .route(
+            "/settings/email",
+            web::get().to(account::email_settings::<D>),
+        )
+        .route(
+            "/settings/email",
+            web::post().to(account::email_settings_post::<D>),
+        )
+        .route(
+            "/settings/credentials",
+            web::get().to(account::credentials::<D>),
+        )
+        .route(
+            "/settings/password",
+            web::post().to(account::change_password_post::<D>),
+        )
+        .route(
+            "/settings/username",
+            web::post().to(account::change_username_post::<D>),
+        )
+        .route(
+            "/settings/display_name",
+            web::post().to(account::display_name_post::<D>),
+        )
+        .route(
+            "/settings/notifications",
+            web::post().to(account::notification_settings_post::<D>),
+        )
+        .route("/settings/tokens", web::get().to(api_token::tokens::<D>))
+        .route(
+            "/settings/tokens",
+            web::post().to(api_token::create_token::<D>),
+        )
+        .route(
+            "/settings/tokens/revoke",
+            web::post().to(api_token::revoke_token::<D>),
+        )
+        .route("/settings/2fa", web::get().to(totp::settings::<D>))
+        .route(
+            "/settings/2fa/enroll",
+            web::post().to(totp::enroll_post::<D>),
+        )
+        .route(
+            "/settings/2fa/confirm",
+            web::post().to(totp::confirm_post::<D>),
+        )
+        .route(
+            "/settings/2fa/recovery_codes",
+            web::post().to(totp::recovery_codes_post::<D>),
+        )
+        .route(
+            "/settings/2fa/disable",
+            web::post().to(totp::disable_post::<D>),
+        )
+        .route("/settings/identities", web::get().to(oidc::identities::<D>))
+        .route(
+            "/settings/identities/link",
+            web::post().to(oidc::link_post::<D>),
+        )
+        .route(
+            "/settings/identities/unlink",
+            web::post().to(oidc::unlink_post::<D>),
+        )
+        .route("/settings/account", web::get().to(privacy::account::<D>))
+        .route(
+            "/settings/account/export",
+            web::get().to(privacy::export_data::<D>),
+        )
+        .route(
+            "/settings/account/delete",
+            web::post().to(privacy::delete_post::<D>),
+        )
+        .route("/admin", web::get().to(admin::dashboard::<D>))
+        .route("/admin/users", web::get().to(admin::users::<D>))
+        .route(
+            "/admin/users/disable",
+            web::post().to(admin::disable_post::<D>),
+        )
+        .route("/admin/users/role", web::post().to(admin::role_post::<D>))
+        .route(
+            "/admin/users/reset_password",
+            web::post().to(admin::reset_password_post::<D>),
+        )
+        .route(
+            "/admin/users/delete",
+            web::post().to(admin::delete_post::<D>),
+        )
+        .route("/admin/movies", web::get().to(admin::movies::<D>))
+        .route(
+            "/admin/movies/delete",
+            web::post().to(admin::delete_movie_post::<D>),
+        )
+        .route("/movies/search", web::get().to(search::movies::<D>))
+        .route(
+            "/movies/search/recommend",
+            web::post().to(search::recommend_post::<D>),
+        )
+        .route("/users/search", web::get().to(search::users::<D>))
+        .route("/groups", web::get().to(groups::groups::<D>))
+        .route("/groups", web::post().to(groups::create_post::<D>))
+        .route(
+            "/groups/movies",
+            web::post().to(groups::add_movie_post::<D>),
+        )
+        .route("/groups/join/{code}", web::get().to(groups::join::<D>))
+        .route(
+            "/groups/join/{code}",
+            web::post().to(groups::join_post::<D>),
+        )
+        .route("/groups/{id}", web::get().to(groups::group::<D>))
+        .route("/groups/{id}/vote", web::post().to(groups::vote_post::<D>))
+        .route(
+            "/groups/{id}/remove_movie",
+            web::post().to(groups::remove_movie_post::<D>),
+        )
+        .route("/groups/{id}/pick", web::post().to(groups::pick_post::<D>))
+        .route(
+            "/groups/{id}/invite",
+            web::post().to(groups::reset_invite_post::<D>),
+        )
+        .route(
+            "/groups/{id}/remove_member",
+            web::post().to(groups::remove_member_post::<D>),
+        )
+        .route(
+            "/groups/{id}/leave",
+            web::post().to(groups::leave_post::<D>),
+        )
+        .route(
+            "/groups/{id}/delete",
+            web::post().to(groups::delete_post::<D>),
+        )
+        .route("/recommend", web::post().to(watchlist::recommend_post::<D>))
+        .route(
+            "/friends/add",
+            web::post().to(watchlist::add_friend_post::<D>),
+        )
+        .route(
+            "/notifications",
+            web::get().to(notifications::notifications::<D>),
+        )
+        .route(
+            "/notifications/read_all",
+            web::post().to(notifications::mark_all_read::<D>),
+        )
+        .route(
+            "/notifications/{id}/read",
+            web::post().to(notifications::mark_read::<D>),
+        )
+        .route("/verify_email", web::get().to(account::verify_email::<D>))
+        .route("/forgot_password", web::get().to(account::forgot_password))
+        .route(
+            "/forgot_password",
+            web::post().to(account::forgot_password_post::<D>),
+        )
+        .route(
+            "/reset_password",
+            web::get().to(account::reset_password::<D>),
+        )
+        .route(
+            "/reset_password",
+            web::post().to(account::reset_password_post::<D>),
+        );
+}
+
+/// `nextflix import <imdb|tmdb> <file>`
+fn import_command(db: &sled::Db, args: &[String]) -> std::io::Result<()> {
+    let usage = || std::io::Error::new(std::io::ErrorKind::InvalidInput, config::USAGE);
+    let format = args
+        .first()
+        .ok_or_else(usage)?
+        .parse::<import::Format>()
+        .map_err(|_| usage())?;
+    let file = args.get(1).ok_or_else(usage)?;
+    import::import(db, format, file).map_err(|err| std::io::Error::other(err.to_string()))?;
+    Ok(())
+}
+
+/// `nextflix disable-2fa <username>`, for users that lost their second factor
+fn disable_2fa_command(db: &sled::Db, args: &[String]) -> std::io::Result<()> {
+    let username = args
+        .first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, config::USAGE))?;
+    if !totp::disable(db, username).map_err(std::io::Error::other)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No user named {}", username),
+        ));
+    }
+    println!("Disabled two-factor authentication for {}", username);
+    Ok(())
+}
+
+/// `nextflix grant-admin <username>`, to appoint the first admin
+fn grant_admin_command(db: &sled::Db, args: &[String]) -> std::io::Result<()> {
+    let username = args
+        .first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, config::USAGE))?;
+    if !admin::grant(db, username).map_err(std::io::Error::other)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No user named {}", username),
+        ));
+    }
+    println!("{} is now an admin", username);
+    Ok(())
+}
+
+fn add_demo_data<D: DbExt>(
+    db: &D,
+    password_config: &config::PasswordConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let pulp_fiction_id = db.add_movie(&Movie {
+        name: "Pulp Fiction".to_owned(),
+        year: Some(1994),
+        runtime: Some(154),
+        genres: vec!["Crime".to_owned(), "Drama".to_owned()],
+        imdb_id: Some("tt0110912".to_owned()),
+        tmdb_id: Some(680),
+        ..Default::default()
+    })?;
+    let admin_id = db.add_user(&User {
+        username: "admin".to_owned(),
+        password_hash: password::hash(password_config, "password")?,
+        email: Some("admin@localhost".to_owned()),
+        email_verified: true,
+        role: Role::Admin,
+        ..Default::default()
+    })?;
+    db.add_user(&User {
+        username: "foo".to_owned(),
+        password_hash: password::hash(password_config, "1234")?,
+        friends: vec![(
+            admin_id,
+            FriendData {
+                movies: vec![pulp_fiction_id],
+            },
+        )]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    })?;
+    Ok(())
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let private_key = [0u8; 32];
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (config, args) = config::Config::load(&args).map_err(|err| {
+        eprintln!("{}", err);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    })?;
 
-    std::env::set_var("RUST_LOG", "nextflix=debug,actix_web=info");
     std::env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
-    let db = sled::Config::new().temporary(true).open().unwrap();
-    let pulp_fiction_id = db
-        .add_movie(&Movie {
-            name: "Pulp Fiction".to_owned(),
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+
+    let db = config.open_db().map_err(std::io::Error::other)?;
+    schema::migrate(&db).map_err(std::io::Error::other)?;
+
+    match args.first().map(String::as_str) {
+        Some("import") => return import_command(&db, &args[1..]),
+        Some("disable-2fa") => return disable_2fa_command(&db, &args[1..]),
+        Some("grant-admin") => return grant_admin_command(&db, &args[1..]),
+        Some(_) => {
+            eprintln!("{}", config::USAGE);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Unknown command",
+            ));
+        }
+        None => {}
+    }
+
+    if config.database.temporary {
+        add_demo_data(&db, &config.password).map_err(std::io::Error::other)?;
+    }
+    // Afterwards the notification worker repeats this every `notify::CLEANUP_INTERVAL`
+    remove_expired(&db, model::unix_time()).map_err(std::io::Error::other)?;
+
+    let keys = identity::Keys::load(&config.cookie, config.database.temporary)?;
+    // Loaded once, so that all workers use the same random key of a temporary database
+    let totp = web::Data::new(totp::Totp::load(
+        &config.two_factor,
+        config.database.temporary,
+    )?);
+    let bind = config.bind.clone();
+    let mailer = mail::Mailer::new(&config.mail);
+    notify::spawn_worker(db.clone(), mailer.clone(), config.public_url.clone())?;
+    HttpServer::new(move || {
+        let tera = tera::Tera::new(&config.template_dir.join("**/*").to_string_lossy()).unwrap();
+        App::new()
+            .wrap(Logger::default())
+            .wrap(csrf::Csrf::<sled::Db>::new(config.cookie.secure))
+            .wrap(IdentityService::new(identity::RotatingCookiePolicy::new(
+                &keys,
+                &config.cookie,
+            )))
+            .data(tera)
+            .data(db.clone())
+            .data(mailer.clone())
+            .data(config.clone())
+            .app_data(totp.clone())
+            .configure(routes::<sled::Db>)
+    })
+    .bind(bind)?
+    .run()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::Cookie, test};
+    use memory_db::MemoryDb;
+
+    macro_rules! test_app {
+        ($db:expr) => {
+            test_app!($db, mail::Mailer::memory().0)
+        };
+        ($db:expr, $mailer:expr) => {
+            test_app!($db, $mailer, test_config())
+        };
+        ($db:expr, $mailer:expr, $config:expr) => {
+            test::init_service(
+                App::new()
+                    .wrap(csrf::Csrf::<MemoryDb>::new(false))
+                    .wrap(IdentityService::new(identity::RotatingCookiePolicy::new(
+                        &identity::Keys {
+                            current: identity::generate_key(),
+                            old: vec![],
+                        },
+                        &config::CookieConfig::default(),
+                    )))
+                    .data(
+                        tera::Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*"))
+                            .unwrap(),
+                    )
+                    .data($db)
+                    .data($mailer)
+                    .data($config)
+                    .data(totp::Totp::new(&identity::generate_key(), "nextflix"))
+                    .configure(routes::<MemoryDb>),
+            )
+            .await
+        };
+    }
+
+    /// Default configuration with cheap password hashing
+    fn test_config() -> config::Config {
+        config::Config {
+            password: config::PasswordConfig {
+                argon2_memory: 64,
+                argon2_iterations: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn add_user(db: &MemoryDb, username: &str, password: &str) -> u64 {
+        db.add_user(&User {
+            username: username.to_owned(),
+            password_hash: bcrypt::hash(password, 4).unwrap(),
+            ..Default::default()
         })
         .unwrap()
-        .unwrap();
-    let admin_id = db
-        .add_user(&User {
-            username: "admin".to_owned(),
-            password_hash: bcrypt::hash("password", bcrypt::DEFAULT_COST).unwrap(),
-            friends: HashMap::new(),
+    }
+
+    /// POST request with a valid CSRF token
+    fn post(uri: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .cookie(Cookie::new(csrf::COOKIE_NAME, "token"))
+            .header(csrf::HEADER_NAME, "token")
+    }
+
+    async fn json_body(resp: actix_web::dev::ServiceResponse) -> serde_json::Value {
+        serde_json::from_slice(&test::read_body(resp).await).unwrap()
+    }
+
+    fn login_request(username: &str, password: &str) -> test::TestRequest {
+        post("/login").set_form(&LoginParams {
+            username: username.to_owned(),
+            password: password.to_owned(),
         })
-        .unwrap()
-        .unwrap();
-    let _foo_id = db
-        .add_user(&User {
+    }
+
+    fn auth_cookie(resp: &actix_web::dev::ServiceResponse) -> Cookie<'static> {
+        resp.response()
+            .cookies()
+            .find(|c| c.name() == "auth-cookie")
+            .unwrap()
+            .into_owned()
+    }
+
+    #[actix_rt::test]
+    async fn login_and_watchlist() {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                genres: vec!["Crime".to_owned()],
+                ..Default::default()
+            })
+            .unwrap();
+        let admin_id = add_user(&db, "admin", "password");
+        db.add_user(&User {
             username: "foo".to_owned(),
-            password_hash: bcrypt::hash("1234", bcrypt::DEFAULT_COST).unwrap(),
+            password_hash: bcrypt::hash("12345678", 4).unwrap(),
             friends: vec![(
                 admin_id,
                 FriendData {
-                    movies: vec![pulp_fiction_id],
+                    movies: vec![movie_id],
                 },
             )]
             .into_iter()
             .collect(),
+            ..Default::default()
         })
-        .unwrap()
         .unwrap();
+        let mut app = test_app!(db);
 
-    HttpServer::new(move || {
-        let tera = tera::Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).unwrap();
-        App::new()
-            .wrap(Logger::default())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&private_key)
-                    .name("auth-cookie")
-                    .secure(false),
-            ))
-            .data(tera)
-            .data(db.clone())
-            .route("/", web::get().to(index))
-            .route("/login", web::get().to(login))
-            .route("/login", web::post().to(login_post))
-            .route("/logout", web::get().to(logout))
-            .route("/register", web::get().to(register))
-            .route("/register", web::post().to(register_post))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+        let req = post("/login")
+            .set_form(&LoginParams {
+                username: "foo".to_owned(),
+                password: "wrong".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+
+        let req = post("/login")
+            .set_form(&LoginParams {
+                username: "foo".to_owned(),
+                password: "12345678".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+        let cookie = auth_cookie(&resp);
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("Pulp Fiction"));
+
+        let req = test::TestRequest::get()
+            .uri("/?genre=comedy")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(!std::str::from_utf8(&body).unwrap().contains("Pulp Fiction"));
+
+        // The filter form submits fields that were left empty as empty strings
+        for uri in &[
+            "/?genre=&year=",
+            "/?genre=Crime&year=",
+            "/?genre=+&year=",
+            "/api/v1/watchlist?genre=&year=",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+            let body = test::read_body(resp).await;
+            assert!(
+                std::str::from_utf8(&body).unwrap().contains("Pulp Fiction"),
+                "{}",
+                uri
+            );
+        }
+        let req = test::TestRequest::get()
+            .uri("/?genre=&year=1994x")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn logout_everywhere() {
+        let db = MemoryDb::new();
+        add_user(&db, "foo", "12345678");
+        let mut app = test_app!(db);
+
+        let mut cookies = Vec::new();
+        for _ in 0..2 {
+            let req = post("/login")
+                .set_form(&LoginParams {
+                    username: "foo".to_owned(),
+                    password: "12345678".to_owned(),
+                })
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            cookies.push(auth_cookie(&resp));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(cookies[0].clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(
+            std::str::from_utf8(&body)
+                .unwrap()
+                .matches("name=\"session_id\"")
+                .count(),
+            2
+        );
+
+        let req = post("/sessions/logout_everywhere")
+            .cookie(cookies[0].clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        for cookie in cookies {
+            let req = test::TestRequest::get()
+                .uri("/sessions")
+                .cookie(cookie)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.headers().get("location").unwrap(), "/login");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn logout_and_expiry() {
+        let db = MemoryDb::new();
+        let user_id = add_user(&db, "foo", "12345678");
+        let mut app = test_app!(db.clone());
+
+        let resp =
+            test::call_service(&mut app, login_request("foo", "12345678").to_request()).await;
+        let cookie = auth_cookie(&resp);
+        // Logging out needs a POST with a CSRF token, so that other sites can't log users out
+        let req = test::TestRequest::get()
+            .uri("/logout")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_ne!(resp.status(), StatusCode::FOUND);
+        let req = test::TestRequest::post()
+            .uri("/logout")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(db.get_sessions_by_user(user_id).unwrap().len(), 1);
+        let req = post("/logout").cookie(cookie).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login?logout");
+        assert!(db.get_sessions_by_user(user_id).unwrap().is_empty());
+
+        let resp =
+            test::call_service(&mut app, login_request("foo", "12345678").to_request()).await;
+        let cookie = auth_cookie(&resp);
+        let (session_id, _) = db.get_sessions_by_user(user_id).unwrap().remove(0);
+        db.touch_session(&session_id, model::unix_time() - session::IDLE_TTL)
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login");
+        assert!(db.get_session(&session_id).unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn login_throttling() {
+        let db = MemoryDb::new();
+        add_user(&db, "foo", "12345678");
+        let mut app = test_app!(db);
+
+        let login = |password: &str| {
+            post("/login")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .set_form(&LoginParams {
+                    username: "foo".to_owned(),
+                    password: password.to_owned(),
+                })
+                .to_request()
+        };
+        for _ in 0..3 {
+            let resp = test::call_service(&mut app, login("wrong")).await;
+            assert_eq!(resp.status(), StatusCode::FOUND);
+        }
+        let resp = test::call_service(&mut app, login("12345678")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().get("retry-after").is_some());
+    }
+
+    #[actix_rt::test]
+    async fn register_without_mail_server() {
+        let db = MemoryDb::new();
+        // Nothing listens on port 1
+        let mailer = mail::Mailer::new(&config::MailConfig {
+            transport: config::MailTransport::Smtp,
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: 1,
+            ..Default::default()
+        });
+        let mut app = test_app!(db.clone(), mailer);
+        let req = post("/register")
+            .set_form(&RegisterParams {
+                username: "alice".to_owned(),
+                password: "correct horse".to_owned(),
+                password_repeat: "correct horse".to_owned(),
+                email: "alice@example.com".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert!(db.get_user_by_username("alice").unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn register_errors() {
+        let db = MemoryDb::new();
+        add_user(&db, "alice", "password");
+        let mut app = test_app!(db);
+
+        let register = |username: &str, password: &str, password_repeat: &str| {
+            post("/register")
+                .set_form(&RegisterParams {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                    password_repeat: password_repeat.to_owned(),
+                    email: String::new(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, register("foo", "correct horse", "1234")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Passwords don&#x27;t match"));
+        assert!(body.contains("value=\"foo\""));
+        let resp = test::call_service(&mut app, register("foo", "1234", "1234")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(
+            &mut app,
+            register("admin", "correct horse", "correct horse"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(
+            &mut app,
+            register("Alice", "correct horse", "correct horse"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&RegisterParams {
+                username: "foo".to_owned(),
+                password: "correct horse".to_owned(),
+                password_repeat: "correct horse".to_owned(),
+                email: String::new(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    /// Token of the link in the last sent email
+    fn token_from_email(sent: &std::sync::Mutex<Vec<mail::Email>>) -> String {
+        let sent = sent.lock().unwrap();
+        let body = &sent.last().unwrap().body;
+        let start = body.find("token=").unwrap() + "token=".len();
+        body[start..].split_whitespace().next().unwrap().to_owned()
+    }
+
+    #[actix_rt::test]
+    async fn verify_email_and_reset_password() {
+        let db = MemoryDb::new();
+        let (mailer, sent) = mail::Mailer::memory();
+        let mut app = test_app!(db.clone(), mailer.clone());
+        // Reset links are sent by the notification worker
+        let deliver = || notify::deliver_due(&db, &mailer, "", model::unix_time()).unwrap();
+
+        let req = post("/register")
+            .set_form(&RegisterParams {
+                username: "alice".to_owned(),
+                password: "correct horse".to_owned(),
+                password_repeat: "correct horse".to_owned(),
+                email: "alice@example.com".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(sent.lock().unwrap()[0].to, "alice@example.com");
+
+        // Unverified addresses can't be used to reset the password
+        let forgot_password = || {
+            post("/forgot_password")
+                .set_form(&account::EmailParams {
+                    email: "Alice@example.com".to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, forgot_password()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        deliver();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        let uri = format!("/verify_email?token={}", token_from_email(&sent));
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        test::call_service(&mut app, forgot_password()).await;
+        assert_eq!(deliver(), 1);
+        assert_eq!(sent.lock().unwrap().len(), 2);
+        let token = token_from_email(&sent);
+        let reset_password = |password: &str| {
+            post("/reset_password")
+                .set_form(&account::ResetPasswordParams {
+                    token: token.clone(),
+                    password: password.to_owned(),
+                    password_repeat: password.to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, reset_password("short")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(&mut app, reset_password("battery staple")).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?password_reset"
+        );
+        let resp = test::call_service(&mut app, reset_password("battery staple")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = post("/login")
+            .set_form(&LoginParams {
+                username: "alice".to_owned(),
+                password: "battery staple".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+    }
+    #[actix_rt::test]
+    async fn notification_center() {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        add_user(&db, "alice", "password");
+        add_user(&db, "bob", "password");
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let resp =
+            test::call_service(&mut app, login_request("bob", "password").to_request()).await;
+        let bob = auth_cookie(&resp);
+        let page = |uri: &str, cookie: &Cookie<'static>| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        let req = post("/friends/add")
+            .cookie(alice.clone())
+            .set_form(&watchlist::AddFriendParams {
+                username: "bob".to_owned(),
+            })
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let body = test::read_response(&mut app, page("/", &bob)).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("Notifications (1)"));
+        let body = test::read_response(&mut app, page("/notifications", &bob)).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("alice added you as a friend"));
+        assert!(body.contains("Add back"));
+
+        let req = post("/recommend")
+            .cookie(bob.clone())
+            .set_form(&watchlist::RecommendParams {
+                friend: "alice".to_owned(),
+                movie_id,
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let body = test::read_response(&mut app, page("/notifications", &alice)).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("bob recommends Pulp Fiction"));
+
+        let req = post("/notifications/read_all")
+            .cookie(alice.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let body = test::read_response(&mut app, page("/", &alice)).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Pulp Fiction"));
+        assert!(!body.contains("Notifications ("));
+    }
+
+    #[actix_rt::test]
+    async fn json_api() {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                year: Some(1994),
+                ..Default::default()
+            })
+            .unwrap();
+        add_user(&db, "alice", "password");
+        let bob_id = add_user(&db, "bob", "password");
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(alice.clone())
+                .to_request()
+        };
+
+        let resp = test::call_service(
+            &mut app,
+            test::TestRequest::get().uri("/api/v1/me").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_response_json(&mut app, get("/api/v1/me")).await;
+        assert_eq!(body["username"], "alice");
+        let body: serde_json::Value =
+            test::read_response_json(&mut app, get("/api/v1/movies/search?q=Pulp")).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["results"][0]["id"], movie_id);
+        assert_eq!(body["results"][0]["name"], "Pulp Fiction");
+
+        let resp = test::call_service(&mut app, get("/api/v1/movies/12345")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "not_found");
+        let resp = test::call_service(&mut app, get("/api/v1/movies/abc")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Without CSRF token
+        let req = test::TestRequest::post()
+            .uri("/api/v1/friends")
+            .cookie(alice.clone())
+            .set_json(&watchlist::AddFriendParams {
+                username: "bob".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "csrf");
+
+        let req = post("/api/v1/friends")
+            .cookie(alice.clone())
+            .set_json(&watchlist::AddFriendParams {
+                username: "bob".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: serde_json::Value =
+            test::read_response_json(&mut app, get("/api/v1/friends")).await;
+        assert_eq!(body[0]["id"], bob_id);
+
+        // Bob hasn't added alice
+        let req = post("/api/v1/recommendations")
+            .cookie(alice.clone())
+            .set_json(&watchlist::RecommendParams {
+                friend: "bob".to_owned(),
+                movie_id,
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "invalid");
+
+        let req = post("/api/v1/movies")
+            .cookie(alice.clone())
+            .header("content-type", "application/json")
+            .set_payload("{\"name\": 1}")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(resp).await;
+        assert_eq!(body["error"]["code"], "bad_request");
+    }
+
+    #[actix_rt::test]
+    async fn api_tokens() {
+        let db = MemoryDb::new();
+        add_user(&db, "alice", "password");
+        add_user(&db, "bob", "password");
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let create_token = |write: bool| {
+            post("/settings/tokens")
+                .cookie(alice.clone())
+                .set_form(&api_token::CreateTokenParams {
+                    name: "script".to_owned(),
+                    read: Some("on".to_owned()),
+                    write: if write { Some("on".to_owned()) } else { None },
+                    expires_in_days: 30,
+                })
+                .to_request()
+        };
+        let body = test::read_response(&mut app, create_token(false)).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let start = body.find("nfx_").unwrap();
+        let read_token = body[start..start + body[start..].find('<').unwrap()].to_owned();
+
+        let bearer = |token: &str| format!("Bearer {}", token);
+        let req = test::TestRequest::get()
+            .uri("/api/v1/me")
+            .header("authorization", bearer(&read_token))
+            .to_request();
+        let body = json_body(test::call_service(&mut app, req).await).await;
+        assert_eq!(body["username"], "alice");
+
+        // Read only, no CSRF token needed
+        let add_bob = |token: &str| {
+            test::TestRequest::post()
+                .uri("/api/v1/friends")
+                .header("authorization", bearer(token))
+                .set_json(&watchlist::AddFriendParams {
+                    username: "bob".to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, add_bob(&read_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json_body(resp).await["error"]["code"], "forbidden");
+
+        let body = test::read_response(&mut app, create_token(true)).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let start = body.find("nfx_").unwrap();
+        let write_token = body[start..start + body[start..].find('<').unwrap()].to_owned();
+        let resp = test::call_service(&mut app, add_bob(&write_token)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // Bearer tokens are only accepted by the API, so they don't replace the CSRF token of forms
+        let req = test::TestRequest::post()
+            .uri("/groups")
+            .cookie(alice.clone())
+            .header("authorization", bearer(&write_token))
+            .set_form(&[("name", "Movie night")])
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/settings/tokens")
+            .cookie(alice.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let marker = "name=\"token_id\" value=\"";
+        for token_id in body.split(marker).skip(1) {
+            let token_id = &token_id[..token_id.find('"').unwrap()];
+            let req = post("/settings/tokens/revoke")
+                .cookie(alice.clone())
+                .set_form(&api_token::RevokeTokenParams {
+                    token_id: token_id.to_owned(),
+                })
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        // Revoked tokens don't exempt the request from the CSRF check either
+        let resp = test::call_service(&mut app, add_bob(&write_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json_body(resp).await["error"]["code"], "csrf");
+        let resp = test::call_service(&mut app, add_bob("nfx_invalid")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn oidc_login() {
+        let issuer = oidc::mock::MockIssuer::start();
+        let db = MemoryDb::new();
+        add_user(&db, "bob", "password");
+        db.add_user(&User {
+            username: "carol".to_owned(),
+            identities: vec![ExternalIdentity {
+                issuer: issuer.issuer.clone(),
+                subject: "carol-sub".to_owned(),
+            }],
+            two_factor: Some(TwoFactor {
+                secret: Vec::new(),
+                enabled: true,
+                recovery_codes: Vec::new(),
+                last_step: 0,
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut config = test_config();
+        config.oidc.issuer = Some(issuer.issuer.clone());
+        config.oidc.client_secret = Some(oidc::mock::CLIENT_SECRET.to_owned());
+        let mut app = test_app!(db, mail::Mailer::memory().0, config);
+
+        // Returns the query string the provider redirects back with and the state cookie
+        let authorize = |resp: &actix_web::dev::ServiceResponse, subject: &str| {
+            assert_eq!(resp.status(), StatusCode::FOUND);
+            let location = resp.headers().get("location").unwrap().to_str().unwrap();
+            assert!(location.starts_with(&format!("{}/authorize?", issuer.issuer)));
+            let state = resp
+                .response()
+                .cookies()
+                .find(|c| c.name() == "oidc_state")
+                .unwrap()
+                .into_owned();
+            (issuer.authorize(location, subject, "alice"), state)
+        };
+        let callback =
+            |query: &str| test::TestRequest::get().uri(&format!("/login/oidc/callback?{}", query));
+        let start = || test::TestRequest::get().uri("/login/oidc").to_request();
+        let me = |cookie: &Cookie<'static>| {
+            test::TestRequest::get()
+                .uri("/api/v1/me")
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        let (query, state) = authorize(&test::call_service(&mut app, start()).await, "alice-sub");
+        // Only works in the browser that started the login
+        let resp = test::call_service(&mut app, callback(&query).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let (query, state2) = authorize(&test::call_service(&mut app, start()).await, "alice-sub");
+        let resp = test::call_service(&mut app, callback(&query).cookie(state).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = test::call_service(
+            &mut app,
+            callback(&query).cookie(state2.clone()).to_request(),
+        )
+        .await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+        let alice = auth_cookie(&resp);
+        let body = json_body(test::call_service(&mut app, me(&alice)).await).await;
+        assert_eq!(body["username"], "alice");
+        let alice_id = body["id"].clone();
+        // Codes can only be used once
+        let resp = test::call_service(&mut app, callback(&query).cookie(state2).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Logging in again finds the same user
+        let (query, state) = authorize(&test::call_service(&mut app, start()).await, "alice-sub");
+        let resp = test::call_service(&mut app, callback(&query).cookie(state).to_request()).await;
+        let body = json_body(test::call_service(&mut app, me(&auth_cookie(&resp))).await).await;
+        assert_eq!(body["id"], alice_id);
+        // There is no password to log in with
+        let resp = test::call_service(&mut app, login_request("alice", "").to_request()).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+        // And the only identity can't be unlinked
+        let unlink = |cookie: &Cookie<'static>, subject: &str| {
+            post("/settings/identities/unlink")
+                .cookie(cookie.clone())
+                .set_form(&oidc::UnlinkParams {
+                    issuer: issuer.issuer.clone(),
+                    subject: subject.to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, unlink(&alice, "alice-sub")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // The second factor is still required
+        let (query, state) = authorize(&test::call_service(&mut app, start()).await, "carol-sub");
+        let resp = test::call_service(&mut app, callback(&query).cookie(state).to_request()).await;
+        assert_eq!(resp.headers().get("location").unwrap(), totp::LOGIN_PATH);
+        assert!(resp.response().cookies().all(|c| c.name() != "auth-cookie"));
+        assert!(resp.response().cookies().any(|c| c.name() == "login_2fa"));
+
+        // Bob links another identity to his account
+        let resp =
+            test::call_service(&mut app, login_request("bob", "password").to_request()).await;
+        let bob = auth_cookie(&resp);
+        let link = || {
+            post("/settings/identities/link")
+                .cookie(bob.clone())
+                .to_request()
+        };
+        let (query, state) = authorize(&test::call_service(&mut app, link()).await, "bob-sub");
+        let req = callback(&query)
+            .cookie(state)
+            .cookie(bob.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/settings/identities"
+        );
+        let (query, state) = authorize(&test::call_service(&mut app, start()).await, "bob-sub");
+        let resp = test::call_service(&mut app, callback(&query).cookie(state).to_request()).await;
+        let body = json_body(test::call_service(&mut app, me(&auth_cookie(&resp))).await).await;
+        assert_eq!(body["username"], "bob");
+
+        // Alice's identity can't be linked to Bob as well
+        let (query, state) = authorize(&test::call_service(&mut app, link()).await, "alice-sub");
+        let req = callback(&query)
+            .cookie(state)
+            .cookie(bob.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = test::call_service(&mut app, unlink(&bob, "bob-sub")).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let req = test::TestRequest::get()
+            .uri("/settings/identities")
+            .cookie(bob)
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(!std::str::from_utf8(&body).unwrap().contains("bob-sub"));
+    }
+
+    #[actix_rt::test]
+    async fn two_factor() {
+        let db = MemoryDb::new();
+        add_user(&db, "alice", "password");
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let code_request = |uri: &str, code: &str| {
+            post(uri).set_form(&totp::CodeParams {
+                code: code.to_owned(),
+            })
+        };
+        // Text of the first `<code>` element after `after`
+        let code_after = |body: &[u8], after: &str| {
+            let body = std::str::from_utf8(body).unwrap();
+            let body = &body[body.find(after).unwrap()..];
+            let start = body.find("<code>").unwrap() + "<code>".len();
+            body[start..start + body[start..].find('<').unwrap()].to_owned()
+        };
+
+        let req = post("/settings/2fa/enroll")
+            .cookie(alice.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/settings/2fa")
+            .cookie(alice.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("/settings/2fa/confirm"));
+        let secret = code_after(&body, "the key");
+        let req = code_request("/settings/2fa/confirm", "abcdef")
+            .cookie(alice.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let req = code_request("/settings/2fa/confirm", &totp::current_code(&secret))
+            .cookie(alice.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        let recovery_code = code_after(&body, "recovery codes");
+
+        // The password alone isn't enough anymore
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        assert_eq!(resp.headers().get("location").unwrap(), totp::LOGIN_PATH);
+        assert!(resp.response().cookies().all(|c| c.name() != "auth-cookie"));
+        let login_cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == "login_2fa")
+            .unwrap()
+            .into_owned();
+        let req = code_request(totp::LOGIN_PATH, "abcdef")
+            .cookie(login_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let req = code_request(totp::LOGIN_PATH, &recovery_code)
+            .cookie(login_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+        auth_cookie(&resp);
+        // The login can't be finished twice
+        let resp = test::call_service(
+            &mut app,
+            code_request(totp::LOGIN_PATH, &recovery_code)
+                .cookie(login_cookie)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login?expired");
+
+        // Recovery codes only work once
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let login_cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == "login_2fa")
+            .unwrap()
+            .into_owned();
+        let req = code_request(totp::LOGIN_PATH, &recovery_code)
+            .cookie(login_cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Codes can't be guessed with the session either
+        let mut throttled = false;
+        for _ in 0..rate_limit::LOCKOUT_THRESHOLD {
+            let req = code_request("/settings/2fa/disable", "abcdef")
+                .cookie(alice.clone())
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                throttled = true;
+                break;
+            }
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert!(throttled);
+    }
+
+    #[actix_rt::test]
+    async fn admin() {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let admin_id = db
+            .add_user(&User {
+                username: "admin".to_owned(),
+                password_hash: bcrypt::hash("password", 4).unwrap(),
+                role: Role::Admin,
+                ..Default::default()
+            })
+            .unwrap();
+        let foo_id = db
+            .add_user(&User {
+                username: "foo".to_owned(),
+                password_hash: bcrypt::hash("12345678", 4).unwrap(),
+                friends: vec![(
+                    admin_id,
+                    FriendData {
+                        movies: vec![movie_id],
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut app = test_app!(db);
+        let login = |username: &str, password: &str| login_request(username, password).to_request();
+
+        let resp = test::call_service(&mut app, login("foo", "12345678")).await;
+        let foo_cookie = auth_cookie(&resp);
+        let req = test::TestRequest::get()
+            .uri("/admin")
+            .cookie(foo_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&mut app, login("admin", "password")).await;
+        let admin_cookie = auth_cookie(&resp);
+        let req = test::TestRequest::get()
+            .uri("/admin")
+            .cookie(admin_cookie.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("users_username"));
+        let req = test::TestRequest::get()
+            .uri("/admin/users?q=fo")
+            .cookie(admin_cookie.clone())
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("foo"));
+        assert!(!body.contains("<td>admin"));
+
+        // Admins can't lock themselves out
+        let req = post("/admin/users/delete")
+            .cookie(admin_cookie.clone())
+            .set_form(&admin::UserParams { user_id: admin_id })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Disabled users are logged out and can't log in again
+        let req = post("/admin/users/disable")
+            .cookie(admin_cookie.clone())
+            .set_form(&admin::DisableParams {
+                user_id: foo_id,
+                disabled: true,
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/admin/users");
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(foo_cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login");
+        let resp = test::call_service(&mut app, login("foo", "12345678")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = post("/admin/users/disable")
+            .cookie(admin_cookie.clone())
+            .set_form(&admin::DisableParams {
+                user_id: foo_id,
+                disabled: false,
+            })
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        let req = post("/admin/users/reset_password")
+            .cookie(admin_cookie.clone())
+            .set_form(&admin::UserParams { user_id: foo_id })
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let start = body.find("foo is ").unwrap() + "foo is ".len();
+        let password = &body[start..start + 16];
+        let resp = test::call_service(&mut app, login("foo", "12345678")).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+        let resp = test::call_service(&mut app, login("foo", password)).await;
+        let foo_cookie = auth_cookie(&resp);
+
+        // Deleted movies disappear from watchlists
+        let req = post("/admin/movies/delete")
+            .cookie(admin_cookie.clone())
+            .set_form(&admin::MovieParams { movie_id })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/admin/movies");
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(foo_cookie)
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(!std::str::from_utf8(&body).unwrap().contains("Pulp Fiction"));
+
+        let req = post("/admin/users/delete")
+            .cookie(admin_cookie)
+            .set_form(&admin::UserParams { user_id: foo_id })
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let resp = test::call_service(&mut app, login("foo", password)).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn account_export_and_deletion() {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                year: Some(1994),
+                ..Default::default()
+            })
+            .unwrap();
+        let bob_id = add_user(&db, "bob", "password");
+        let alice_id = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                password_hash: bcrypt::hash("password", 4).unwrap(),
+                friends: vec![
+                    (
+                        bob_id,
+                        FriendData {
+                            // Left behind by a removed movie
+                            movies: vec![movie_id, 54321],
+                        },
+                    ),
+                    // Left behind by a removed user
+                    (
+                        12345,
+                        FriendData {
+                            movies: vec![movie_id],
+                        },
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        db.add_friend(bob_id, alice_id).unwrap();
+        let mut app = test_app!(db);
+
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice_cookie = auth_cookie(&resp);
+        let req = test::TestRequest::get()
+            .uri("/settings/account/export")
+            .cookie(alice_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp
+            .headers()
+            .get("content-disposition")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("attachment"));
+        let export = json_body(resp).await;
+        assert_eq!(export["username"], "alice");
+        assert_eq!(export["friends"][0]["username"], "bob");
+        assert_eq!(
+            export["friends"][0]["recommendations"][0]["name"],
+            "Pulp Fiction"
+        );
+        assert_eq!(export["friends"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            export["friends"][0]["recommendations"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(export["watchlist"].as_array().unwrap().len(), 1);
+        assert_eq!(export["watchlist"][0]["id"], movie_id);
+        assert_eq!(
+            export["watchlist"][0]["recommended_by"],
+            serde_json::json!([bob_id])
+        );
+        assert_eq!(export["added_by"], serde_json::json!([bob_id]));
+        assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+        assert!(export.get("password_hash").is_none());
+
+        let delete = |confirmation: &str| {
+            post("/settings/account/delete")
+                .cookie(alice_cookie.clone())
+                .set_form(&privacy::DeleteParams {
+                    confirmation: confirmation.to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, delete("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(&mut app, delete("password")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(alice_cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login");
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+
+        // Alice is gone from the friend list of bob
+        let resp =
+            test::call_service(&mut app, login_request("bob", "password").to_request()).await;
+        let req = test::TestRequest::get()
+            .uri("/api/v1/friends")
+            .cookie(auth_cookie(&resp))
+            .to_request();
+        let friends: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(friends, serde_json::json!([]));
+    }
+
+    #[actix_rt::test]
+    async fn change_credentials() {
+        let db = MemoryDb::new();
+        add_user(&db, "foo", "old password");
+        add_user(&db, "bar", "password");
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("foo", "old password").to_request()).await;
+        let cookie = auth_cookie(&resp);
+        let resp =
+            test::call_service(&mut app, login_request("foo", "old password").to_request()).await;
+        let other_cookie = auth_cookie(&resp);
+
+        let change_password = |current_password: &str| {
+            post("/settings/password")
+                .cookie(cookie.clone())
+                .set_form(&account::ChangePasswordParams {
+                    current_password: current_password.to_owned(),
+                    password: "new password".to_owned(),
+                    password_repeat: "new password".to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, change_password("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(&mut app, change_password("old password")).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/settings/credentials?password_changed"
+        );
+        // Other sessions are logged out
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .cookie(other_cookie)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/login");
+        let resp =
+            test::call_service(&mut app, login_request("foo", "old password").to_request()).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+        let resp =
+            test::call_service(&mut app, login_request("foo", "new password").to_request()).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+
+        let rename = |username: &str| {
+            post("/settings/username")
+                .cookie(cookie.clone())
+                .set_form(&account::ChangeUsernameParams {
+                    username: username.to_owned(),
+                    current_password: "new password".to_owned(),
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, rename("BAR")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = test::call_service(&mut app, rename("x")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let resp = test::call_service(&mut app, rename("baz")).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/settings/credentials?username_changed"
+        );
+        // The session survives the rename
+        let req = test::TestRequest::get()
+            .uri("/api/v1/me")
+            .cookie(cookie)
+            .to_request();
+        let me: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(me["username"], "baz");
+        let resp =
+            test::call_service(&mut app, login_request("foo", "new password").to_request()).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/login?wrong_password"
+        );
+        // The wrong current password counted as a failed login from this address too
+        let req = login_request("baz", "new password")
+            .peer_addr("10.0.0.2:1234".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.headers().get("location").unwrap(), "/");
+
+        // The current password can't be guessed with the session
+        let cookie = auth_cookie(&resp);
+        let mut throttled = false;
+        for _ in 0..rate_limit::LOCKOUT_THRESHOLD {
+            let req = post("/settings/username")
+                .cookie(cookie.clone())
+                .set_form(&account::ChangeUsernameParams {
+                    username: "qux".to_owned(),
+                    current_password: "wrong".to_owned(),
+                })
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                throttled = true;
+                break;
+            }
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert!(throttled);
+    }
+
+    #[actix_rt::test]
+    async fn movie_search() {
+        let db = MemoryDb::new();
+        for i in 0..25 {
+            db.add_movie(&Movie {
+                name: format!("Star Wars {}", i),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let alice_id = add_user(&db, "alice", "password");
+        db.add_user(&User {
+            username: "bob".to_owned(),
+            password_hash: bcrypt::hash("password", 4).unwrap(),
+            friends: vec![(alice_id, FriendData { movies: vec![] })]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(alice.clone())
+                .to_request()
+        };
+
+        let body = test::read_response(&mut app, get("/movies/search?q=Star+Wars")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("25 results"));
+        assert!(body.contains("score"));
+        assert!(body.contains("page=1"));
+        assert!(!body.contains("Pulp Fiction"));
+        let body = test::read_response(&mut app, get("/movies/search?q=Star+Wars&page=1")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.matches("<li>").count(), 5);
+        assert!(!body.contains("page=2"));
+
+        // Bob added alice, so she can add movies to his list
+        let body = test::read_response(&mut app, get("/movies/search?q=Pulp")).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains(r#"<option value="bob">"#));
+        let req = post("/movies/search/recommend")
+            .cookie(alice.clone())
+            .set_form(&search::RecommendParams {
+                friend: "bob".to_owned(),
+                movie_id,
+                q: "Pulp".to_owned(),
+                page: 0,
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(
+            resp.headers().get("location").unwrap(),
+            "/movies/search?q=Pulp&page=0&recommended="
+        );
+        let resp =
+            test::call_service(&mut app, login_request("bob", "password").to_request()).await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(auth_cookie(&resp))
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("Pulp Fiction"));
+    }
+
+    #[actix_rt::test]
+    async fn user_search() {
+        let db = MemoryDb::new();
+        let add = |username: &str, display_name: Option<&str>, friends: &[u64]| {
+            db.add_user(&User {
+                username: username.to_owned(),
+                display_name: display_name.map(str::to_owned),
+                friends: friends
+                    .iter()
+                    .map(|id| (*id, FriendData { movies: vec![] }))
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let dave = add("dave", Some("Dave Miller"), &[]);
+        let erin = add("erin", None, &[]);
+        let bob = add("bob", None, &[dave]);
+        let carol = add("carol", None, &[dave, erin]);
+        let alice = add_user(&db, "alice", "password");
+        let mut user = db.get_user(alice).unwrap().unwrap();
+        user.friends = vec![bob, carol]
+            .into_iter()
+            .map(|id| (id, FriendData { movies: vec![] }))
+            .collect();
+        db.update_user(alice, &user).unwrap();
+        let mut app = test_app!(db);
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let cookie = auth_cookie(&resp);
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        // Found by an incomplete display name
+        let body = test::read_response(&mut app, get("/users/search?q=Mil")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("1 results"));
+        assert!(body.contains("dave (Dave Miller)"));
+        assert!(body.contains("2 mutual friends"));
+        let body = test::read_response(&mut app, get("/users/search?q=car")).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("&middot; friend"));
+
+        // Suggestions are ranked by mutual friends
+        let body = test::read_response(&mut app, get("/users/search")).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        let suggestions = &body[body.find("People you may know").unwrap()..];
+        let dave_at = suggestions.find(r#"value="dave""#).unwrap();
+        let erin_at = suggestions.find(r#"value="erin""#).unwrap();
+        assert!(dave_at < erin_at);
+        assert!(suggestions.contains("1 mutual friend\n"));
+        assert!(!suggestions.contains(r#"value="bob""#));
+
+        let req = post("/settings/display_name")
+            .cookie(cookie.clone())
+            .set_form(&account::DisplayNameParams {
+                display_name: " Alice Liddell ".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let body = test::read_response(&mut app, get("/settings/credentials")).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains(r#"value="Alice Liddell""#));
+        let req = post("/settings/display_name")
+            .cookie(cookie.clone())
+            .set_form(&account::DisplayNameParams {
+                display_name: "x".repeat(65),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_rt::test]
+    async fn groups() {
+        let db = MemoryDb::new();
+        let pulp_fiction = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let jackie_brown = db
+            .add_movie(&Movie {
+                name: "Jackie Brown".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let alice_id = add_user(&db, "alice", "password");
+        add_user(&db, "bob", "password");
+        let mut app = test_app!(db.clone());
+        let resp =
+            test::call_service(&mut app, login_request("alice", "password").to_request()).await;
+        let alice = auth_cookie(&resp);
+        let resp =
+            test::call_service(&mut app, login_request("bob", "password").to_request()).await;
+        let bob = auth_cookie(&resp);
+        let get = |uri: &str, cookie: &Cookie<'static>| {
+            test::TestRequest::get()
+                .uri(uri)
+                .cookie(cookie.clone())
+                .to_request()
+        };
+
+        let req = post("/groups")
+            .cookie(alice.clone())
+            .set_form(&groups::CreateParams {
+                name: "Movie night".to_owned(),
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let group_uri = resp
+            .headers()
+            .get("location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let group_id = group_uri["/groups/".len()..].parse::<u64>().unwrap();
+        let body = test::read_response(&mut app, get(&group_uri, &alice)).await;
+        // Slashes are escaped in the link
+        let body = std::str::from_utf8(&body).unwrap().replace("&#x2F;", "/");
+        let start = body.find("/groups/join/").unwrap();
+        let invite = body[start..start + body[start..].find('"').unwrap()].to_owned();
+
+        // Bob can't see the group until he joins through the invite link
+        let resp = test::call_service(&mut app, get(&group_uri, &bob)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = test::read_response(&mut app, get(&invite, &bob)).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("Join Movie night"));
+        let resp =
+            test::call_service(&mut app, post(&invite).cookie(bob.clone()).to_request()).await;
+        assert_eq!(resp.headers().get("location").unwrap(), group_uri.as_str());
+
+        for (cookie, movie_id) in &[(&alice, pulp_fiction), (&bob, jackie_brown)] {
+            let req = post("/groups/movies")
+                .cookie((*cookie).clone())
+                .set_form(&groups::AddMovieParams {
+                    group_id,
+                    movie_id: *movie_id,
+                })
+                .to_request();
+            test::call_service(&mut app, req).await;
+        }
+        let pick = || {
+            post(&format!("{}/pick", group_uri))
+                .cookie(alice.clone())
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, pick()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Only voted movies can be picked
+        let vote = |cookie: &Cookie<'static>| {
+            post(&format!("{}/vote", group_uri))
+                .cookie(cookie.clone())
+                .set_form(&groups::MovieParams {
+                    movie_id: jackie_brown,
+                })
+                .to_request()
+        };
+        test::call_service(&mut app, vote(&alice)).await;
+        test::call_service(&mut app, vote(&bob)).await;
+        let resp = test::call_service(&mut app, pick()).await;
+        let picked = resp.headers().get("location").unwrap().to_str().unwrap();
+        assert_eq!(picked, format!("{}?picked={}", group_uri, jackie_brown));
+        let body = test::read_response(&mut app, get(picked, &bob)).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("Tonight's movie: <strong>Jackie Brown</strong>"));
+        assert!(body.contains("2 votes"));
+        // Voting again takes the vote back
+        test::call_service(&mut app, vote(&bob)).await;
+        let body = test::read_response(&mut app, get(&group_uri, &bob)).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("1 vote\n"));
+        // Left behind by a movie that was removed while it was added
+        db.modify_group(group_id, |group| {
+            group.movies.push(GroupMovie {
+                movie_id: 54321,
+                added_by: alice_id,
+                votes: Vec::new(),
+            });
+            Ok(())
+        })
+        .unwrap();
+        let resp = test::call_service(&mut app, get(&group_uri, &bob)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Bob didn't add Pulp Fiction and isn't the owner
+        let req = post(&format!("{}/remove_movie", group_uri))
+            .cookie(bob.clone())
+            .set_form(&groups::MovieParams {
+                movie_id: pulp_fiction,
+            })
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Resetting the link makes the old one stop working
+        let req = post(&format!("{}/invite", group_uri))
+            .cookie(bob.clone())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = post(&format!("{}/invite", group_uri))
+            .cookie(alice.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let resp = test::call_service(&mut app, get(&invite, &bob)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Bob takes over when alice leaves
+        let req = post(&format!("{}/leave", group_uri))
+            .cookie(alice.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let resp = test::call_service(&mut app, get(&group_uri, &alice)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = test::read_response(&mut app, get("/groups", &bob)).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("owner"));
+        let req = post(&format!("{}/delete", group_uri))
+            .cookie(bob.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let resp = test::call_service(&mut app, get(&group_uri, &bob)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
 }
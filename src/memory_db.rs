@@ -0,0 +1,757 @@
+//! Storage backend that keeps everything in memory, used for tests.
+//!
+//! Searches go through the same [`FTSTree`] as in production, kept in a temporary sled database,
+//! so that tests see the same results and ranks.
+
+use crate::{
+    database::*,
+    fts_tree::{FTSExt, FTSTree},
+    model::*,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    users: BTreeMap<u64, User>,
+    users_username: HashMap<String, u64>,
+    movies: BTreeMap<u64, Movie>,
+    groups: BTreeMap<u64, Group>,
+    sessions: BTreeMap<String, Session>,
+    login_attempts: HashMap<String, LoginAttempts>,
+    tokens: HashMap<String, Token>,
+    oidc_logins: HashMap<String, OidcLogin>,
+    api_tokens: BTreeMap<String, ApiToken>,
+    outbox: BTreeMap<u64, OutboxEntry>,
+    notifications: HashMap<u64, BTreeMap<u64, Notification>>,
+}
+
+impl State {
+    fn generate_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+/// Clones share the same data, like clones of `sled::Db`
+#[derive(Clone)]
+pub struct MemoryDb {
+    state: Arc<RwLock<State>>,
+    search: sled::Db,
+}
+
+impl Default for MemoryDb {
+    fn default() -> Self {
+        MemoryDb {
+            state: Arc::default(),
+            search: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+}
+
+impl MemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn movies_name(&self) -> DbResult<FTSTree> {
+        Ok(self.search.open_fts(b"movies_name")?)
+    }
+
+    fn users_name(&self) -> DbResult<FTSTree> {
+        Ok(self.search.open_fts(b"users_name")?)
+    }
+}
+
+/// Sorts search results like the sled backend, best match first
+fn sort_results<T>(results: &mut [(u64, T, f32)]) {
+    results.sort_by(|(a_id, _, a), (b_id, _, b)| {
+        b.partial_cmp(a)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a_id.cmp(b_id))
+    });
+}
+
+/// Id of another user with the same verified email address as `user`
+fn find_by_email(state: &State, user: &User) -> Option<u64> {
+    let email = email_key(user);
+    state
+        .users
+        .iter()
+        .find(|(_, other)| email_key(other) == email)
+        .map(|(id, _)| *id)
+}
+
+/// Id of another user that has one of the identities of `user`
+fn find_by_identity(state: &State, user: &User) -> Option<u64> {
+    state
+        .users
+        .iter()
+        .find(|(_, other)| {
+            other
+                .identities
+                .iter()
+                .any(|identity| user.identities.contains(identity))
+        })
+        .map(|(id, _)| *id)
+}
+
+impl DbExt for MemoryDb {
+    fn add_user(&self, user: &User) -> DbResult<u64> {
+        validate_user(user)?;
+        let mut state = self.state.write().unwrap();
+        if state
+            .users_username
+            .contains_key(&username_key(&user.username))
+        {
+            return Err(DbError::Conflict(format!(
+                "Username {} is taken",
+                user.username
+            )));
+        }
+        if email_key(user).is_some() && find_by_email(&state, user).is_some() {
+            return Err(DbError::Conflict(
+                "Email address is already in use".to_owned(),
+            ));
+        }
+        if find_by_identity(&state, user).is_some() {
+            return Err(DbError::Conflict(
+                "Identity is already linked to another user".to_owned(),
+            ));
+        }
+        let id = state.generate_id();
+        self.users_name()?
+            .insert(id.to_le_bytes(), &user_search_text(user))?;
+        state.users.insert(id, user.clone());
+        state
+            .users_username
+            .insert(username_key(&user.username), id);
+        Ok(id)
+    }
+
+    fn get_user(&self, id: u64) -> DbResult<Option<User>> {
+        Ok(self.state.read().unwrap().users.get(&id).cloned())
+    }
+
+    fn get_user_by_username(&self, username: &str) -> DbResult<Option<(u64, User)>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .users_username
+            .get(&username_key(username))
+            .map(|id| (*id, state.users[id].clone())))
+    }
+
+    fn get_user_by_email(&self, email: &str) -> DbResult<Option<(u64, User)>> {
+        let email = Some(email.to_lowercase());
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .users
+            .iter()
+            .find(|(_, user)| email_key(user) == email)
+            .map(|(id, user)| (*id, user.clone())))
+    }
+
+    fn get_user_by_identity(&self, issuer: &str, subject: &str) -> DbResult<Option<(u64, User)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .users
+            .iter()
+            .find(|(_, user)| {
+                user.identities
+                    .iter()
+                    .any(|identity| identity.issuer == issuer && identity.subject == subject)
+            })
+            .map(|(id, user)| (*id, user.clone())))
+    }
+
+    fn modify_user<T, F: Fn(&mut User) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T> {
+        let mut state = self.state.write().unwrap();
+        let old = state.users.get(&id).ok_or(DbError::NotFound)?;
+        let mut user = old.clone();
+        let result = f(&mut user)?;
+        validate_user(&user)?;
+        let user = &user;
+        let (old_username, new_username) =
+            (username_key(&old.username), username_key(&user.username));
+        if old_username != new_username && state.users_username.contains_key(&new_username) {
+            return Err(DbError::Conflict(format!(
+                "Username {} is taken",
+                user.username
+            )));
+        }
+        if email_key(user).is_some() && find_by_email(&state, user).is_some_and(|i| i != id) {
+            return Err(DbError::Conflict(
+                "Email address is already in use".to_owned(),
+            ));
+        }
+        if state.users.iter().any(|(other_id, other)| {
+            *other_id != id
+                && other
+                    .identities
+                    .iter()
+                    .any(|identity| user.identities.contains(identity))
+        }) {
+            return Err(DbError::Conflict(
+                "Identity is already linked to another user".to_owned(),
+            ));
+        }
+        let (old_text, new_text) = (user_search_text(old), user_search_text(user));
+        if old_text != new_text {
+            let users_name = self.users_name()?;
+            users_name.remove(id.to_le_bytes(), &old_text)?;
+            users_name.insert(id.to_le_bytes(), &new_text)?;
+        }
+        state.users_username.remove(&old_username);
+        state.users_username.insert(new_username, id);
+        state.users.insert(id, user.clone());
+        Ok(result)
+    }
+
+    fn get_users(&self) -> DbResult<Vec<(u64, User)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .users
+            .iter()
+            .map(|(id, user)| (*id, user.clone()))
+            .collect())
+    }
+
+    fn search_users(&self, query: &str) -> DbResult<Vec<(u64, User, f32)>> {
+        let state = self.state.read().unwrap();
+        let mut results = self
+            .users_name()?
+            .query(&query.to_lowercase())?
+            .into_iter()
+            .filter_map(|(key, rank)| {
+                let id = u64::from_le_bytes(key.as_ref().try_into().ok()?);
+                Some((id, state.users.get(&id)?.clone(), rank))
+            })
+            .collect::<Vec<_>>();
+        sort_results(&mut results);
+        Ok(results)
+    }
+
+    fn remove_user(&self, id: u64) -> DbResult<()> {
+        let mut state = self.state.write().unwrap();
+        let user = state.users.remove(&id).ok_or(DbError::NotFound)?;
+        self.users_name()?
+            .remove(id.to_le_bytes(), &user_search_text(&user))?;
+        state.users_username.remove(&username_key(&user.username));
+        for other in state.users.values_mut() {
+            other.friends.remove(&id);
+        }
+        state.sessions.retain(|_, session| session.user_id != id);
+        state.api_tokens.retain(|_, token| token.user_id != id);
+        state.tokens.retain(|_, token| token.user_id != id);
+        state.notifications.remove(&id);
+        state.outbox.retain(|_, entry| entry.user_id != id);
+        for group in state.groups.values_mut() {
+            remove_member(group, id);
+        }
+        state.groups.retain(|_, group| !group.members.is_empty());
+        Ok(())
+    }
+
+    fn recommend_movie(&self, from: u64, to: u64, movie_id: u64) -> DbResult<bool> {
+        let mut state = self.state.write().unwrap();
+        if !state.movies.contains_key(&movie_id) {
+            return Err(DbError::NotFound);
+        }
+        let user = state.users.get_mut(&to).ok_or(DbError::NotFound)?;
+        add_recommendation(user, from, movie_id)
+    }
+
+    fn add_friend(&self, user_id: u64, friend_id: u64) -> DbResult<bool> {
+        let mut state = self.state.write().unwrap();
+        if user_id == friend_id || !state.users.contains_key(&friend_id) {
+            return Err(DbError::NotFound);
+        }
+        let user = state.users.get_mut(&user_id).ok_or(DbError::NotFound)?;
+        if user.friends.contains_key(&friend_id) {
+            return Ok(false);
+        }
+        user.friends
+            .insert(friend_id, FriendData { movies: Vec::new() });
+        Ok(true)
+    }
+
+    fn add_movie(&self, movie: &Movie) -> DbResult<u64> {
+        validate_movie(movie)?;
+        let mut state = self.state.write().unwrap();
+        for other in state.movies.values() {
+            if movie.imdb_id.is_some() && other.imdb_id == movie.imdb_id {
+                return Err(DbError::Conflict(format!(
+                    "Movie {} already exists",
+                    movie.imdb_id.as_deref().unwrap_or_default()
+                )));
+            }
+            if movie.tmdb_id.is_some() && other.tmdb_id == movie.tmdb_id {
+                return Err(DbError::Conflict(format!(
+                    "Movie with TMDb id {} already exists",
+                    movie.tmdb_id.unwrap_or_default()
+                )));
+            }
+        }
+        let id = state.generate_id();
+        self.movies_name()?.insert(id.to_le_bytes(), &movie.name)?;
+        state.movies.insert(id, movie.clone());
+        Ok(id)
+    }
+
+    fn add_movies(&self, movies: &[Movie]) -> DbResult<u64> {
+        for movie in movies {
+            validate_movie(movie)?;
+        }
+        let mut added = 0;
+        for movie in movies {
+            match self.add_movie(movie) {
+                Ok(_) => added += 1,
+                Err(DbError::Conflict(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(added)
+    }
+
+    fn get_movie(&self, id: u64) -> DbResult<Option<Movie>> {
+        Ok(self.state.read().unwrap().movies.get(&id).cloned())
+    }
+
+    fn search_movie(&self, query: &str) -> DbResult<Vec<(u64, Movie, f32)>> {
+        let state = self.state.read().unwrap();
+        let mut results = self
+            .movies_name()?
+            .query(query)?
+            .into_iter()
+            .filter_map(|(key, rank)| {
+                let id = u64::from_le_bytes(key.as_ref().try_into().ok()?);
+                Some((id, state.movies.get(&id)?.clone(), rank))
+            })
+            .collect::<Vec<_>>();
+        sort_results(&mut results);
+        Ok(results)
+    }
+
+    fn get_movies_by_genre(&self, genre: &str) -> DbResult<Vec<u64>> {
+        let genre = genre.to_lowercase();
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .movies
+            .iter()
+            .filter(|(_, movie)| movie.genres.iter().any(|g| g.to_lowercase() == genre))
+            .map(|(id, _)| *id)
+            .collect())
+    }
+
+    fn get_movies_by_year(&self, from: u16, to: u16) -> DbResult<Vec<u64>> {
+        let state = self.state.read().unwrap();
+        let mut movies = state
+            .movies
+            .iter()
+            .filter_map(|(id, movie)| movie.year.map(|year| (year, *id)))
+            .filter(|(year, _)| (from..=to).contains(year))
+            .collect::<Vec<_>>();
+        movies.sort_unstable();
+        Ok(movies.into_iter().map(|(_, id)| id).collect())
+    }
+
+    fn get_movie_by_imdb_id(&self, imdb_id: &str) -> DbResult<Option<u64>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .movies
+            .iter()
+            .find(|(_, movie)| movie.imdb_id.as_deref() == Some(imdb_id))
+            .map(|(id, _)| *id))
+    }
+
+    fn get_movie_by_tmdb_id(&self, tmdb_id: u64) -> DbResult<Option<u64>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .movies
+            .iter()
+            .find(|(_, movie)| movie.tmdb_id == Some(tmdb_id))
+            .map(|(id, _)| *id))
+    }
+
+    fn remove_movie(&self, id: u64) -> DbResult<()> {
+        let mut state = self.state.write().unwrap();
+        let movie = state.movies.remove(&id).ok_or(DbError::NotFound)?;
+        self.movies_name()?.remove(id.to_le_bytes(), &movie.name)?;
+        for user in state.users.values_mut() {
+            for friend_data in user.friends.values_mut() {
+                friend_data.movies.retain(|movie_id| *movie_id != id);
+            }
+        }
+        for group in state.groups.values_mut() {
+            group.movies.retain(|movie| movie.movie_id != id);
+        }
+        Ok(())
+    }
+
+    fn add_group(&self, group: &Group) -> DbResult<u64> {
+        validate_group(group)?;
+        let mut state = self.state.write().unwrap();
+        if state
+            .groups
+            .values()
+            .any(|other| other.invite_code == group.invite_code)
+        {
+            return Err(DbError::Conflict("Duplicate invite code".to_owned()));
+        }
+        let id = state.generate_id();
+        state.groups.insert(id, group.clone());
+        Ok(id)
+    }
+
+    fn get_group(&self, id: u64) -> DbResult<Option<Group>> {
+        Ok(self.state.read().unwrap().groups.get(&id).cloned())
+    }
+
+    fn get_group_by_invite_code(&self, invite_code: &str) -> DbResult<Option<(u64, Group)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .groups
+            .iter()
+            .find(|(_, group)| group.invite_code == invite_code)
+            .map(|(id, group)| (*id, group.clone())))
+    }
+
+    fn modify_group<T, F: Fn(&mut Group) -> DbResult<T>>(&self, id: u64, f: F) -> DbResult<T> {
+        let mut state = self.state.write().unwrap();
+        let mut group = state.groups.get(&id).ok_or(DbError::NotFound)?.clone();
+        let result = f(&mut group)?;
+        if group.members.is_empty() {
+            state.groups.remove(&id);
+            return Ok(result);
+        }
+        validate_group(&group)?;
+        if state
+            .groups
+            .iter()
+            .any(|(other_id, other)| *other_id != id && other.invite_code == group.invite_code)
+        {
+            return Err(DbError::Conflict("Duplicate invite code".to_owned()));
+        }
+        state.groups.insert(id, group);
+        Ok(result)
+    }
+
+    fn get_groups_by_user(&self, user_id: u64) -> DbResult<Vec<(u64, Group)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .groups
+            .iter()
+            .filter(|(_, group)| group.members.contains(&user_id))
+            .map(|(id, group)| (*id, group.clone()))
+            .collect())
+    }
+
+    fn add_session(&self, session_id: &str, session: &Session) -> DbResult<()> {
+        let mut state = self.state.write().unwrap();
+        if state.sessions.contains_key(session_id) {
+            return Err(DbError::Conflict("Duplicate session id".to_owned()));
+        }
+        state
+            .sessions
+            .insert(session_id.to_owned(), session.clone());
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &str) -> DbResult<Option<Session>> {
+        Ok(self.state.read().unwrap().sessions.get(session_id).cloned())
+    }
+
+    fn touch_session(&self, session_id: &str, last_seen: u64) -> DbResult<()> {
+        if let Some(session) = self.state.write().unwrap().sessions.get_mut(session_id) {
+            session.last_seen = last_seen;
+        }
+        Ok(())
+    }
+
+    fn remove_session(&self, session_id: &str) -> DbResult<()> {
+        self.state.write().unwrap().sessions.remove(session_id);
+        Ok(())
+    }
+
+    fn get_sessions_by_user(&self, user_id: u64) -> DbResult<Vec<(String, Session)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.user_id == user_id)
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect())
+    }
+
+    fn remove_sessions_by_user(&self, user_id: u64) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .sessions
+            .retain(|_, session| session.user_id != user_id);
+        Ok(())
+    }
+
+    fn remove_expired_sessions(&self, created_before: u64, seen_before: u64) -> DbResult<()> {
+        self.state.write().unwrap().sessions.retain(|_, session| {
+            session.created >= created_before && session.last_seen >= seen_before
+        });
+        Ok(())
+    }
+
+    fn get_login_attempts(&self, key: &str) -> DbResult<Option<LoginAttempts>> {
+        Ok(self.state.read().unwrap().login_attempts.get(key).cloned())
+    }
+
+    fn update_login_attempts<F: Fn(Option<LoginAttempts>) -> Option<LoginAttempts>>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> DbResult<Option<LoginAttempts>> {
+        let mut state = self.state.write().unwrap();
+        let attempts = f(state.login_attempts.remove(key));
+        if let Some(attempts) = &attempts {
+            state
+                .login_attempts
+                .insert(key.to_owned(), attempts.clone());
+        }
+        Ok(attempts)
+    }
+
+    fn remove_login_attempts(&self, key: &str) -> DbResult<()> {
+        self.state.write().unwrap().login_attempts.remove(key);
+        Ok(())
+    }
+
+    fn remove_expired_login_attempts(&self, before: u64) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .login_attempts
+            .retain(|_, a| a.last_failure >= before || a.locked_until >= before);
+        Ok(())
+    }
+    fn add_token(&self, token_hash: &str, token: &Token) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .tokens
+            .insert(token_hash.to_owned(), token.clone());
+        Ok(())
+    }
+
+    fn get_token(&self, token_hash: &str) -> DbResult<Option<Token>> {
+        Ok(self.state.read().unwrap().tokens.get(token_hash).cloned())
+    }
+
+    fn take_token(&self, token_hash: &str) -> DbResult<Option<Token>> {
+        Ok(self.state.write().unwrap().tokens.remove(token_hash))
+    }
+
+    fn remove_expired_tokens(&self, before: u64) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .tokens
+            .retain(|_, token| token.expires >= before);
+        Ok(())
+    }
+    fn add_oidc_login(&self, state_hash: &str, login: &OidcLogin) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .oidc_logins
+            .insert(state_hash.to_owned(), login.clone());
+        Ok(())
+    }
+
+    fn take_oidc_login(&self, state_hash: &str) -> DbResult<Option<OidcLogin>> {
+        Ok(self.state.write().unwrap().oidc_logins.remove(state_hash))
+    }
+
+    fn remove_expired_oidc_logins(&self, before: u64) -> DbResult<()> {
+        self.state
+            .write()
+            .unwrap()
+            .oidc_logins
+            .retain(|_, login| login.expires >= before);
+        Ok(())
+    }
+
+    fn add_api_token(&self, token_hash: &str, token: &ApiToken) -> DbResult<()> {
+        let mut state = self.state.write().unwrap();
+        if state.api_tokens.contains_key(token_hash) {
+            return Err(DbError::Conflict("Duplicate token".to_owned()));
+        }
+        state
+            .api_tokens
+            .insert(token_hash.to_owned(), token.clone());
+        Ok(())
+    }
+
+    fn get_api_token(&self, token_hash: &str) -> DbResult<Option<ApiToken>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .api_tokens
+            .get(token_hash)
+            .cloned())
+    }
+
+    fn touch_api_token(&self, token_hash: &str, last_used: u64) -> DbResult<()> {
+        if let Some(token) = self.state.write().unwrap().api_tokens.get_mut(token_hash) {
+            token.last_used = Some(last_used);
+        }
+        Ok(())
+    }
+
+    fn remove_api_token(&self, token_hash: &str) -> DbResult<()> {
+        self.state.write().unwrap().api_tokens.remove(token_hash);
+        Ok(())
+    }
+
+    fn get_api_tokens_by_user(&self, user_id: u64) -> DbResult<Vec<(String, ApiToken)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .api_tokens
+            .iter()
+            .filter(|(_, token)| token.user_id == user_id)
+            .map(|(hash, token)| (hash.clone(), token.clone()))
+            .collect())
+    }
+
+    fn add_to_outbox(&self, entry: &OutboxEntry) -> DbResult<u64> {
+        let mut state = self.state.write().unwrap();
+        let id = state.generate_id();
+        state.outbox.insert(id, entry.clone());
+        Ok(id)
+    }
+
+    fn get_outbox(&self) -> DbResult<Vec<(u64, OutboxEntry)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .outbox
+            .iter()
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect())
+    }
+
+    fn update_outbox(&self, id: u64, entry: &OutboxEntry) -> DbResult<()> {
+        self.state.write().unwrap().outbox.insert(id, entry.clone());
+        Ok(())
+    }
+
+    fn remove_from_outbox(&self, id: u64) -> DbResult<()> {
+        self.state.write().unwrap().outbox.remove(&id);
+        Ok(())
+    }
+    fn add_notification(&self, user_id: u64, notification: &Notification) -> DbResult<u64> {
+        let mut state = self.state.write().unwrap();
+        let id = state.generate_id();
+        state
+            .notifications
+            .entry(user_id)
+            .or_default()
+            .insert(id, notification.clone());
+        Ok(id)
+    }
+
+    fn get_notifications(&self, user_id: u64) -> DbResult<Vec<(u64, Notification)>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .notifications
+            .get(&user_id)
+            .map(|notifications| {
+                notifications
+                    .iter()
+                    .rev()
+                    .map(|(id, notification)| (*id, notification.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn count_unread_notifications(&self, user_id: u64) -> DbResult<usize> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .notifications
+            .get(&user_id)
+            .map(|notifications| notifications.values().filter(|n| !n.read).count())
+            .unwrap_or(0))
+    }
+
+    fn mark_notification_read(&self, user_id: u64, id: u64) -> DbResult<()> {
+        let mut state = self.state.write().unwrap();
+        let notification = state
+            .notifications
+            .get_mut(&user_id)
+            .and_then(|notifications| notifications.get_mut(&id))
+            .ok_or(DbError::NotFound)?;
+        notification.read = true;
+        Ok(())
+    }
+
+    fn mark_all_notifications_read(&self, user_id: u64) -> DbResult<()> {
+        if let Some(notifications) = self.state.write().unwrap().notifications.get_mut(&user_id) {
+            for notification in notifications.values_mut() {
+                notification.read = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn tree_sizes(&self) -> DbResult<BTreeMap<String, usize>> {
+        let state = self.state.read().unwrap();
+        Ok(vec![
+            ("users", state.users.len()),
+            ("users_username", state.users_username.len()),
+            ("movies", state.movies.len()),
+            ("groups", state.groups.len()),
+            ("sessions", state.sessions.len()),
+            ("login_attempts", state.login_attempts.len()),
+            ("tokens", state.tokens.len()),
+            ("oidc_logins", state.oidc_logins.len()),
+            ("api_tokens", state.api_tokens.len()),
+            ("outbox", state.outbox.len()),
+            (
+                "notifications",
+                state.notifications.values().map(BTreeMap::len).sum(),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, len)| (name.to_owned(), len))
+        .collect())
+    }
+}
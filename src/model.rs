@@ -1,19 +1,216 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Seconds since the unix epoch, used for all stored timestamps
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct User {
     pub username: String,
     pub password_hash: String,
     pub friends: HashMap<u64, FriendData>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub notifications: NotificationPreference,
+    /// Accounts at OpenID Connect providers the user can log in with
+    pub identities: Vec<ExternalIdentity>,
+    pub two_factor: Option<TwoFactor>,
+    pub role: Role,
+    /// Disabled users can't log in
+    pub disabled: bool,
+    /// Shown next to the username, e.g. the real name
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// Can manage users and movies in the admin area
+    Admin,
+}
+
+/// TOTP second factor of a user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TwoFactor {
+    /// TOTP secret, encrypted with the server's two-factor key
+    pub secret: Vec<u8>,
+    /// Set once the user entered a valid code after scanning the secret
+    pub enabled: bool,
+    /// Hashes of the recovery codes that haven't been used yet
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, so that codes can't be used twice
+    pub last_step: u64,
+}
+
+/// Account at an OpenID Connect provider
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    /// `sub` claim of the ID token, unique per issuer
+    pub subject: String,
+}
+
+/// When to send emails about new recommendations
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationPreference {
+    #[default]
+    Instant,
+    DailyDigest,
+    Off,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FriendData {
     pub movies: Vec<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
 pub struct Movie {
     pub name: String,
+    /// Year of the original release
+    pub year: Option<u16>,
+    /// Runtime in minutes
+    pub runtime: Option<u16>,
+    pub genres: Vec<String>,
+    pub poster_url: Option<String>,
+    /// IMDb title id, e.g. `tt0110912`
+    pub imdb_id: Option<String>,
+    pub tmdb_id: Option<u64>,
+}
+
+/// Friends that pick movies to watch together
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Group {
+    pub name: String,
+    /// Can invite and remove members and delete the group
+    pub owner: u64,
+    /// All members, including the owner
+    pub members: Vec<u64>,
+    /// Secret part of the invite link. It is stored as is, so that every member can share the
+    /// link again, and replaced when the owner resets the link.
+    pub invite_code: String,
+    /// The shared watchlist, in the order the movies were added
+    pub movies: Vec<GroupMovie>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupMovie {
+    pub movie_id: u64,
+    pub added_by: u64,
+    /// Members that want to watch this movie next
+    pub votes: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub user_id: u64,
+    pub created: u64,
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+}
+
+/// Failed login attempts for a username or an IP address
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LoginAttempts {
+    pub failures: u32,
+    pub last_failure: u64,
+    pub locked_until: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    /// The password was correct but the second factor is still missing. Kept in a cookie
+    /// instead of being sent by email.
+    TwoFactorLogin,
+}
+
+/// Single use token, usually sent to a user by email, stored under the hash of the token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Token {
+    pub purpose: TokenPurpose,
+    pub user_id: u64,
+    pub expires: u64,
+    /// The address the token was sent to, so that it can't be used after the email changed
+    pub email: String,
+}
+
+/// Login at an OpenID Connect provider that was started but hasn't come back yet, stored under
+/// the hash of its `state` parameter
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OidcLogin {
+    pub nonce: String,
+    /// PKCE code verifier
+    pub code_verifier: String,
+    pub expires: u64,
+    /// Link the identity to this user instead of logging in
+    pub link_user: Option<u64>,
+}
+
+/// What a personal access token may be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Requests that don't change anything
+    Read,
+    /// Requests that change something, e.g. adding friends
+    Write,
+}
+
+/// Personal access token for the API, stored under the hash of the token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiToken {
+    pub user_id: u64,
+    /// Chosen by the user to recognize the token
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub last_used: Option<u64>,
+}
+
+/// Something that happened that a user gets notified about.
+///
+/// Records store the index of the variant, so new variants can be appended without changing
+/// how the existing ones are stored. Changing a variant needs new versions of [`Notification`]
+/// and [`OutboxEntry`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The friend `from` added a movie to the user's watchlist
+    Recommendation { from: u64, movie_id: u64 },
+    /// `from` added the user as a friend and waits to be added back
+    FriendRequest { from: u64 },
+    /// The user asked for a new password. Only sent by email, the reset token is created when
+    /// the email is sent, so that it is never stored.
+    PasswordReset,
+}
+
+/// Notification shown on the site
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub event: Event,
+    pub created: u64,
+    pub read: bool,
+}
+
+/// An email notification waiting to be sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    pub user_id: u64,
+    pub event: Event,
+    pub created: u64,
+    /// Failed delivery attempts so far
+    pub attempts: u32,
+    /// Don't send before this time, used for digests and retries
+    pub next_attempt: u64,
 }
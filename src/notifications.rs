@@ -0,0 +1,89 @@
+//! Notification center on the site.
+//!
+//! Every event a user is notified about is stored in their notifications tree, where it stays
+//! until the account is deleted, and is also queued for email delivery by [`notify`].
+
+use crate::{csrf::CsrfToken, database::*, model::*, notify, render, session, Tera};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+/// Notifies the user on the site and by email
+pub fn send<D: DbExt>(db: &D, user_id: u64, event: Event) -> DbResult<()> {
+    db.add_notification(
+        user_id,
+        &Notification {
+            event: event.clone(),
+            created: unix_time(),
+            read: false,
+        },
+    )?;
+    notify::queue(db, user_id, event)
+}
+
+#[derive(Serialize)]
+struct NotificationInfo {
+    id: u64,
+    text: String,
+    created: u64,
+    read: bool,
+    /// Username of somebody who sent a friend request that wasn't answered yet
+    add_friend: Option<String>,
+}
+
+pub async fn notifications<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let mut infos = Vec::new();
+    for (notification_id, notification) in db.get_notifications(current.user_id)? {
+        let text = match notify::describe(&**db, &notification.event)? {
+            Some(text) => text,
+            // Refers to something that was deleted
+            None => continue,
+        };
+        let add_friend = match notification.event {
+            Event::FriendRequest { from } if !current.user.friends.contains_key(&from) => {
+                db.get_user(from)?.map(|friend| friend.username)
+            }
+            _ => None,
+        };
+        infos.push(NotificationInfo {
+            id: notification_id,
+            text,
+            created: notification.created,
+            read: notification.read,
+            add_friend,
+        });
+    }
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("notifications", &infos);
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "notifications.html", &ctx, HttpResponse::Ok())
+}
+
+pub async fn mark_read<D: DbExt>(
+    path: web::Path<u64>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    db.mark_notification_read(current.user_id, path.into_inner())?;
+    Ok(HttpResponse::Found()
+        .header("location", "/notifications")
+        .finish())
+}
+
+pub async fn mark_all_read<D: DbExt>(
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    db.mark_all_notifications_read(current.user_id)?;
+    Ok(HttpResponse::Found()
+        .header("location", "/notifications")
+        .finish())
+}
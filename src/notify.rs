@@ -0,0 +1,422 @@
+//! Email notifications about new recommendations and friend requests.
+//!
+//! Events are queued in the `outbox` tree and delivered by a background thread that polls the
+//! outbox every [`POLL_INTERVAL`]. Users who want a daily digest get their notifications queued
+//! for the next midnight (UTC), all notifications of a user that are due are sent as one email.
+//! Password reset requests are queued as well, but always sent right away and on their own. Their
+//! token is only created when the email is sent, so the outbox never contains one. Failed
+//! deliveries are retried with exponential backoff.
+//!
+//! The same thread removes expired sessions, tokens and login attempts every
+//! [`CLEANUP_INTERVAL`].
+
+use crate::{
+    database::*,
+    mail::{Email, Mailer},
+    model::*,
+    token,
+};
+use log::{debug, warn};
+use std::{collections::BTreeMap, time::Duration};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Seconds between removals of expired entries
+pub const CLEANUP_INTERVAL: u64 = 60 * 60;
+/// Notifications are dropped after this many failed deliveries
+const MAX_ATTEMPTS: u32 = 8;
+/// Delay in seconds after the first failure, doubled for every further failure
+const RETRY_BASE_DELAY: u64 = 60;
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60;
+const DAY: u64 = 24 * 60 * 60;
+
+fn retry_delay(attempts: u32) -> u64 {
+    let exponent = attempts.saturating_sub(1).min(32);
+    RETRY_BASE_DELAY
+        .saturating_mul(1 << exponent)
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Start of the next day in UTC
+fn next_digest(now: u64) -> u64 {
+    now - now % DAY + DAY
+}
+
+/// Queues an email notification about `event` according to the preference of the user
+pub fn queue<D: DbExt>(db: &D, user_id: u64, event: Event) -> DbResult<()> {
+    let user = db.get_user(user_id)?.ok_or(DbError::NotFound)?;
+    let now = unix_time();
+    let next_attempt = match user.notifications {
+        NotificationPreference::Instant => now,
+        NotificationPreference::DailyDigest => next_digest(now),
+        NotificationPreference::Off => return Ok(()),
+    };
+    db.add_to_outbox(&OutboxEntry {
+        user_id,
+        event,
+        created: now,
+        attempts: 0,
+        next_attempt,
+    })?;
+    Ok(())
+}
+
+/// Queues an email with a link to reset the password, regardless of the notification preference
+pub fn queue_password_reset<D: DbExt>(db: &D, user_id: u64) -> DbResult<()> {
+    let now = unix_time();
+    db.add_to_outbox(&OutboxEntry {
+        user_id,
+        event: Event::PasswordReset,
+        created: now,
+        attempts: 0,
+        next_attempt: now,
+    })?;
+    Ok(())
+}
+
+/// One line describing the event, `None` if something it refers to was deleted
+pub fn describe<D: DbExt>(db: &D, event: &Event) -> DbResult<Option<String>> {
+    Ok(match event {
+        Event::Recommendation { from, movie_id } => {
+            match (db.get_user(*from)?, db.get_movie(*movie_id)?) {
+                (Some(friend), Some(movie)) => Some(match movie.year {
+                    Some(year) => {
+                        format!("{} recommends {} ({})", friend.username, movie.name, year)
+                    }
+                    None => format!("{} recommends {}", friend.username, movie.name),
+                }),
+                _ => None,
+            }
+        }
+        Event::FriendRequest { from } => db
+            .get_user(*from)?
+            .map(|friend| format!("{} added you as a friend", friend.username)),
+        // Only sent by email, see `compose_password_reset`
+        Event::PasswordReset => None,
+    })
+}
+
+/// Email with a new reset link for the request `entry`, `None` if the address isn't verified
+/// (anymore) or the request expired
+fn compose_password_reset<D: DbExt>(
+    db: &D,
+    user_id: u64,
+    user: &User,
+    entry: &OutboxEntry,
+    public_url: &str,
+    now: u64,
+) -> DbResult<Option<Email>> {
+    let to = match &user.email {
+        Some(email) if user.email_verified => email.clone(),
+        _ => return Ok(None),
+    };
+    if entry.created + token::PASSWORD_RESET_TTL <= now {
+        return Ok(None);
+    }
+    let token = token::issue(db, TokenPurpose::PasswordReset, user_id, &to)?;
+    Ok(Some(Email {
+        to,
+        subject: "Reset your password".to_owned(),
+        body: format!(
+            "Hello {},\n\n\
+             you can choose a new password for Nextflix by opening this link:\n\n\
+             {}/reset_password?token={}\n\n\
+             The link is valid for {} minutes and can only be used once. If you didn't ask for a \
+             new password, you can ignore this email.",
+            user.username,
+            public_url,
+            token,
+            token::PASSWORD_RESET_TTL / 60
+        ),
+    }))
+}
+
+fn compose<D: DbExt>(
+    db: &D,
+    user: &User,
+    entries: &[(u64, OutboxEntry)],
+    public_url: &str,
+) -> DbResult<Option<Email>> {
+    let to = match &user.email {
+        Some(email) if user.email_verified => email.clone(),
+        _ => return Ok(None),
+    };
+    let mut lines = Vec::new();
+    for (_, entry) in entries {
+        lines.extend(describe(db, &entry.event)?);
+    }
+    let subject = match lines.as_slice() {
+        [] => return Ok(None),
+        [line] => line.clone(),
+        lines => format!("{} new notifications", lines.len()),
+    };
+    Ok(Some(Email {
+        to,
+        subject,
+        body: format!(
+            "Hello {},\n\n{}\n\nSee your watchlist at {}/\n\n\
+             You can change how often you get these emails at {}/settings/email",
+            user.username,
+            lines
+                .iter()
+                .map(|line| format!("* {}", line))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            public_url,
+            public_url,
+        ),
+    }))
+}
+
+/// Sends all notifications that are due at `now`, returns the number of emails sent
+pub fn deliver_due<D: DbExt>(
+    db: &D,
+    mailer: &Mailer,
+    public_url: &str,
+    now: u64,
+) -> DbResult<usize> {
+    let mut due = BTreeMap::<u64, Vec<(u64, OutboxEntry)>>::new();
+    for (id, entry) in db.get_outbox()? {
+        if entry.next_attempt <= now {
+            due.entry(entry.user_id).or_default().push((id, entry));
+        }
+    }
+    let mut sent = 0;
+    for (user_id, entries) in due {
+        let user = db.get_user(user_id)?;
+        let (resets, entries): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(_, entry)| entry.event == Event::PasswordReset);
+        for reset in resets {
+            let email = match &user {
+                Some(user) => compose_password_reset(db, user_id, user, &reset.1, public_url, now)?,
+                None => None,
+            };
+            sent += send(db, mailer, user_id, email, vec![reset], now)? as usize;
+        }
+        if entries.is_empty() {
+            continue;
+        }
+        let email = match &user {
+            Some(user) if user.notifications != NotificationPreference::Off => {
+                compose(db, user, &entries, public_url)?
+            }
+            _ => None,
+        };
+        sent += send(db, mailer, user_id, email, entries, now)? as usize;
+    }
+    Ok(sent)
+}
+
+/// Sends the email and removes the entries it was composed of from the outbox, or schedules
+/// them for a retry. Returns whether an email was sent.
+fn send<D: DbExt>(
+    db: &D,
+    mailer: &Mailer,
+    user_id: u64,
+    email: Option<Email>,
+    entries: Vec<(u64, OutboxEntry)>,
+    now: u64,
+) -> DbResult<bool> {
+    let result = match &email {
+        Some(email) => mailer.send_blocking(email),
+        // Nothing to send, or nowhere to send it to
+        None => Ok(()),
+    };
+    match result {
+        Ok(()) => {
+            for (id, _) in entries {
+                db.remove_from_outbox(id)?;
+            }
+            Ok(email.is_some())
+        }
+        Err(err) => {
+            warn!("Can't send notification to user {}: {}", user_id, err);
+            for (id, mut entry) in entries {
+                entry.attempts += 1;
+                if entry.attempts >= MAX_ATTEMPTS {
+                    warn!(
+                        "Dropping notification {} after {} attempts",
+                        id, entry.attempts
+                    );
+                    db.remove_from_outbox(id)?;
+                } else {
+                    entry.next_attempt = now + retry_delay(entry.attempts);
+                    db.update_outbox(id, &entry)?;
+                }
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Starts the thread that delivers queued notifications and removes expired entries. They were
+/// just removed at startup, so the first cleanup happens after [`CLEANUP_INTERVAL`].
+pub fn spawn_worker<D: DbExt + Send + 'static>(
+    db: D,
+    mailer: Mailer,
+    public_url: String,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name("notifications".to_owned())
+        .spawn(move || {
+            let mut last_cleanup = unix_time();
+            loop {
+                let now = unix_time();
+                match deliver_due(&db, &mailer, &public_url, now) {
+                    Ok(0) => {}
+                    Ok(sent) => debug!("Sent {} notification emails", sent),
+                    Err(err) => warn!("Can't deliver notifications: {}", err),
+                }
+                if now >= last_cleanup + CLEANUP_INTERVAL {
+                    if let Err(err) = crate::remove_expired(&db, now) {
+                        warn!("Can't remove expired entries: {}", err);
+                    }
+                    last_cleanup = now;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+
+    fn setup(notifications: NotificationPreference) -> (MemoryDb, u64, u64, u64) {
+        let db = MemoryDb::new();
+        let movie_id = db
+            .add_movie(&Movie {
+                name: "Pulp Fiction".to_owned(),
+                year: Some(1994),
+                ..Default::default()
+            })
+            .unwrap();
+        let alice = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let bob = db
+            .add_user(&User {
+                username: "bob".to_owned(),
+                email: Some("bob@example.com".to_owned()),
+                email_verified: true,
+                notifications,
+                ..Default::default()
+            })
+            .unwrap();
+        queue(
+            &db,
+            bob,
+            Event::Recommendation {
+                from: alice,
+                movie_id,
+            },
+        )
+        .unwrap();
+        (db, alice, bob, movie_id)
+    }
+
+    #[test]
+    fn instant() {
+        let (db, ..) = setup(NotificationPreference::Instant);
+        let (mailer, sent) = Mailer::memory();
+        assert_eq!(deliver_due(&db, &mailer, "", unix_time()).unwrap(), 1);
+        assert_eq!(
+            sent.lock().unwrap()[0].subject,
+            "alice recommends Pulp Fiction (1994)"
+        );
+        assert!(db.get_outbox().unwrap().is_empty());
+    }
+
+    #[test]
+    fn daily_digest() {
+        let (db, alice, bob, movie_id) = setup(NotificationPreference::DailyDigest);
+        queue(
+            &db,
+            bob,
+            Event::Recommendation {
+                from: alice,
+                movie_id,
+            },
+        )
+        .unwrap();
+        let (mailer, sent) = Mailer::memory();
+        let now = unix_time();
+        assert_eq!(deliver_due(&db, &mailer, "", now).unwrap(), 0);
+        assert_eq!(deliver_due(&db, &mailer, "", next_digest(now)).unwrap(), 1);
+        assert_eq!(sent.lock().unwrap()[0].subject, "2 new notifications");
+    }
+
+    #[test]
+    fn off() {
+        let (db, ..) = setup(NotificationPreference::Off);
+        assert!(db.get_outbox().unwrap().is_empty());
+    }
+
+    #[test]
+    fn password_reset() {
+        let (db, _, bob, _) = setup(NotificationPreference::Off);
+        queue_password_reset(&db, bob).unwrap();
+        queue_password_reset(&db, bob).unwrap();
+        let (mailer, sent) = Mailer::memory();
+        let now = unix_time();
+        // Sent right away and on their own
+        assert_eq!(deliver_due(&db, &mailer, "", now).unwrap(), 2);
+        let sent = sent.lock().unwrap();
+        let tokens = sent
+            .iter()
+            .map(|email| {
+                let start = email.body.find("token=").unwrap() + "token=".len();
+                email.body[start..]
+                    .split_whitespace()
+                    .next()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(tokens[0], tokens[1]);
+        for token in &tokens {
+            let token = token::peek(&db, token, TokenPurpose::PasswordReset).unwrap();
+            assert_eq!(token.unwrap().user_id, bob);
+        }
+        assert!(db.get_outbox().unwrap().is_empty());
+
+        // Expired requests aren't sent anymore
+        queue_password_reset(&db, bob).unwrap();
+        let (mailer, sent) = Mailer::memory();
+        let later = now + token::PASSWORD_RESET_TTL + 1;
+        assert_eq!(deliver_due(&db, &mailer, "", later).unwrap(), 0);
+        assert!(sent.lock().unwrap().is_empty());
+        assert!(db.get_outbox().unwrap().is_empty());
+    }
+
+    #[test]
+    fn retry() {
+        let (db, ..) = setup(NotificationPreference::Instant);
+        // Nothing listens on port 1
+        let mailer = Mailer::new(&crate::config::MailConfig {
+            transport: crate::config::MailTransport::Smtp,
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: 1,
+            ..Default::default()
+        });
+        let now = unix_time();
+        assert_eq!(deliver_due(&db, &mailer, "", now).unwrap(), 0);
+        let (_, entry) = db.get_outbox().unwrap().remove(0);
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.next_attempt, now + RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), 2 * RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(30), MAX_RETRY_DELAY);
+
+        let mut now = now;
+        for _ in 1..MAX_ATTEMPTS {
+            now += MAX_RETRY_DELAY;
+            deliver_due(&db, &mailer, "", now).unwrap();
+        }
+        assert!(db.get_outbox().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,935 @@
+//! Login through an OpenID Connect provider.
+//!
+//! Uses the authorization code flow with PKCE. The endpoints of the provider are discovered from
+//! `<issuer>/.well-known/openid-configuration` on every login. While the user logs in at the
+//! provider, the nonce and code verifier are stored in the `oidc_logins` tree under the hash of
+//! the `state` parameter, which is also kept in a cookie so that the login can only be finished
+//! in the browser that started it. ID tokens signed with RS256 are verified with the keys of the
+//! provider, tokens signed with HS256 with the client secret.
+//!
+//! Identities are linked to users by issuer and subject. Somebody logging in with an unknown
+//! identity gets a new account if `oidc.allow_registration` is set, and logged in users can link
+//! further identities on the identities settings page.
+
+use crate::{
+    account::{render_disabled, render_message},
+    config::Config,
+    csrf::CsrfToken,
+    database::*,
+    log_error,
+    model::*,
+    render, session, token, totp, validation, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{
+    client::Client,
+    cookie::{Cookie, SameSite},
+    http::header,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+
+const STATE_COOKIE: &str = "oidc_state";
+const CALLBACK_PATH: &str = "/login/oidc/callback";
+/// Time in seconds the user has to log in at the provider
+const LOGIN_TTL: u64 = 10 * 60;
+/// Allowed difference between our clock and the one of the provider in seconds
+const CLOCK_SKEW: u64 = 60;
+/// Responses of the provider larger than this are rejected
+const MAX_RESPONSE_SIZE: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider couldn't be reached or returned an error
+    Http(String),
+    InvalidResponse(String),
+    InvalidToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Http(msg) => write!(f, "Request to provider failed: {}", msg),
+            OidcError::InvalidResponse(msg) => write!(f, "Invalid response from provider: {}", msg),
+            OidcError::InvalidToken(msg) => write!(f, "Invalid ID token: {}", msg),
+        }
+    }
+}
+
+fn invalid_token<S: Into<String>>(message: S) -> OidcError {
+    OidcError::InvalidToken(message.into())
+}
+
+/// The parts of the discovery document that are needed for logging in
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    /// Modulus of RSA keys
+    n: Option<String>,
+    /// Exponent of RSA keys
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    aud: Audience,
+    exp: u64,
+    iat: Option<u64>,
+    nonce: Option<String>,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+/// What the claims of an ID token have to match
+struct Expected<'a> {
+    issuer: &'a str,
+    client_id: &'a str,
+    client_secret: Option<&'a str>,
+    nonce: &'a str,
+    now: u64,
+}
+
+fn decode_base64(part: &str) -> Result<Vec<u8>, OidcError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| invalid_token("Invalid base64"))
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> Result<T, OidcError> {
+    serde_json::from_slice(&decode_base64(part)?)
+        .map_err(|err| invalid_token(format!("Invalid JSON: {}", err)))
+}
+
+fn verify_signature(
+    header: &JwtHeader,
+    message: &[u8],
+    signature: &[u8],
+    jwks: &Jwks,
+    client_secret: Option<&str>,
+) -> Result<(), OidcError> {
+    match header.alg.as_str() {
+        "RS256" => {
+            let verified = jwks
+                .keys
+                .iter()
+                .filter(|key| key.kty == "RSA")
+                .filter(|key| header.kid.is_none() || key.kid == header.kid)
+                .filter_map(|key| {
+                    Some((
+                        decode_base64(key.n.as_ref()?).ok()?,
+                        decode_base64(key.e.as_ref()?).ok()?,
+                    ))
+                })
+                .any(|(n, e)| {
+                    ring::signature::RsaPublicKeyComponents { n: &n, e: &e }
+                        .verify(
+                            &ring::signature::RSA_PKCS1_2048_8192_SHA256,
+                            message,
+                            signature,
+                        )
+                        .is_ok()
+                });
+            if verified {
+                Ok(())
+            } else {
+                Err(invalid_token("Invalid signature"))
+            }
+        }
+        "HS256" => {
+            let secret =
+                client_secret.ok_or_else(|| invalid_token("HS256 needs a client secret"))?;
+            let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+            ring::hmac::verify(&key, message, signature)
+                .map_err(|_| invalid_token("Invalid signature"))
+        }
+        alg => Err(invalid_token(format!("Unsupported algorithm {}", alg))),
+    }
+}
+
+/// Checks the signature and claims of an ID token and returns the claims
+fn validate_id_token(
+    id_token: &str,
+    jwks: &Jwks,
+    expected: &Expected,
+) -> Result<Claims, OidcError> {
+    let parts = id_token.split('.').collect::<Vec<_>>();
+    let (header, payload, signature) = match parts.as_slice() {
+        [header, payload, signature] => (*header, *payload, *signature),
+        _ => return Err(invalid_token("Not a JWT")),
+    };
+    let message_len = header.len() + 1 + payload.len();
+    verify_signature(
+        &decode_json(header)?,
+        &id_token.as_bytes()[..message_len],
+        &decode_base64(signature)?,
+        jwks,
+        expected.client_secret,
+    )?;
+    let claims: Claims = decode_json(payload)?;
+    if claims.iss != expected.issuer {
+        return Err(invalid_token(format!("Unexpected issuer {}", claims.iss)));
+    }
+    if !claims.aud.contains(expected.client_id) {
+        return Err(invalid_token("Not issued for this client"));
+    }
+    if claims.exp + CLOCK_SKEW <= expected.now {
+        return Err(invalid_token("Expired"));
+    }
+    if claims
+        .iat
+        .is_some_and(|iat| iat > expected.now + CLOCK_SKEW)
+    {
+        return Err(invalid_token("Issued in the future"));
+    }
+    if claims.nonce.as_deref() != Some(expected.nonce) {
+        return Err(invalid_token("Nonce doesn't match"));
+    }
+    Ok(claims)
+}
+
+async fn get_json<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T, OidcError> {
+    let mut response = client
+        .get(url)
+        .header(header::ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|err| OidcError::Http(format!("{}: {}", url, err)))?;
+    if !response.status().is_success() {
+        return Err(OidcError::Http(format!(
+            "{} returned {}",
+            url,
+            response.status()
+        )));
+    }
+    response
+        .json()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(|err| OidcError::InvalidResponse(format!("{}: {}", url, err)))
+}
+
+async fn discover(client: &Client, issuer: &str) -> Result<ProviderMetadata, OidcError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let metadata: ProviderMetadata = get_json(client, &url).await?;
+    if metadata.issuer != issuer {
+        return Err(OidcError::InvalidResponse(format!(
+            "Discovery document is for issuer {}",
+            metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+fn redirect_uri(config: &Config) -> String {
+    format!("{}{}", config.public_url, CALLBACK_PATH)
+}
+
+/// Redeems the authorization code for an ID token and returns its validated claims
+async fn exchange_code(
+    config: &Config,
+    issuer: &str,
+    code: &str,
+    login: &OidcLogin,
+) -> Result<Claims, OidcError> {
+    let client = Client::default();
+    let metadata = discover(&client, issuer).await?;
+    let redirect_uri = redirect_uri(config);
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &redirect_uri),
+        ("code_verifier", &login.code_verifier),
+    ];
+    let mut request = client
+        .post(&metadata.token_endpoint)
+        .header(header::ACCEPT, "application/json");
+    match &config.oidc.client_secret {
+        Some(secret) => request = request.basic_auth(&config.oidc.client_id, Some(secret)),
+        // Public clients only identify themselves
+        None => form.push(("client_id", &config.oidc.client_id)),
+    }
+    let mut response = request
+        .send_form(&form)
+        .await
+        .map_err(|err| OidcError::Http(format!("{}: {}", metadata.token_endpoint, err)))?;
+    if !response.status().is_success() {
+        return Err(OidcError::Http(format!(
+            "{} returned {}",
+            metadata.token_endpoint,
+            response.status()
+        )));
+    }
+    let tokens: TokenResponse = response
+        .json()
+        .limit(MAX_RESPONSE_SIZE)
+        .await
+        .map_err(|err| OidcError::InvalidResponse(err.to_string()))?;
+    let jwks: Jwks = get_json(&client, &metadata.jwks_uri).await?;
+    validate_id_token(
+        &tokens.id_token,
+        &jwks,
+        &Expected {
+            issuer,
+            client_id: &config.oidc.client_id,
+            client_secret: config.oidc.client_secret.as_deref(),
+            nonce: &login.nonce,
+            now: unix_time(),
+        },
+    )
+}
+
+fn login_failed(
+    tera: &tera::Tera,
+    config: &Config,
+    err: OidcError,
+) -> actix_web::Result<HttpResponse> {
+    warn!("OpenID Connect login failed: {}", err);
+    render_message(
+        tera,
+        "Login failed",
+        &format!(
+            "Logging in with {} didn't work, please try again later.",
+            config.oidc.name
+        ),
+        HttpResponse::BadGateway(),
+    )
+}
+
+/// Remembers a new login and redirects to the provider
+async fn start<D: DbExt>(
+    db: &D,
+    tera: &tera::Tera,
+    config: &Config,
+    link_user: Option<u64>,
+) -> actix_web::Result<HttpResponse> {
+    let issuer = match &config.oidc.issuer {
+        Some(issuer) => issuer,
+        None => return Err(DbError::NotFound.into()),
+    };
+    let metadata = match discover(&Client::default(), issuer).await {
+        Ok(metadata) => metadata,
+        Err(err) => return login_failed(tera, config, err),
+    };
+    let state = token::generate();
+    let login = OidcLogin {
+        nonce: token::generate(),
+        code_verifier: token::generate(),
+        expires: unix_time() + LOGIN_TTL,
+        link_user,
+    };
+    db.add_oidc_login(&token::hash(&state), &login)?;
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", &config.oidc.client_id),
+        ("redirect_uri", &redirect_uri(config)),
+        ("scope", "openid profile email"),
+        ("state", &state),
+        ("nonce", &login.nonce),
+        // The S256 challenge is the same hash that is used for storing tokens
+        ("code_challenge", &token::hash(&login.code_verifier)),
+        ("code_challenge_method", "S256"),
+    ])
+    .map_err(|err| log_error(err, "Encoding error"))?;
+    let separator = if metadata.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let cookie = Cookie::build(STATE_COOKIE, state)
+        .path(CALLBACK_PATH)
+        .http_only(true)
+        .secure(config.cookie.secure)
+        // Has to be sent when the provider redirects back
+        .same_site(SameSite::Lax)
+        .max_age(LOGIN_TTL as i64)
+        .finish();
+    Ok(HttpResponse::Found()
+        .header(
+            "location",
+            format!("{}{}{}", metadata.authorization_endpoint, separator, query),
+        )
+        .cookie(cookie)
+        .finish())
+}
+
+pub async fn login<D: DbExt>(
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    start(&**db, &tera, &config, None).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Username for a new account, based on what the provider knows about the user
+fn new_username<D: DbExt>(db: &D, claims: &Claims) -> DbResult<Option<String>> {
+    let base = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref()?.split('@').next())
+        .unwrap_or("")
+        .chars()
+        .filter(|c| validation::is_username_character(*c))
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(28)
+        .collect::<String>();
+    let base = if base.len() < 3 { "user" } else { &base };
+    for i in 1..100 {
+        let username = match i {
+            1 => base.to_owned(),
+            i => format!("{}{}", base, i),
+        };
+        let mut errors = validation::FieldErrors::default();
+        validation::validate_username(&username, &mut errors);
+        if errors.is_empty() && db.get_user_by_username(&username)?.is_none() {
+            return Ok(Some(username));
+        }
+    }
+    Ok(None)
+}
+
+fn register<D: DbExt>(db: &D, claims: &Claims) -> DbResult<Option<u64>> {
+    let username = match new_username(db, claims)? {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let email = match &claims.email {
+        Some(email) if claims.email_verified && db.get_user_by_email(email)?.is_none() => {
+            Some(email.clone())
+        }
+        _ => None,
+    };
+    let user_id = db.add_user(&User {
+        username,
+        // Can only log in through the provider until a password is set
+        password_hash: String::new(),
+        email_verified: email.is_some(),
+        email,
+        identities: vec![ExternalIdentity {
+            issuer: claims.iss.clone(),
+            subject: claims.sub.clone(),
+        }],
+        ..Default::default()
+    })?;
+    info!("registered {} through OpenID Connect", user_id);
+    Ok(Some(user_id))
+}
+
+fn link<D: DbExt>(
+    db: &D,
+    tera: &tera::Tera,
+    user_id: u64,
+    claims: &Claims,
+) -> actix_web::Result<HttpResponse> {
+    let identity = ExternalIdentity {
+        issuer: claims.iss.clone(),
+        subject: claims.sub.clone(),
+    };
+    let linked = db.modify_user(user_id, |user| {
+        if !user.identities.contains(&identity) {
+            user.identities.push(identity.clone());
+        }
+        Ok(())
+    });
+    match linked {
+        Ok(()) => {}
+        Err(DbError::Conflict(_)) => {
+            return render_message(
+                tera,
+                "Already linked",
+                "This account is already linked to another user.",
+                HttpResponse::Conflict(),
+            )
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/identities")
+        .finish())
+}
+
+pub async fn callback<D: DbExt>(
+    query: web::Query<CallbackQuery>,
+    req: HttpRequest,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let issuer = match &config.oidc.issuer {
+        Some(issuer) => issuer,
+        None => return Err(DbError::NotFound.into()),
+    };
+    let expired = || {
+        render_message(
+            &tera,
+            "Login expired",
+            "This login has expired or was already used, please try again.",
+            HttpResponse::BadRequest(),
+        )
+    };
+    if let Some(error) = &query.error {
+        info!("OpenID Connect login cancelled: {}", error);
+        return render_message(
+            &tera,
+            "Login cancelled",
+            &format!("Logging in with {} was cancelled.", config.oidc.name),
+            HttpResponse::BadRequest(),
+        );
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return expired(),
+    };
+    // Only the browser that started the login may finish it
+    if req
+        .cookie(STATE_COOKIE)
+        .map(|c| c.value().to_owned())
+        .as_ref()
+        != Some(state)
+    {
+        return expired();
+    }
+    let login = match db.take_oidc_login(&token::hash(state))? {
+        Some(login) if login.expires > unix_time() => login,
+        _ => return expired(),
+    };
+    let claims = match exchange_code(&config, issuer, code, &login).await {
+        Ok(claims) => claims,
+        Err(err) => return login_failed(&tera, &config, err),
+    };
+    let mut response = if let Some(link_user) = login.link_user {
+        match session::current_user(&id, &**db)? {
+            Some(current) if current.user_id == link_user => {
+                link(&**db, &tera, link_user, &claims)?
+            }
+            _ => return expired(),
+        }
+    } else {
+        let user_id = match db.get_user_by_identity(&claims.iss, &claims.sub)? {
+            Some((_, user)) if user.disabled => return render_disabled(&tera),
+            Some((user_id, user)) => Some((user_id, totp::is_enabled(&user))),
+            None if config.oidc.allow_registration => {
+                register(&**db, &claims)?.map(|user_id| (user_id, false))
+            }
+            None => None,
+        };
+        match user_id {
+            // The provider only replaces the password, not the second factor
+            Some((user_id, true)) => totp::start_login(&**db, &config, user_id)?,
+            Some((user_id, false)) => {
+                session::start_session(&id, &**db, user_id, &req)?;
+                HttpResponse::Found().header("location", "/").finish()
+            }
+            None => {
+                return render_message(
+                    &tera,
+                    "No account",
+                    &format!(
+                        "Your {} account isn't linked to a user. Log in with your password and \
+                         link it on the settings page.",
+                        config.oidc.name
+                    ),
+                    HttpResponse::Forbidden(),
+                )
+            }
+        }
+    };
+    response.add_cookie(
+        &Cookie::build(STATE_COOKIE, "")
+            .path(CALLBACK_PATH)
+            .max_age(0)
+            .finish(),
+    )?;
+    Ok(response)
+}
+
+pub async fn identities<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("identities", &current.user.identities);
+    ctx.insert("oidc_enabled", &config.oidc.issuer.is_some());
+    ctx.insert("oidc_name", &config.oidc.name);
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "identities.html", &ctx, HttpResponse::Ok())
+}
+
+/// Starts a login at the provider whose identity is then linked to the current user
+pub async fn link_post<D: DbExt>(
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    config: web::Data<Config>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    start(&**db, &tera, &config, Some(current.user_id)).await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnlinkParams {
+    pub issuer: String,
+    pub subject: String,
+}
+
+pub async fn unlink_post<D: DbExt>(
+    params: web::Form<UnlinkParams>,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let identity = ExternalIdentity {
+        issuer: params.issuer.clone(),
+        subject: params.subject.clone(),
+    };
+    // Checked on the stored user, so that two requests can't unlink the last two identities
+    let unlinked = db.modify_user(current.user_id, |user| {
+        if !user.identities.contains(&identity) {
+            return Err(DbError::NotFound);
+        }
+        if user.password_hash.is_empty() && user.identities.len() == 1 {
+            return Ok(false);
+        }
+        user.identities.retain(|other| *other != identity);
+        Ok(true)
+    })?;
+    if !unlinked {
+        return render_message(
+            &tera,
+            "Can't unlink",
+            "This is the only way you can log in. Set a password first.",
+            HttpResponse::Conflict(),
+        );
+    }
+    Ok(HttpResponse::Found()
+        .header("location", "/settings/identities")
+        .finish())
+}
+
+/// A local OpenID Connect provider for tests
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use actix_web::{test, App};
+    use ring::{rand::SystemRandom, signature};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    pub const CLIENT_ID: &str = "nextflix";
+    pub const CLIENT_SECRET: &str = "secret";
+    const PRIVATE_KEY: &[u8] = include_bytes!("testdata/oidc_test_key.pk8");
+    const JWKS: &str = include_str!("testdata/oidc_test_jwks.json");
+
+    fn encode<T: Serialize>(value: &T) -> String {
+        base64::encode_config(serde_json::to_vec(value).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Signs `claims` with the test key
+    pub fn sign_rs256(claims: &serde_json::Value) -> String {
+        let key = signature::RsaKeyPair::from_pkcs8(PRIVATE_KEY).unwrap();
+        let message = format!(
+            "{}.{}",
+            encode(&serde_json::json!({"alg": "RS256", "kid": "test"})),
+            encode(claims)
+        );
+        let mut signature = vec![0; key.public_modulus_len()];
+        key.sign(
+            &signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .unwrap();
+        format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn jwks() -> Jwks {
+        serde_json::from_str(JWKS).unwrap()
+    }
+
+    /// A login the user finished at the provider, waiting for the code to be redeemed
+    struct Authorization {
+        code_challenge: String,
+        nonce: String,
+        subject: String,
+        username: String,
+    }
+
+    #[derive(Default)]
+    struct State {
+        issuer: String,
+        codes: HashMap<String, Authorization>,
+    }
+
+    pub struct MockIssuer {
+        pub issuer: String,
+        state: Arc<Mutex<State>>,
+        _server: test::TestServer,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenParams {
+        grant_type: String,
+        code: String,
+        code_verifier: String,
+    }
+
+    async fn discovery(state: web::Data<Arc<Mutex<State>>>) -> HttpResponse {
+        let issuer = state.lock().unwrap().issuer.clone();
+        HttpResponse::Ok().json(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        }))
+    }
+
+    async fn keys() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(JWKS)
+    }
+
+    async fn token(
+        params: web::Form<TokenParams>,
+        req: HttpRequest,
+        state: web::Data<Arc<Mutex<State>>>,
+    ) -> HttpResponse {
+        let credentials = format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", CLIENT_ID, CLIENT_SECRET))
+        );
+        if req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .map(|v| v.as_bytes())
+            != Some(credentials.as_bytes())
+        {
+            return HttpResponse::Unauthorized().finish();
+        }
+        let mut state = state.lock().unwrap();
+        let authorization = match state.codes.remove(&params.code) {
+            Some(authorization) if params.grant_type == "authorization_code" => authorization,
+            _ => return HttpResponse::BadRequest().finish(),
+        };
+        if token::hash(&params.code_verifier) != authorization.code_challenge {
+            return HttpResponse::BadRequest().finish();
+        }
+        let now = unix_time();
+        HttpResponse::Ok().json(serde_json::json!({
+            "access_token": "unused",
+            "token_type": "Bearer",
+            "id_token": sign_rs256(&serde_json::json!({
+                "iss": state.issuer,
+                "sub": authorization.subject,
+                "aud": CLIENT_ID,
+                "exp": now + 300,
+                "iat": now,
+                "nonce": authorization.nonce,
+                "preferred_username": authorization.username,
+            })),
+        }))
+    }
+
+    impl MockIssuer {
+        pub fn start() -> Self {
+            let state = Arc::new(Mutex::new(State::default()));
+            let data = state.clone();
+            let server = test::start(move || {
+                App::new()
+                    .data(data.clone())
+                    .route(
+                        "/.well-known/openid-configuration",
+                        web::get().to(discovery),
+                    )
+                    .route("/jwks", web::get().to(keys))
+                    .route("/token", web::post().to(token))
+            });
+            let issuer = server.url("").trim_end_matches('/').to_owned();
+            state.lock().unwrap().issuer = issuer.clone();
+            MockIssuer {
+                issuer,
+                state,
+                _server: server,
+            }
+        }
+
+        /// Logs in `subject` at the provider, given the location the client redirected to.
+        /// Returns the query string the provider redirects back with.
+        pub fn authorize(&self, location: &str, subject: &str, username: &str) -> String {
+            let query = &location[location.find('?').unwrap() + 1..];
+            let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+            assert_eq!(params["client_id"], CLIENT_ID);
+            assert_eq!(params["code_challenge_method"], "S256");
+            let code = token::generate();
+            self.state.lock().unwrap().codes.insert(
+                code.clone(),
+                Authorization {
+                    code_challenge: params["code_challenge"].clone(),
+                    nonce: params["nonce"].clone(),
+                    subject: subject.to_owned(),
+                    username: username.to_owned(),
+                },
+            );
+            serde_urlencoded::to_string([("code", &code), ("state", &params["state"])]).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ISSUER: &str = "https://id.example";
+
+    fn expected(now: u64) -> Expected<'static> {
+        Expected {
+            issuer: ISSUER,
+            client_id: mock::CLIENT_ID,
+            client_secret: Some(mock::CLIENT_SECRET),
+            nonce: "nonce",
+            now,
+        }
+    }
+
+    fn claims(now: u64) -> serde_json::Value {
+        json!({
+            "iss": ISSUER,
+            "sub": "42",
+            "aud": [mock::CLIENT_ID, "other"],
+            "exp": now + 300,
+            "iat": now,
+            "nonce": "nonce",
+            "email": "alice@example.com",
+            "email_verified": true,
+        })
+    }
+
+    #[test]
+    fn valid_tokens() {
+        let now = unix_time();
+        let jwks = mock::jwks();
+        let token = mock::sign_rs256(&claims(now));
+        let claims = validate_id_token(&token, &jwks, &expected(now)).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert!(claims.email_verified);
+
+        // Signed with the client secret
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(r#"{"alg":"HS256"}"#, base64::URL_SAFE_NO_PAD),
+            token.split('.').nth(1).unwrap()
+        );
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, mock::CLIENT_SECRET.as_bytes());
+        let signature = ring::hmac::sign(&key, message.as_bytes());
+        let token = format!(
+            "{}.{}",
+            message,
+            base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+        );
+        assert!(validate_id_token(&token, &jwks, &expected(now)).is_ok());
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let now = unix_time();
+        let jwks = mock::jwks();
+        let reject = |claims: &serde_json::Value| {
+            let token = mock::sign_rs256(claims);
+            assert!(validate_id_token(&token, &jwks, &expected(now)).is_err());
+        };
+        let mut wrong = claims(now);
+        wrong["nonce"] = json!("other");
+        reject(&wrong);
+        let mut wrong = claims(now);
+        wrong["aud"] = json!("other");
+        reject(&wrong);
+        let mut wrong = claims(now);
+        wrong["iss"] = json!("https://evil.example");
+        reject(&wrong);
+        let mut wrong = claims(now);
+        wrong["exp"] = json!(now - CLOCK_SKEW - 1);
+        reject(&wrong);
+
+        // Tampered payload
+        let token = mock::sign_rs256(&claims(now));
+        let mut parts = token.split('.').map(str::to_owned).collect::<Vec<_>>();
+        let mut tampered = claims(now);
+        tampered["sub"] = json!("1");
+        parts[1] = base64::encode_config(tampered.to_string(), base64::URL_SAFE_NO_PAD);
+        assert!(validate_id_token(&parts.join("."), &jwks, &expected(now)).is_err());
+
+        // Unsigned
+        parts[0] = base64::encode_config(r#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD);
+        parts[2] = String::new();
+        assert!(validate_id_token(&parts.join("."), &jwks, &expected(now)).is_err());
+    }
+}
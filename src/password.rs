@@ -0,0 +1,279 @@
+//! Hashing and verifying passwords.
+//!
+//! New hashes use the algorithm and parameters of [`PasswordConfig`]. Hashes of either algorithm
+//! can be verified, so changing the configuration doesn't lock anybody out. Instead
+//! [`verify_and_upgrade`] replaces outdated hashes when users log in.
+//!
+//! Hashing is slow on purpose, so the async functions run it on the blocking thread pool
+//! instead of the worker threads.
+
+use crate::{
+    config::{PasswordAlgorithm, PasswordConfig},
+    database::*,
+    log_error,
+    model::User,
+};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::RngCore;
+use std::{convert::TryFrom, fmt};
+
+/// bcrypt ignores everything after the first 72 bytes
+const BCRYPT_MAX_BYTES: usize = 72;
+/// Argon2 accepts any length, this only keeps requests from hashing huge inputs
+const ARGON2_MAX_BYTES: usize = 1024;
+
+#[derive(Debug)]
+pub enum PasswordError {
+    Bcrypt(bcrypt::BcryptError),
+    Argon2(argon2::Error),
+    Hash(argon2::password_hash::Error),
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordError::Bcrypt(err) => write!(f, "bcrypt error: {}", err),
+            PasswordError::Argon2(err) => write!(f, "argon2 error: {}", err),
+            PasswordError::Hash(err) => write!(f, "Invalid password hash: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PasswordError {}
+
+impl From<bcrypt::BcryptError> for PasswordError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        PasswordError::Bcrypt(err)
+    }
+}
+
+impl From<argon2::Error> for PasswordError {
+    fn from(err: argon2::Error) -> Self {
+        PasswordError::Argon2(err)
+    }
+}
+
+impl From<argon2::password_hash::Error> for PasswordError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        PasswordError::Hash(err)
+    }
+}
+
+fn argon2_params(config: &PasswordConfig) -> Result<argon2::Params, PasswordError> {
+    Ok(argon2::Params::new(
+        config.argon2_memory,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        None,
+    )?)
+}
+
+/// Longest password in bytes that the configured algorithm fully takes into account
+pub fn max_bytes(config: &PasswordConfig) -> usize {
+    match config.algorithm {
+        PasswordAlgorithm::Bcrypt => BCRYPT_MAX_BYTES,
+        PasswordAlgorithm::Argon2id => ARGON2_MAX_BYTES,
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    hash.starts_with("$2")
+}
+
+/// Hashes `password` with a random salt
+pub fn hash(config: &PasswordConfig, password: &str) -> Result<String, PasswordError> {
+    match config.algorithm {
+        PasswordAlgorithm::Bcrypt => Ok(bcrypt::hash(password, config.bcrypt_cost)?),
+        PasswordAlgorithm::Argon2id => {
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let argon2 = argon2::Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                argon2_params(config)?,
+            );
+            Ok(argon2
+                .hash_password(password.as_bytes(), &SaltString::encode_b64(&salt)?)?
+                .to_string())
+        }
+    }
+}
+
+/// Checks `password` against a hash of any supported algorithm. The empty hash of users without
+/// a password never matches.
+pub fn verify(password: &str, hash: &str) -> Result<bool, PasswordError> {
+    if hash.is_empty() {
+        Ok(false)
+    } else if is_bcrypt(hash) {
+        Ok(bcrypt::verify(password, hash)?)
+    } else {
+        // The parameters are taken from the hash
+        match argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &PasswordHash::new(hash)?)
+        {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Whether `hash` was made with another algorithm or other parameters than configured
+pub fn needs_rehash(config: &PasswordConfig, hash: &str) -> bool {
+    match config.algorithm {
+        PasswordAlgorithm::Bcrypt => {
+            // $2b$<cost>$<salt and hash>
+            !is_bcrypt(hash)
+                || hash.split('$').nth(2) != Some(&format!("{:02}", config.bcrypt_cost))
+        }
+        PasswordAlgorithm::Argon2id => {
+            let parsed = match PasswordHash::new(hash) {
+                Ok(parsed) => parsed,
+                Err(_) => return true,
+            };
+            let params = match argon2::Params::try_from(&parsed) {
+                Ok(params) => params,
+                Err(_) => return true,
+            };
+            parsed.algorithm != argon2::Algorithm::Argon2id.ident()
+                || params.m_cost() != config.argon2_memory
+                || params.t_cost() != config.argon2_iterations
+                || params.p_cost() != config.argon2_parallelism
+        }
+    }
+}
+
+/// [`hash`] on the blocking thread pool
+pub async fn hash_blocking(config: &PasswordConfig, password: &str) -> actix_web::Result<String> {
+    let (config, password) = (config.clone(), password.to_owned());
+    actix_web::web::block(move || hash(&config, &password))
+        .await
+        .map_err(|err| log_error(err, "Hashing error"))
+}
+
+/// [`verify`] on the blocking thread pool
+pub async fn verify_blocking(password: &str, hash: &str) -> actix_web::Result<bool> {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+    actix_web::web::block(move || verify(&password, &hash))
+        .await
+        .map_err(|err| log_error(err, "Verification error"))
+}
+
+/// Verifies the password of a user that is logging in and replaces the hash if it is outdated
+pub async fn verify_and_upgrade<D: DbExt>(
+    db: &D,
+    config: &PasswordConfig,
+    user_id: u64,
+    user: &mut User,
+    password: &str,
+) -> actix_web::Result<bool> {
+    if !verify_blocking(password, &user.password_hash).await? {
+        return Ok(false);
+    }
+    if needs_rehash(config, &user.password_hash) {
+        let new_hash = hash_blocking(config, password).await?;
+        let upgraded = db.modify_user(user_id, |stored| {
+            // Keep a password that was changed while hashing
+            if stored.password_hash != user.password_hash {
+                return Ok(false);
+            }
+            stored.password_hash = new_hash.clone();
+            Ok(true)
+        })?;
+        if upgraded {
+            user.password_hash = new_hash;
+            log::info!("rehashed password of {}", user_id);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(algorithm: PasswordAlgorithm) -> PasswordConfig {
+        PasswordConfig {
+            algorithm,
+            bcrypt_cost: 4,
+            argon2_memory: 64,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn algorithms() {
+        for algorithm in &[PasswordAlgorithm::Bcrypt, PasswordAlgorithm::Argon2id] {
+            let config = config(*algorithm);
+            let hash = hash(&config, "correct horse").unwrap();
+            assert!(verify("correct horse", &hash).unwrap());
+            assert!(!verify("wrong horse", &hash).unwrap());
+            assert!(!needs_rehash(&config, &hash));
+        }
+        assert!(hash(&config(PasswordAlgorithm::Argon2id), "a")
+            .unwrap()
+            .starts_with("$argon2id$"));
+        assert!(!verify("", "").unwrap());
+        assert!(verify("a", "garbage").is_err());
+    }
+
+    #[test]
+    fn rehash() {
+        let bcrypt = config(PasswordAlgorithm::Bcrypt);
+        let argon2 = config(PasswordAlgorithm::Argon2id);
+        let bcrypt_hash = hash(&bcrypt, "password").unwrap();
+        let argon2_hash = hash(&argon2, "password").unwrap();
+        assert!(needs_rehash(&argon2, &bcrypt_hash));
+        assert!(needs_rehash(&bcrypt, &argon2_hash));
+        assert!(needs_rehash(
+            &PasswordConfig {
+                bcrypt_cost: 5,
+                ..bcrypt.clone()
+            },
+            &bcrypt_hash
+        ));
+        assert!(needs_rehash(
+            &PasswordConfig {
+                argon2_iterations: 2,
+                ..argon2.clone()
+            },
+            &argon2_hash
+        ));
+        // Hashes from before the algorithm was configurable
+        assert!(needs_rehash(
+            &PasswordConfig {
+                bcrypt_cost: bcrypt::DEFAULT_COST,
+                ..bcrypt
+            },
+            &bcrypt::hash("password", 4).unwrap()
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn upgrade() {
+        let db = crate::memory_db::MemoryDb::new();
+        let mut user = User {
+            username: "alice".to_owned(),
+            password_hash: bcrypt::hash("password", 4).unwrap(),
+            ..Default::default()
+        };
+        let user_id = db.add_user(&user).unwrap();
+        let config = config(PasswordAlgorithm::Argon2id);
+        assert!(
+            !verify_and_upgrade(&db, &config, user_id, &mut user, "wrong")
+                .await
+                .unwrap()
+        );
+        assert!(user.password_hash.starts_with("$2"));
+        assert!(
+            verify_and_upgrade(&db, &config, user_id, &mut user, "password")
+                .await
+                .unwrap()
+        );
+        let stored = db.get_user(user_id).unwrap().unwrap().password_hash;
+        assert!(stored.starts_with("$argon2id$"));
+        assert_eq!(stored, user.password_hash);
+        assert!(verify("password", &stored).unwrap());
+    }
+}
@@ -0,0 +1,286 @@
+//! Downloading all data of an account and deleting it.
+
+use crate::{
+    account::render_message, csrf::CsrfToken, database::*, log_error, model::*, password, render,
+    session, watchlist, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+struct MovieInfo {
+    id: u64,
+    name: String,
+    year: Option<u16>,
+}
+
+impl MovieInfo {
+    /// `None` for movies that were removed, they can stay in friend lists and groups if they
+    /// were added while `remove_movie` ran
+    fn load<D: DbExt>(db: &D, id: u64) -> DbResult<Option<MovieInfo>> {
+        Ok(db.get_movie(id)?.map(|movie| MovieInfo {
+            id,
+            name: movie.name,
+            year: movie.year,
+        }))
+    }
+}
+
+#[derive(Serialize)]
+struct FriendInfo {
+    id: u64,
+    username: String,
+    /// Movies the friend recommended
+    recommendations: Vec<MovieInfo>,
+}
+
+#[derive(Serialize)]
+struct WatchlistInfo {
+    #[serde(flatten)]
+    movie: MovieInfo,
+    recommended_by: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct GroupInfo {
+    id: u64,
+    name: String,
+    is_owner: bool,
+    /// Movies of the shared watchlist the user added
+    added_movies: Vec<MovieInfo>,
+    /// Movies of the shared watchlist the user voted for
+    votes: Vec<MovieInfo>,
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    created: u64,
+    last_seen: u64,
+    user_agent: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ApiTokenInfo {
+    name: String,
+    scopes: Vec<Scope>,
+    created: u64,
+    expires: Option<u64>,
+    last_used: Option<u64>,
+}
+
+/// Everything stored about a user, except for secrets like the password hash
+#[derive(Serialize)]
+struct Export {
+    id: u64,
+    username: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    email_verified: bool,
+    notification_preference: NotificationPreference,
+    role: Role,
+    two_factor_enabled: bool,
+    identities: Vec<ExternalIdentity>,
+    friends: Vec<FriendInfo>,
+    /// Users that added this one as a friend
+    added_by: Vec<u64>,
+    watchlist: Vec<WatchlistInfo>,
+    groups: Vec<GroupInfo>,
+    sessions: Vec<SessionInfo>,
+    api_tokens: Vec<ApiTokenInfo>,
+    notifications: Vec<Notification>,
+}
+
+fn export<D: DbExt>(db: &D, user_id: u64, user: &User) -> DbResult<Export> {
+    let mut friends = Vec::new();
+    for (friend_id, friend_data) in &user.friends {
+        // Removed users stay in friend lists until `remove_user` gets to them
+        let friend = match db.get_user(*friend_id)? {
+            Some(friend) => friend,
+            None => continue,
+        };
+        friends.push(FriendInfo {
+            id: *friend_id,
+            username: friend.username,
+            recommendations: friend_data
+                .movies
+                .iter()
+                .filter_map(|movie_id| MovieInfo::load(db, *movie_id).transpose())
+                .collect::<DbResult<_>>()?,
+        });
+    }
+    friends.sort_by_key(|friend| friend.id);
+    let added_by = watchlist::added_by(db, user_id)?
+        .into_iter()
+        .map(|(other_id, _)| other_id)
+        .collect();
+    let watchlist = watchlist::watchlist(db, user, &Default::default())?
+        .into_iter()
+        .map(|entry| WatchlistInfo {
+            movie: MovieInfo {
+                id: entry.movie_id,
+                name: entry.movie.name,
+                year: entry.movie.year,
+            },
+            recommended_by: entry.recommended_by,
+        })
+        .collect();
+    let groups = db
+        .get_groups_by_user(user_id)?
+        .into_iter()
+        .map(|(group_id, group)| {
+            let movies_where = |matches: &dyn Fn(&GroupMovie) -> bool| {
+                group
+                    .movies
+                    .iter()
+                    .filter(|movie| matches(movie))
+                    .filter_map(|movie| MovieInfo::load(db, movie.movie_id).transpose())
+                    .collect::<DbResult<Vec<_>>>()
+            };
+            Ok(GroupInfo {
+                id: group_id,
+                name: group.name.clone(),
+                is_owner: group.owner == user_id,
+                added_movies: movies_where(&|movie| movie.added_by == user_id)?,
+                votes: movies_where(&|movie| movie.votes.contains(&user_id))?,
+            })
+        })
+        .collect::<DbResult<_>>()?;
+    let sessions = db
+        .get_sessions_by_user(user_id)?
+        .into_iter()
+        .map(|(_, session)| SessionInfo {
+            created: session.created,
+            last_seen: session.last_seen,
+            user_agent: session.user_agent,
+        })
+        .collect();
+    let api_tokens = db
+        .get_api_tokens_by_user(user_id)?
+        .into_iter()
+        .map(|(_, token)| ApiTokenInfo {
+            name: token.name,
+            scopes: token.scopes,
+            created: token.created,
+            expires: token.expires,
+            last_used: token.last_used,
+        })
+        .collect();
+    let notifications = db
+        .get_notifications(user_id)?
+        .into_iter()
+        .map(|(_, notification)| notification)
+        .collect();
+    Ok(Export {
+        id: user_id,
+        username: user.username.clone(),
+        display_name: user.display_name.clone(),
+        email: user.email.clone(),
+        email_verified: user.email_verified,
+        notification_preference: user.notifications,
+        role: user.role,
+        two_factor_enabled: crate::totp::is_enabled(user),
+        identities: user.identities.clone(),
+        friends,
+        added_by,
+        watchlist,
+        groups,
+        sessions,
+        api_tokens,
+        notifications,
+    })
+}
+
+pub async fn account<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("has_password", &!current.user.password_hash.is_empty());
+    ctx.insert("error", &None::<&str>);
+    render(&tera, "account.html", &ctx, HttpResponse::Ok())
+}
+
+/// "Download my data", a JSON file with everything stored about the user
+pub async fn export_data<D: DbExt>(
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let export = export(&**db, current.user_id, &current.user)?;
+    let body =
+        serde_json::to_vec_pretty(&export).map_err(|err| log_error(err, "Serialization error"))?;
+    log::info!("export {}", current.user_id);
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(
+            "content-disposition",
+            "attachment; filename=\"nextflix-data.json\"",
+        )
+        .body(body))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteParams {
+    /// The password, or the username for users that only log in through OpenID Connect
+    pub confirmation: String,
+}
+
+/// Deletes the account with everything belonging to it, including the friend list entries of
+/// other users
+pub async fn delete_post<D: DbExt>(
+    params: web::Form<DeleteParams>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let has_password = !current.user.password_hash.is_empty();
+    let confirmed = if has_password {
+        password::verify_blocking(&params.confirmation, &current.user.password_hash).await?
+    } else {
+        params.confirmation == current.user.username
+    };
+    let error = if !confirmed {
+        Some(if has_password {
+            "Wrong password"
+        } else {
+            "Enter your username to confirm"
+        })
+    } else if current.user.role == Role::Admin
+        && !db
+            .get_users()?
+            .iter()
+            .any(|(other_id, other)| *other_id != current.user_id && other.role == Role::Admin)
+    {
+        Some("You are the only admin. Appoint another admin before deleting your account.")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        let mut ctx = session::user_context(&**db, &current)?;
+        ctx.insert("csrf_token", &csrf.0);
+        ctx.insert("has_password", &has_password);
+        ctx.insert("error", &Some(error));
+        return render(
+            &tera,
+            "account.html",
+            &ctx,
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    db.remove_user(current.user_id)?;
+    id.forget();
+    log::info!("delete account {}", current.user_id);
+    render_message(
+        &tera,
+        "Account deleted",
+        "Your account and all of its data were deleted.",
+        HttpResponse::Ok(),
+    )
+}
@@ -0,0 +1,313 @@
+//! Throttling of login attempts.
+//!
+//! Failed logins are counted per username and per IP address. After a few free attempts every
+//! further attempt has to wait exponentially longer, and after [`LOCKOUT_THRESHOLD`] failures
+//! the username or address is locked for [`LOCKOUT_DURATION`]. Counters expire
+//! [`ATTEMPTS_TTL`] seconds after the last failure.
+//!
+//! Requests for password reset links are throttled the same way per email address, see
+//! [`allow_password_reset`].
+//!
+//! [`check`] counts every attempt it allows as a failure right away, in the same atomic update,
+//! so that concurrent requests can't all get through before the first failure is recorded.
+//! [`record_success`] and [`cancel`] take it back.
+
+use crate::{config::Config, database::*, model::*};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::warn;
+use std::{cell::Cell, net::IpAddr};
+
+/// Failures that don't cause any delay
+const FREE_ATTEMPTS: u32 = 3;
+/// Delay in seconds after the first failure that isn't free, doubled for every further failure
+const BASE_DELAY: u64 = 1;
+const MAX_DELAY: u64 = 15 * 60;
+pub const LOCKOUT_THRESHOLD: u32 = 10;
+pub const LOCKOUT_DURATION: u64 = 60 * 60;
+pub const ATTEMPTS_TTL: u64 = 24 * 60 * 60;
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+fn reset_key(email: &str) -> String {
+    format!("reset:{}", email.to_lowercase())
+}
+
+fn is_expired(attempts: &LoginAttempts, now: u64) -> bool {
+    attempts.last_failure + ATTEMPTS_TTL <= now && attempts.locked_until <= now
+}
+
+/// Seconds until the next attempt is allowed, `None` if it is allowed right away
+pub fn retry_after(attempts: &LoginAttempts, now: u64) -> Option<u64> {
+    if attempts.locked_until > now {
+        return Some(attempts.locked_until - now);
+    }
+    if attempts.failures < FREE_ATTEMPTS || is_expired(attempts, now) {
+        return None;
+    }
+    let exponent = (attempts.failures - FREE_ATTEMPTS).min(32);
+    let delay = BASE_DELAY.saturating_mul(1 << exponent).min(MAX_DELAY);
+    let allowed_at = attempts.last_failure + delay;
+    if allowed_at > now {
+        Some(allowed_at - now)
+    } else {
+        None
+    }
+}
+
+/// Returns the updated counter after a failed attempt
+pub fn add_failure(attempts: Option<LoginAttempts>, now: u64) -> LoginAttempts {
+    let mut attempts = attempts
+        .filter(|attempts| !is_expired(attempts, now))
+        .unwrap_or_default();
+    attempts.failures += 1;
+    attempts.last_failure = now;
+    if attempts.failures.is_multiple_of(LOCKOUT_THRESHOLD) {
+        attempts.locked_until = now + LOCKOUT_DURATION;
+    }
+    attempts
+}
+
+/// Takes back a failure that [`check`] counted in advance, including the lockout it caused
+pub fn remove_failure(attempts: Option<LoginAttempts>) -> Option<LoginAttempts> {
+    let mut attempts = attempts?;
+    if attempts.failures.is_multiple_of(LOCKOUT_THRESHOLD) {
+        attempts.locked_until = 0;
+    }
+    attempts.failures = attempts.failures.saturating_sub(1);
+    if attempts.failures == 0 {
+        return None;
+    }
+    Some(attempts)
+}
+
+/// Address of the client that attempts to log in. Behind one of the configured
+/// `trusted_proxies` it is the last address in `X-Forwarded-For` that isn't a trusted proxy,
+/// the ones before could be made up by the client.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let peer = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return "unknown".to_owned(),
+    };
+    let trusted_proxies = match req.app_data::<web::Data<Config>>() {
+        Some(config) => &config.trusted_proxies,
+        None => return peer.to_string(),
+    };
+    if !trusted_proxies.contains(&peer) {
+        return peer.to_string();
+    }
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted_proxies.contains(ip))
+        .or_else(|| forwarded.first())
+        .unwrap_or(&peer)
+        .to_string()
+}
+
+pub fn too_many_attempts(retry_after: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header("retry-after", retry_after.to_string())
+        .body(format!(
+            "Too many failed login attempts, try again in {} seconds",
+            retry_after
+        ))
+}
+
+/// Returns the number of seconds the client has to wait before trying to log in as `username`
+/// from `ip`, or `None` if the attempt is allowed. Allowed attempts are counted as failures.
+pub fn check<D: DbExt>(db: &D, username: &str, ip: &str) -> DbResult<Option<u64>> {
+    let now = unix_time();
+    let keys = [user_key(username), ip_key(ip)];
+    for (i, key) in keys.iter().enumerate() {
+        let wait = Cell::new(None);
+        db.update_login_attempts(key, |attempts| {
+            let attempts = attempts.filter(|attempts| !is_expired(attempts, now));
+            wait.set(attempts.as_ref().and_then(|a| retry_after(a, now)));
+            match wait.get() {
+                Some(_) => attempts,
+                None => Some(add_failure(attempts, now)),
+            }
+        })?;
+        if let Some(wait) = wait.get() {
+            for key in &keys[..i] {
+                db.update_login_attempts(key, remove_failure)?;
+            }
+            return Ok(Some(wait));
+        }
+    }
+    Ok(None)
+}
+
+/// Counts a request for a password reset link sent to `email`, returns false if there were too
+/// many recently
+pub fn allow_password_reset<D: DbExt>(db: &D, email: &str) -> DbResult<bool> {
+    let now = unix_time();
+    let allowed = Cell::new(false);
+    db.update_login_attempts(&reset_key(email), |attempts| {
+        let attempts = attempts.filter(|attempts| !is_expired(attempts, now));
+        allowed.set(
+            attempts
+                .as_ref()
+                .and_then(|a| retry_after(a, now))
+                .is_none(),
+        );
+        if allowed.get() {
+            Some(add_failure(attempts, now))
+        } else {
+            attempts
+        }
+    })?;
+    Ok(allowed.get())
+}
+
+/// Logs a failed attempt, [`check`] already counted it
+pub fn record_failure<D: DbExt>(db: &D, username: &str, ip: &str) -> DbResult<()> {
+    let now = unix_time();
+    for key in &[user_key(username), ip_key(ip)] {
+        let attempts = match db.get_login_attempts(key)? {
+            Some(attempts) => attempts,
+            None => continue,
+        };
+        if attempts.locked_until > now {
+            warn!(
+                target: "audit",
+                "Locked {} for {} seconds after {} failed logins (username {:?} from {})",
+                key, LOCKOUT_DURATION, attempts.failures, username, ip
+            );
+        } else if attempts.failures >= FREE_ATTEMPTS {
+            warn!(
+                target: "audit",
+                "{} failed logins for {} (username {:?} from {})",
+                attempts.failures, key, username, ip
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Resets the counter of `username` after a successful login. The IP address only gets back
+/// the attempt, otherwise an attacker with one valid account could reset its counter.
+pub fn record_success<D: DbExt>(db: &D, username: &str, ip: &str) -> DbResult<()> {
+    unlock(db, username)?;
+    db.update_login_attempts(&ip_key(ip), remove_failure)?;
+    Ok(())
+}
+
+/// Takes back the attempt when it neither failed nor succeeded yet, e.g. when the password was
+/// right but the second factor is still missing
+pub fn cancel<D: DbExt>(db: &D, username: &str, ip: &str) -> DbResult<()> {
+    for key in &[user_key(username), ip_key(ip)] {
+        db.update_login_attempts(key, remove_failure)?;
+    }
+    Ok(())
+}
+
+/// Resets the counter of `username`, e.g. after the password was reset
+pub fn unlock<D: DbExt>(db: &D, username: &str) -> DbResult<()> {
+    db.remove_login_attempts(&user_key(username))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+
+    #[test]
+    fn backoff() {
+        let mut attempts = None;
+        for _ in 0..FREE_ATTEMPTS - 1 {
+            attempts = Some(add_failure(attempts, 100));
+        }
+        assert_eq!(retry_after(attempts.as_ref().unwrap(), 100), None);
+        let attempts = add_failure(attempts, 100);
+        assert_eq!(retry_after(&attempts, 100), Some(1));
+        let attempts = add_failure(Some(attempts), 100);
+        assert_eq!(retry_after(&attempts, 100), Some(2));
+        assert_eq!(retry_after(&attempts, 102), None);
+        let attempts = add_failure(Some(attempts), 102);
+        assert_eq!(retry_after(&attempts, 103), Some(3));
+        // counters expire
+        assert_eq!(add_failure(Some(attempts), 102 + ATTEMPTS_TTL).failures, 1);
+    }
+
+    #[test]
+    fn lockout() {
+        let mut attempts = LoginAttempts::default();
+        for now in 0..LOCKOUT_THRESHOLD as u64 {
+            attempts = add_failure(Some(attempts), now * MAX_DELAY);
+        }
+        let now = (LOCKOUT_THRESHOLD as u64 - 1) * MAX_DELAY;
+        assert_eq!(attempts.locked_until, now + LOCKOUT_DURATION);
+        assert_eq!(retry_after(&attempts, now + 1), Some(LOCKOUT_DURATION - 1));
+        assert_eq!(retry_after(&attempts, now + LOCKOUT_DURATION), None);
+        // Taking back the failure that caused the lockout lifts it
+        let attempts = remove_failure(Some(attempts)).unwrap();
+        assert_eq!(attempts.failures, LOCKOUT_THRESHOLD - 1);
+        assert_eq!(attempts.locked_until, 0);
+        assert_eq!(remove_failure(Some(add_failure(None, 0))), None);
+    }
+
+    #[test]
+    fn check_counts_attempts() {
+        let db = MemoryDb::new();
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(check(&db, "Alice", "1.2.3.4").unwrap(), None);
+        }
+        assert!(check(&db, "alice", "5.6.7.8").unwrap().is_some());
+        // The rejected attempt isn't counted for the other address
+        assert!(db.get_login_attempts("ip:5.6.7.8").unwrap().is_none());
+        record_success(&db, "alice", "1.2.3.4").unwrap();
+        assert_eq!(
+            db.get_login_attempts("ip:1.2.3.4")
+                .unwrap()
+                .unwrap()
+                .failures,
+            FREE_ATTEMPTS - 1
+        );
+        assert_eq!(check(&db, "alice", "5.6.7.8").unwrap(), None);
+        cancel(&db, "alice", "5.6.7.8").unwrap();
+        assert!(db.get_login_attempts("user:alice").unwrap().is_none());
+        assert!(db.get_login_attempts("ip:5.6.7.8").unwrap().is_none());
+    }
+
+    #[test]
+    fn password_reset_requests() {
+        let db = MemoryDb::new();
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(allow_password_reset(&db, "alice@example.com").unwrap());
+        }
+        assert!(!allow_password_reset(&db, "Alice@example.com").unwrap());
+        assert!(allow_password_reset(&db, "bob@example.com").unwrap());
+    }
+
+    #[test]
+    fn forwarded_client_ip() {
+        let proxy = "10.0.0.1:1234".parse().unwrap();
+        let request = |config: Config| {
+            actix_web::test::TestRequest::default()
+                .peer_addr(proxy)
+                .header("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2")
+                .data(config)
+                .to_http_request()
+        };
+        assert_eq!(client_ip(&request(Config::default())), "10.0.0.1");
+        let config = Config {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(client_ip(&request(config)), "1.2.3.4");
+    }
+}
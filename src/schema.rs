@@ -0,0 +1,669 @@
+//! Versioned storage format for records and migrations between schema versions.
+//!
+//! Every record is stored as a little endian `u32` version followed by the bincode encoding of
+//! the record. The version of the whole database is stored in the default tree under
+//! [`SCHEMA_VERSION`] and upgraded on startup by [`migrate`].
+
+use crate::model::*;
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::TryInto;
+
+const SCHEMA_VERSION: &[u8] = b"schema_version";
+
+pub trait Record: Serialize + DeserializeOwned {
+    /// Version of the record format, has to be bumped whenever the struct changes
+    const VERSION: u32;
+}
+
+impl Record for User {
+    const VERSION: u32 = 7;
+}
+
+impl Record for Movie {
+    const VERSION: u32 = 1;
+}
+
+impl Record for Group {
+    const VERSION: u32 = 1;
+}
+
+impl Record for Session {
+    const VERSION: u32 = 1;
+}
+
+impl Record for LoginAttempts {
+    const VERSION: u32 = 1;
+}
+
+impl Record for Token {
+    const VERSION: u32 = 1;
+}
+
+impl Record for OidcLogin {
+    const VERSION: u32 = 1;
+}
+
+impl Record for ApiToken {
+    const VERSION: u32 = 1;
+}
+
+impl Record for OutboxEntry {
+    const VERSION: u32 = 2;
+}
+
+/// Still the first version: `FriendRequest` was appended to [`Event`], which doesn't change how
+/// the other variants are stored, and `PasswordReset` is never shown on the site
+impl Record for Notification {
+    const VERSION: u32 = 1;
+}
+
+pub fn encode<T: Record>(record: &T) -> Vec<u8> {
+    let mut data = T::VERSION.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut data, record).unwrap();
+    data
+}
+
+pub fn decode<T: Record>(data: &[u8]) -> bincode::Result<T> {
+    let version = data
+        .get(0..4)
+        .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+        .ok_or_else(|| {
+            Box::new(bincode::ErrorKind::Custom(
+                "Missing record version".to_owned(),
+            ))
+        })?;
+    if version != T::VERSION {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "Expected record version {}, found {}",
+            T::VERSION,
+            version
+        ))));
+    }
+    bincode::deserialize(&data[4..])
+}
+
+/// Record formats of previous schema versions
+mod v0 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Movie {
+        pub name: String,
+    }
+}
+
+mod v1 {
+    use crate::model::FriendData;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub enum Event {
+        Recommendation { from: u64, movie_id: u64 },
+        FriendRequest { from: u64 },
+        PasswordReset { token: String },
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct OutboxEntry {
+        pub user_id: u64,
+        pub event: Event,
+        pub created: u64,
+        pub attempts: u32,
+        pub next_attempt: u64,
+    }
+
+    impl super::Record for OutboxEntry {
+        const VERSION: u32 = 1;
+    }
+}
+
+mod v2 {
+    use crate::model::FriendData;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+        pub email: Option<String>,
+        pub email_verified: bool,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 2;
+    }
+}
+
+mod v3 {
+    use crate::model::{FriendData, NotificationPreference};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+        pub email: Option<String>,
+        pub email_verified: bool,
+        pub notifications: NotificationPreference,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 3;
+    }
+}
+
+mod v4 {
+    use crate::model::{ExternalIdentity, FriendData, NotificationPreference};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+        pub email: Option<String>,
+        pub email_verified: bool,
+        pub notifications: NotificationPreference,
+        pub identities: Vec<ExternalIdentity>,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 4;
+    }
+}
+
+mod v5 {
+    use crate::model::{ExternalIdentity, FriendData, NotificationPreference, TwoFactor};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+        pub email: Option<String>,
+        pub email_verified: bool,
+        pub notifications: NotificationPreference,
+        pub identities: Vec<ExternalIdentity>,
+        pub two_factor: Option<TwoFactor>,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 5;
+    }
+}
+
+mod v6 {
+    use crate::model::{ExternalIdentity, FriendData, NotificationPreference, Role, TwoFactor};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct User {
+        pub username: String,
+        pub password_hash: String,
+        pub friends: HashMap<u64, FriendData>,
+        pub email: Option<String>,
+        pub email_verified: bool,
+        pub notifications: NotificationPreference,
+        pub identities: Vec<ExternalIdentity>,
+        pub two_factor: Option<TwoFactor>,
+        pub role: Role,
+        pub disabled: bool,
+    }
+
+    impl super::Record for User {
+        const VERSION: u32 = 6;
+    }
+}
+
+struct Migration {
+    /// Schema version after this migration ran
+    version: u32,
+    description: &'static str,
+    /// Has to be idempotent, since the database could be closed before the new version is
+    /// recorded.
+    run: fn(&sled::Db) -> sled::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "wrap users and movies in versioned envelopes",
+        run: migrate_v1,
+    },
+    Migration {
+        version: 2,
+        description: "make usernames case insensitive",
+        run: migrate_v2,
+    },
+    Migration {
+        version: 3,
+        description: "add email addresses to users",
+        run: migrate_v3,
+    },
+    Migration {
+        version: 4,
+        description: "add notification preferences to users",
+        run: migrate_v4,
+    },
+    Migration {
+        version: 5,
+        description: "link users to external identities",
+        run: migrate_v5,
+    },
+    Migration {
+        version: 6,
+        description: "add two-factor authentication to users",
+        run: migrate_v6,
+    },
+    Migration {
+        version: 7,
+        description: "add roles to users",
+        run: migrate_v7,
+    },
+    Migration {
+        version: 8,
+        description: "add display names to users and index names for search",
+        run: migrate_v8,
+    },
+    Migration {
+        version: 9,
+        description: "remove password reset tokens from the outbox",
+        run: migrate_v9,
+    },
+];
+
+pub fn current_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn schema_version(db: &sled::Db) -> sled::Result<u32> {
+    match db.get(SCHEMA_VERSION)? {
+        Some(v) => Ok(u32::from_le_bytes(v.as_ref().try_into().map_err(|_| {
+            sled::Error::Unsupported(format!(
+                "Invalid value of {}",
+                String::from_utf8_lossy(SCHEMA_VERSION)
+            ))
+        })?)),
+        None => Ok(0),
+    }
+}
+
+/// Runs all migrations that have not been applied to `db` yet.
+pub fn migrate(db: &sled::Db) -> sled::Result<()> {
+    let version = schema_version(db)?;
+    if version > current_version() {
+        return Err(sled::Error::Unsupported(format!(
+            "Database schema version {} is newer than supported version {}",
+            version,
+            current_version()
+        )));
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        info!(
+            "Migrating database to schema version {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)(db)?;
+        db.insert(SCHEMA_VERSION, &migration.version.to_le_bytes())?;
+        db.flush()?;
+    }
+    Ok(())
+}
+
+/// Rewrites every record in `tree` that can't be decoded as `T` using `upgrade`.
+fn upgrade_tree<T: Record, F: Fn(&[u8]) -> Option<T>>(
+    tree: &sled::Tree,
+    upgrade: F,
+) -> sled::Result<()> {
+    let mut batch = sled::Batch::default();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        if decode::<T>(&value).is_ok() {
+            continue;
+        }
+        let record = upgrade(&value)
+            .ok_or_else(|| sled::Error::Unsupported(format!("Can't migrate record {:?}", key)))?;
+        batch.insert(key, encode(&record));
+    }
+    tree.apply_batch(batch)
+}
+
+fn migrate_v1(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v1::User, _>(&db.open_tree(b"users")?, |data| {
+        bincode::deserialize(data).ok()
+    })?;
+    upgrade_tree::<Movie, _>(&db.open_tree(b"movies")?, |data| {
+        let movie: v0::Movie = bincode::deserialize(data).ok()?;
+        Some(Movie {
+            name: movie.name,
+            ..Default::default()
+        })
+    })
+}
+
+/// Rebuilds `users_username` with lowercased keys. If two usernames only differ in case, the
+/// older user keeps the name and the newer ones are renamed by appending their id.
+fn migrate_v2(db: &sled::Db) -> sled::Result<()> {
+    let users = db.open_tree(b"users")?;
+    let users_username = db.open_tree(b"users_username")?;
+    let mut entries = users_username
+        .iter()
+        .map(|entry| {
+            let (name, id) = entry?;
+            let id = id
+                .as_ref()
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| sled::Error::Unsupported(format!("Invalid user id for {:?}", name)))?;
+            Ok((String::from_utf8_lossy(&name).into_owned(), id))
+        })
+        .collect::<sled::Result<Vec<_>>>()?;
+    entries.sort_by_key(|(_, id)| *id);
+    let names = entries
+        .iter()
+        .map(|(name, _)| name.to_lowercase())
+        .collect::<std::collections::HashSet<_>>();
+    let mut batch = sled::Batch::default();
+    let mut renamed = sled::Batch::default();
+    let mut taken = std::collections::HashSet::new();
+    for (name, _) in &entries {
+        batch.remove(name.as_bytes());
+    }
+    for (name, id) in entries {
+        let key = name.to_lowercase();
+        if taken.insert(key.clone()) {
+            batch.insert(key.as_bytes(), &id.to_le_bytes());
+            continue;
+        }
+        // Neither the name of another user nor one that was given out before
+        let mut new_name = format!("{}_{}", name, id);
+        while names.contains(&new_name.to_lowercase()) || taken.contains(&new_name.to_lowercase()) {
+            new_name.push('_');
+        }
+        log::warn!(
+            "Renaming user {} from {:?} to {:?}, since the name conflicts with an older user",
+            id,
+            name,
+            new_name
+        );
+        if let Some(data) = users.get(id.to_le_bytes())? {
+            let mut user: v1::User = decode(&data).map_err(|_| {
+                sled::Error::Unsupported(format!("Can't migrate record of user {}", id))
+            })?;
+            user.username = new_name.clone();
+            renamed.insert(&id.to_le_bytes(), encode(&user));
+        }
+        let key = new_name.to_lowercase();
+        batch.insert(key.as_bytes(), &id.to_le_bytes());
+        taken.insert(key);
+    }
+    // Users first, so that running this again after a crash renames them the same way
+    users.apply_batch(renamed)?;
+    users_username.apply_batch(batch)
+}
+
+fn migrate_v3(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v2::User, _>(&db.open_tree(b"users")?, |data| {
+        let user: v1::User = decode(data).ok()?;
+        Some(v2::User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: None,
+            email_verified: false,
+        })
+    })
+}
+
+fn migrate_v4(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v3::User, _>(&db.open_tree(b"users")?, |data| {
+        let user: v2::User = decode(data).ok()?;
+        Some(v3::User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: user.email,
+            email_verified: user.email_verified,
+            notifications: NotificationPreference::Instant,
+        })
+    })
+}
+
+fn migrate_v5(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v4::User, _>(&db.open_tree(b"users")?, |data| {
+        let user: v3::User = decode(data).ok()?;
+        Some(v4::User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: user.email,
+            email_verified: user.email_verified,
+            notifications: user.notifications,
+            identities: Vec::new(),
+        })
+    })
+}
+
+fn migrate_v6(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v5::User, _>(&db.open_tree(b"users")?, |data| {
+        let user: v4::User = decode(data).ok()?;
+        Some(v5::User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: user.email,
+            email_verified: user.email_verified,
+            notifications: user.notifications,
+            identities: user.identities,
+            two_factor: None,
+        })
+    })
+}
+
+/// Nobody is an admin afterwards, admins are appointed with `nextflix grant-admin`
+fn migrate_v7(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<v6::User, _>(&db.open_tree(b"users")?, |data| {
+        let user: v5::User = decode(data).ok()?;
+        Some(v6::User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: user.email,
+            email_verified: user.email_verified,
+            notifications: user.notifications,
+            identities: user.identities,
+            two_factor: user.two_factor,
+            role: Role::User,
+            disabled: false,
+        })
+    })
+}
+
+/// The search index is rebuilt from scratch, since entries can't be inserted twice
+fn migrate_v8(db: &sled::Db) -> sled::Result<()> {
+    let users = db.open_tree(b"users")?;
+    upgrade_tree::<User, _>(&users, |data| {
+        let user: v6::User = decode(data).ok()?;
+        Some(User {
+            username: user.username,
+            password_hash: user.password_hash,
+            friends: user.friends,
+            email: user.email,
+            email_verified: user.email_verified,
+            notifications: user.notifications,
+            identities: user.identities,
+            two_factor: user.two_factor,
+            role: user.role,
+            disabled: user.disabled,
+            display_name: None,
+        })
+    })?;
+    crate::database::rebuild_user_index(db)
+}
+
+/// Queued resets stay queued, the worker creates a new token when it sends them
+fn migrate_v9(db: &sled::Db) -> sled::Result<()> {
+    upgrade_tree::<OutboxEntry, _>(&db.open_tree(b"outbox")?, |data| {
+        let entry: v1::OutboxEntry = decode(data).ok()?;
+        Some(OutboxEntry {
+            user_id: entry.user_id,
+            event: match entry.event {
+                v1::Event::Recommendation { from, movie_id } => {
+                    Event::Recommendation { from, movie_id }
+                }
+                v1::Event::FriendRequest { from } => Event::FriendRequest { from },
+                v1::Event::PasswordReset { .. } => Event::PasswordReset,
+            },
+            created: entry.created,
+            attempts: entry.attempts,
+            next_attempt: entry.next_attempt,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    #[test]
+    fn case_insensitive_usernames() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(SCHEMA_VERSION, &1u32.to_le_bytes()).unwrap();
+        let users = db.open_tree(b"users").unwrap();
+        let users_username = db.open_tree(b"users_username").unwrap();
+        for (id, name) in &[
+            (1u64, "Bar"),
+            (2, "Foo"),
+            (3, "foo"),
+            (4, "FOO"),
+            (5, "foo_4"),
+        ] {
+            let user = v1::User {
+                username: (*name).to_owned(),
+                password_hash: "hash".to_owned(),
+                friends: HashMap::new(),
+            };
+            users.insert(id.to_le_bytes(), encode(&user)).unwrap();
+            users_username.insert(name, &id.to_le_bytes()).unwrap();
+        }
+        migrate(&db).unwrap();
+        let keys = users_username
+            .iter()
+            .map(|e| {
+                let (k, v) = e.unwrap();
+                (String::from_utf8(k.to_vec()).unwrap(), v[0])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                ("bar".to_owned(), 1),
+                ("foo".to_owned(), 2),
+                ("foo_3".to_owned(), 3),
+                ("foo_4".to_owned(), 5),
+                ("foo_4_".to_owned(), 4),
+            ]
+        );
+        let username = |id: u64| {
+            let data = users.get(id.to_le_bytes()).unwrap().unwrap();
+            decode::<User>(&data).unwrap().username
+        };
+        assert_eq!(username(2), "Foo");
+        assert_eq!(username(3), "foo_3");
+        assert_eq!(username(4), "FOO_4_");
+    }
+
+    #[test]
+    fn invalid_schema_version() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(SCHEMA_VERSION, &[1]).unwrap();
+        assert!(matches!(migrate(&db), Err(sled::Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn migrate_v0() {
+        #[derive(Serialize)]
+        struct MovieV0 {
+            name: String,
+        }
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let user = v1::User {
+            username: "foo".to_owned(),
+            password_hash: "hash".to_owned(),
+            friends: HashMap::new(),
+        };
+        db.open_tree(b"users")
+            .unwrap()
+            .insert(b"u", bincode::serialize(&user).unwrap())
+            .unwrap();
+        let movie = MovieV0 {
+            name: "Pulp Fiction".to_owned(),
+        };
+        db.open_tree(b"movies")
+            .unwrap()
+            .insert(b"m", bincode::serialize(&movie).unwrap())
+            .unwrap();
+
+        migrate(&db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), current_version());
+        let data = db.open_tree(b"users").unwrap().get(b"u").unwrap().unwrap();
+        assert_eq!(decode::<User>(&data).unwrap().username, "foo");
+        // Users that existed before the index can be found
+        let found = crate::fts_tree::FTSExt::open_fts(&db, b"users_name")
+            .unwrap()
+            .query("foo")
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        let data = db.open_tree(b"movies").unwrap().get(b"m").unwrap().unwrap();
+        assert_eq!(decode::<Movie>(&data).unwrap().name, "Pulp Fiction");
+
+        // Reset tokens were stored in the outbox before version 9
+        db.insert(SCHEMA_VERSION, &8u32.to_le_bytes()).unwrap();
+        let outbox = db.open_tree(b"outbox").unwrap();
+        let entry = v1::OutboxEntry {
+            user_id: 1,
+            event: v1::Event::PasswordReset {
+                token: "secret".to_owned(),
+            },
+            created: 2,
+            attempts: 0,
+            next_attempt: 2,
+        };
+        outbox.insert(b"o", encode(&entry)).unwrap();
+        migrate(&db).unwrap();
+        let data = outbox.get(b"o").unwrap().unwrap();
+        assert_eq!(
+            decode::<OutboxEntry>(&data).unwrap().event,
+            Event::PasswordReset
+        );
+
+        // Running the migrations again is a no-op
+        let cs = db.checksum().unwrap();
+        migrate(&db).unwrap();
+        assert_eq!(cs, db.checksum().unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+//! Searching movies and users.
+
+use crate::{csrf::CsrfToken, database::*, log_error, render, session, watchlist, Tera};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+const RESULTS_PER_PAGE: usize = 20;
+const FRIEND_SUGGESTIONS: usize = 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    #[serde(default)]
+    pub page: usize,
+    /// Set after recommending a movie from the results
+    pub recommended: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MovieResult {
+    id: u64,
+    name: String,
+    year: Option<u16>,
+    genres: Vec<String>,
+    score: f32,
+}
+
+pub async fn movies<D: DbExt>(
+    query: web::Query<SearchQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let q = query.q.trim();
+    let results = if q.is_empty() {
+        Vec::new()
+    } else {
+        db.search_movie(q)?
+    };
+    let total = results.len();
+    let results = results
+        .into_iter()
+        .skip(query.page * RESULTS_PER_PAGE)
+        .take(RESULTS_PER_PAGE)
+        .map(|(movie_id, movie, score)| MovieResult {
+            id: movie_id,
+            name: movie.name,
+            year: movie.year,
+            genres: movie.genres,
+            score,
+        })
+        .collect::<Vec<_>>();
+    // Only users that added the current user as a friend accept recommendations from them
+    let friends = watchlist::added_by(&**db, current.user_id)?
+        .into_iter()
+        .map(|(_, friend)| friend.username)
+        .collect::<Vec<_>>();
+    let groups = db
+        .get_groups_by_user(current.user_id)?
+        .into_iter()
+        .map(|(group_id, group)| (group_id, group.name))
+        .collect::<Vec<_>>();
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("q", q);
+    ctx.insert("page", &query.page);
+    ctx.insert("total", &total);
+    ctx.insert("first_rank", &(query.page * RESULTS_PER_PAGE + 1));
+    ctx.insert(
+        "has_next_page",
+        &(total > (query.page + 1) * RESULTS_PER_PAGE),
+    );
+    ctx.insert("results", &results);
+    ctx.insert("friends", &friends);
+    ctx.insert("groups", &groups);
+    ctx.insert("recommended", &query.recommended.is_some());
+    render(&tera, "movies_search.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecommendParams {
+    /// Username of the friend
+    pub friend: String,
+    pub movie_id: u64,
+    /// Search the recommendation was made from, to get back to the same results
+    pub q: String,
+    pub page: usize,
+}
+
+/// Adds a search result to the watchlist of a friend
+pub async fn recommend_post<D: DbExt>(
+    params: web::Form<RecommendParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let (friend_id, _) = db
+        .get_user_by_username(&params.friend)?
+        .ok_or(DbError::NotFound)?;
+    watchlist::recommend(&**db, current.user_id, friend_id, params.movie_id)?;
+    let query = serde_urlencoded::to_string([
+        ("q", params.q.as_str()),
+        ("page", &params.page.to_string()),
+        ("recommended", ""),
+    ])
+    .map_err(|err| log_error(err, "Serialization error"))?;
+    Ok(HttpResponse::Found()
+        .header("location", format!("/movies/search?{}", query))
+        .finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserSearchQuery {
+    #[serde(default)]
+    pub q: String,
+    #[serde(default)]
+    pub page: usize,
+}
+
+#[derive(Serialize)]
+struct UserResult {
+    username: String,
+    display_name: Option<String>,
+    is_friend: bool,
+    /// Number of the current user's friends that have this user as a friend
+    mutual_friends: usize,
+}
+
+/// Matches each word of `q` as a prefix, so results show up while the name is still incomplete
+fn prefix_query(q: &str) -> String {
+    crate::fts_tree::tokens_iter(q)
+        .map(|token| format!("{}*", token.trim_end_matches('*')))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Finds users by username or display name and suggests friends of friends
+pub async fn users<D: DbExt>(
+    query: web::Query<UserSearchQuery>,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let q = query.q.trim();
+    let results = if q.is_empty() {
+        Vec::new()
+    } else {
+        db.search_users(&prefix_query(q))?
+            .into_iter()
+            .filter(|(user_id, user, _)| *user_id != current.user_id && !user.disabled)
+            .collect()
+    };
+    let total = results.len();
+    let mut friends = Vec::new();
+    for friend_id in current.user.friends.keys() {
+        friends.extend(db.get_user(*friend_id)?);
+    }
+    let results = results
+        .into_iter()
+        .skip(query.page * RESULTS_PER_PAGE)
+        .take(RESULTS_PER_PAGE)
+        .map(|(user_id, user, _)| UserResult {
+            is_friend: current.user.friends.contains_key(&user_id),
+            mutual_friends: friends
+                .iter()
+                .filter(|friend| friend.friends.contains_key(&user_id))
+                .count(),
+            username: user.username,
+            display_name: user.display_name,
+        })
+        .collect::<Vec<_>>();
+    let suggestions =
+        watchlist::suggest_friends(&**db, current.user_id, &current.user, FRIEND_SUGGESTIONS)?
+            .into_iter()
+            .map(|(_, user, mutual_friends)| UserResult {
+                username: user.username,
+                display_name: user.display_name,
+                is_friend: false,
+                mutual_friends,
+            })
+            .collect::<Vec<_>>();
+    let mut ctx = session::user_context(&**db, &current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("q", q);
+    ctx.insert("page", &query.page);
+    ctx.insert("total", &total);
+    ctx.insert(
+        "has_next_page",
+        &(total > (query.page + 1) * RESULTS_PER_PAGE),
+    );
+    ctx.insert("results", &results);
+    ctx.insert("suggestions", &suggestions);
+    render(&tera, "users_search.html", &ctx, HttpResponse::Ok())
+}
@@ -0,0 +1,213 @@
+//! Server side sessions.
+//!
+//! The identity cookie only contains a random session id, the session itself is stored in the
+//! `sessions` tree. This allows listing and revoking sessions and keeps sessions valid when a
+//! user is renamed.
+
+use crate::{csrf::CsrfToken, database::*, model::*, render, Tera};
+use actix_identity::Identity;
+use actix_web::{error, http::header, web, HttpRequest, HttpResponse};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+const SESSION_ID_LENGTH: usize = 32;
+
+/// `last_seen` is only updated if it is older than this many seconds
+const TOUCH_INTERVAL: u64 = 60;
+
+/// Sessions end this many seconds after the login, even if they are in use
+pub const SESSION_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// Sessions end if they weren't used for this many seconds
+pub const IDLE_TTL: u64 = 7 * 24 * 60 * 60;
+
+pub struct CurrentUser {
+    pub session_id: String,
+    pub user_id: u64,
+    pub user: User,
+}
+
+fn is_expired(session: &Session, now: u64) -> bool {
+    session.created + SESSION_TTL <= now || session.last_seen + IDLE_TTL <= now
+}
+
+/// Removes all sessions that have expired by `now`
+pub fn remove_expired<D: DbExt>(db: &D, now: u64) -> DbResult<()> {
+    db.remove_expired_sessions(
+        now.saturating_sub(SESSION_TTL),
+        now.saturating_sub(IDLE_TTL),
+    )
+}
+
+fn generate_session_id() -> String {
+    let mut id = [0u8; SESSION_ID_LENGTH];
+    rand::thread_rng().fill_bytes(&mut id);
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+/// Creates a new session for `user_id` and remembers it in the identity cookie
+pub fn start_session<D: DbExt>(
+    id: &Identity,
+    db: &D,
+    user_id: u64,
+    req: &HttpRequest,
+) -> DbResult<()> {
+    let session_id = generate_session_id();
+    let now = unix_time();
+    db.add_session(
+        &session_id,
+        &Session {
+            user_id,
+            created: now,
+            last_seen: now,
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(str::to_owned),
+        },
+    )?;
+    id.remember(session_id);
+    Ok(())
+}
+
+/// Returns the logged in user. Forgets the identity if its session was revoked or expired.
+pub fn current_user<D: DbExt>(id: &Identity, db: &D) -> DbResult<Option<CurrentUser>> {
+    let session_id = match id.identity() {
+        Some(session_id) => session_id,
+        None => return Ok(None),
+    };
+    let now = unix_time();
+    let session = match db.get_session(&session_id)? {
+        Some(session) if !is_expired(&session, now) => session,
+        Some(_) => {
+            db.remove_session(&session_id)?;
+            id.forget();
+            return Ok(None);
+        }
+        None => {
+            id.forget();
+            return Ok(None);
+        }
+    };
+    let user = match db.get_user(session.user_id)? {
+        Some(user) if !user.disabled => user,
+        // Users can be disabled while they are logged in
+        _ => {
+            db.remove_session(&session_id)?;
+            id.forget();
+            return Ok(None);
+        }
+    };
+    if now >= session.last_seen + TOUCH_INTERVAL {
+        db.touch_session(&session_id, now)?;
+    }
+    Ok(Some(CurrentUser {
+        session_id,
+        user_id: session.user_id,
+        user,
+    }))
+}
+
+/// Like [`current_user`], but redirects to the login page if nobody is logged in
+pub fn require_user<D: DbExt>(id: &Identity, db: &D) -> actix_web::Result<CurrentUser> {
+    current_user(id, db)?.ok_or_else(|| {
+        error::InternalError::from_response(
+            "Not logged in",
+            HttpResponse::Found().header("location", "/login").finish(),
+        )
+        .into()
+    })
+}
+
+/// Template context for pages of a logged in user, with everything `base.html` needs
+pub fn user_context<D: DbExt>(db: &D, current: &CurrentUser) -> DbResult<tera::Context> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("user", &current.user);
+    ctx.insert(
+        "unread_notifications",
+        &db.count_unread_notifications(current.user_id)?,
+    );
+    Ok(ctx)
+}
+
+/// Removes the current session and forgets the identity
+pub fn end_session<D: DbExt>(id: &Identity, db: &D) -> DbResult<()> {
+    if let Some(session_id) = id.identity() {
+        db.remove_session(&session_id)?;
+    }
+    id.forget();
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    id: String,
+    current: bool,
+    #[serde(flatten)]
+    session: Session,
+}
+
+pub async fn sessions<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_user(&id, &**db)?;
+    let mut sessions = db
+        .get_sessions_by_user(current.user_id)?
+        .into_iter()
+        .map(|(session_id, session)| SessionInfo {
+            current: session_id == current.session_id,
+            id: session_id,
+            session,
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.session.last_seen));
+    let mut ctx = user_context(&**db, &current)?;
+    ctx.insert("sessions", &sessions);
+    ctx.insert("csrf_token", &csrf.0);
+    render(&tera, "sessions.html", &ctx, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeParams {
+    session_id: String,
+}
+
+pub async fn revoke_session<D: DbExt>(
+    params: web::Form<RevokeParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_user(&id, &**db)?;
+    // Only allow revoking own sessions
+    match db.get_session(&params.session_id)? {
+        Some(session) if session.user_id == current.user_id => {
+            db.remove_session(&params.session_id)?
+        }
+        _ => return Err(DbError::NotFound.into()),
+    }
+    if params.session_id == current.session_id {
+        id.forget();
+        return Ok(HttpResponse::Found()
+            .header("location", "/login?logout")
+            .finish());
+    }
+    Ok(HttpResponse::Found()
+        .header("location", "/sessions")
+        .finish())
+}
+
+pub async fn logout_everywhere<D: DbExt>(
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = require_user(&id, &**db)?;
+    db.remove_sessions_by_user(current.user_id)?;
+    id.forget();
+    Ok(HttpResponse::Found()
+        .header("location", "/login?logout")
+        .finish())
+}
@@ -0,0 +1,120 @@
+//! Single use tokens that are sent to users by email.
+//!
+//! Only the SHA-256 hash of a token is stored, so somebody who can read the database still can't
+//! verify addresses or reset passwords.
+
+use crate::{database::*, model::*};
+use rand::RngCore;
+
+pub const EMAIL_VERIFICATION_TTL: u64 = 24 * 60 * 60;
+pub const PASSWORD_RESET_TTL: u64 = 60 * 60;
+pub const TWO_FACTOR_LOGIN_TTL: u64 = 5 * 60;
+
+pub fn generate() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn hash(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    base64::encode_config(digest.as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Creates a token for `email` of the user and returns it
+pub fn issue<D: DbExt>(
+    db: &D,
+    purpose: TokenPurpose,
+    user_id: u64,
+    email: &str,
+) -> DbResult<String> {
+    let ttl = match purpose {
+        TokenPurpose::EmailVerification => EMAIL_VERIFICATION_TTL,
+        TokenPurpose::PasswordReset => PASSWORD_RESET_TTL,
+        TokenPurpose::TwoFactorLogin => TWO_FACTOR_LOGIN_TTL,
+    };
+    let token = generate();
+    db.add_token(
+        &hash(&token),
+        &Token {
+            purpose,
+            user_id,
+            expires: unix_time() + ttl,
+            email: email.to_owned(),
+        },
+    )?;
+    Ok(token)
+}
+
+fn is_valid(token: &Token, purpose: TokenPurpose) -> bool {
+    token.purpose == purpose && token.expires > unix_time()
+}
+
+/// Returns the token if it is valid for `purpose`, without using it up
+pub fn peek<D: DbExt>(db: &D, token: &str, purpose: TokenPurpose) -> DbResult<Option<Token>> {
+    Ok(db
+        .get_token(&hash(token))?
+        .filter(|token| is_valid(token, purpose)))
+}
+
+/// Removes the token and returns it if it is valid for `purpose`. Tokens for another purpose
+/// are left alone, so that e.g. a verification link can't be used up on the reset page.
+pub fn redeem<D: DbExt>(db: &D, token: &str, purpose: TokenPurpose) -> DbResult<Option<Token>> {
+    if peek(db, token, purpose)?.is_none() {
+        return Ok(None);
+    }
+    // Another request could have taken it in the meantime
+    Ok(db
+        .take_token(&hash(token))?
+        .filter(|token| is_valid(token, purpose)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+
+    #[test]
+    fn single_use() {
+        let db = MemoryDb::new();
+        let token = issue(&db, TokenPurpose::PasswordReset, 1, "a@example.com").unwrap();
+        assert!(peek(&db, &token, TokenPurpose::EmailVerification)
+            .unwrap()
+            .is_none());
+        assert!(redeem(&db, &token, TokenPurpose::EmailVerification)
+            .unwrap()
+            .is_none());
+        assert!(peek(&db, &token, TokenPurpose::PasswordReset)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            redeem(&db, &token, TokenPurpose::PasswordReset)
+                .unwrap()
+                .unwrap()
+                .user_id,
+            1
+        );
+        assert!(redeem(&db, &token, TokenPurpose::PasswordReset)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn expired() {
+        let db = MemoryDb::new();
+        let token = generate();
+        db.add_token(
+            &hash(&token),
+            &Token {
+                purpose: TokenPurpose::EmailVerification,
+                user_id: 1,
+                expires: unix_time() - 1,
+                email: "a@example.com".to_owned(),
+            },
+        )
+        .unwrap();
+        assert!(redeem(&db, &token, TokenPurpose::EmailVerification)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,672 @@
+//! Two-factor authentication with time-based one-time passwords (RFC 6238).
+//!
+//! TOTP secrets are encrypted with AES-256-GCM before they are stored, using a key that is kept
+//! outside of the database like the cookie key. The id of the user is authenticated along with
+//! the secret, so secrets can't be moved between users.
+//!
+//! After the password of a user with two-factor authentication was checked, no session is
+//! started yet. Instead a [`TokenPurpose::TwoFactorLogin`] token is stored in a cookie, and the
+//! session starts once a code from the authenticator app or one of the recovery codes is entered
+//! on `/login/2fa`.
+
+use crate::{
+    config::{Config, TwoFactorConfig},
+    csrf::CsrfToken,
+    database::*,
+    identity, log_error,
+    model::*,
+    rate_limit, render, session, token, Tera,
+};
+use actix_identity::Identity;
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::HttpResponseBuilder,
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use rand::RngCore;
+use ring::{aead, hmac};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, io};
+
+const DIGITS: u32 = 6;
+/// Length of a time step in seconds
+const STEP: u64 = 30;
+/// Number of steps a code is still accepted before or after its own step, for clocks that are
+/// slightly off
+const WINDOW: u64 = 1;
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const NONCE_LENGTH: usize = 12;
+
+pub const LOGIN_PATH: &str = "/login/2fa";
+const LOGIN_COOKIE: &str = "login_2fa";
+
+/// Key that TOTP secrets are encrypted with and the name of the site shown in authenticator apps
+pub struct Totp {
+    key: aead::LessSafeKey,
+    issuer: String,
+}
+
+impl Totp {
+    pub fn new(key: &[u8], issuer: &str) -> Self {
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key[..32]).unwrap();
+        Totp {
+            key: aead::LessSafeKey::new(key),
+            issuer: issuer.to_owned(),
+        }
+    }
+
+    /// Loads the key for `config`. With `temporary` set, a random key is used if none is
+    /// configured instead of creating the key file.
+    pub fn load(config: &TwoFactorConfig, temporary: bool) -> io::Result<Self> {
+        let key = match &config.key {
+            Some(key) => identity::decode_key(key)?,
+            None if temporary => identity::generate_key(),
+            None => identity::load_or_create_key_file(&config.key_file)?,
+        };
+        Ok(Totp::new(&key, &config.issuer))
+    }
+
+    fn encrypt(&self, user_id: u64, secret: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut data = secret.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(user_id.to_le_bytes()),
+                &mut data,
+            )
+            .unwrap();
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(data);
+        encrypted
+    }
+
+    fn decrypt(&self, user_id: u64, encrypted: &[u8]) -> DbResult<Vec<u8>> {
+        if encrypted.len() < NONCE_LENGTH {
+            return Err(DbError::Corruption("TOTP secret is too short".to_owned()));
+        }
+        let (nonce, data) = encrypted.split_at(NONCE_LENGTH);
+        let mut data = data.to_vec();
+        let secret = self
+            .key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce.try_into().unwrap()),
+                aead::Aad::from(user_id.to_le_bytes()),
+                &mut data,
+            )
+            .map_err(|_| DbError::Corruption("Can't decrypt TOTP secret".to_owned()))?;
+        Ok(secret.to_vec())
+    }
+}
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Base32 without padding, as used by authenticator apps
+fn base32(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 8];
+        buffer[3..3 + chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes(buffer);
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            encoded.push(BASE32_ALPHABET[(bits >> (35 - 5 * i) & 31) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Percent encoding for the label of the otpauth URI
+fn encode_label(label: &str) -> String {
+    label
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// URI for authenticator apps, usually shown as QR code
+pub fn otpauth_uri(issuer: &str, username: &str, secret: &[u8]) -> String {
+    let query = serde_urlencoded::to_string([
+        ("secret", base32(secret).as_str()),
+        ("issuer", issuer),
+        ("algorithm", "SHA1"),
+        ("digits", &DIGITS.to_string()),
+        ("period", &STEP.to_string()),
+    ])
+    .unwrap();
+    format!(
+        "otpauth://totp/{}:{}?{}",
+        encode_label(issuer),
+        encode_label(username),
+        query
+    )
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(tag[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    code % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step of `code` if it is valid at `now` and newer than `last_step`
+fn verify_code(secret: &[u8], code: &str, now: u64, last_step: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+    let current = now / STEP;
+    (current.saturating_sub(WINDOW)..=current + WINDOW)
+        .filter(|step| *step > last_step)
+        .find(|step| hotp(secret, *step) == code)
+}
+
+/// Recovery codes are compared without dashes, spaces and case
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Returns new recovery codes and their hashes
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; RECOVERY_CODE_LENGTH];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = base32(&bytes);
+            let hash = token::hash(&code);
+            let chunks = code
+                .as_bytes()
+                .chunks(4)
+                .map(|chunk| std::str::from_utf8(chunk).unwrap())
+                .collect::<Vec<_>>();
+            (chunks.join("-"), hash)
+        })
+        .unzip()
+}
+
+pub fn is_enabled(user: &User) -> bool {
+    user.two_factor
+        .as_ref()
+        .is_some_and(|two_factor| two_factor.enabled)
+}
+
+/// Checks a code from the authenticator app or a recovery code and uses it up
+fn accept_code(totp: &Totp, user_id: u64, user: &mut User, code: &str) -> DbResult<bool> {
+    let two_factor = match &mut user.two_factor {
+        Some(two_factor) => two_factor,
+        None => return Ok(false),
+    };
+    let code = code.trim();
+    let secret = totp.decrypt(user_id, &two_factor.secret)?;
+    if let Some(step) = verify_code(&secret, code, unix_time(), two_factor.last_step) {
+        two_factor.last_step = step;
+    } else if two_factor.enabled {
+        let hash = token::hash(&normalize_recovery_code(code));
+        let len = two_factor.recovery_codes.len();
+        two_factor.recovery_codes.retain(|other| *other != hash);
+        if two_factor.recovery_codes.len() == len {
+            return Ok(false);
+        }
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Checks a code from the authenticator app or a recovery code. Accepted codes are used up in
+/// the same transaction, so concurrent requests can't use a code twice.
+pub fn check_code<D: DbExt>(db: &D, totp: &Totp, user_id: u64, code: &str) -> DbResult<bool> {
+    db.modify_user(user_id, |user| accept_code(totp, user_id, user, code))
+}
+
+/// Turns off two-factor authentication for `username`, for users that lost their authenticator
+/// app and recovery codes. Returns whether the user was found.
+pub fn disable<D: DbExt>(db: &D, username: &str) -> DbResult<bool> {
+    let user_id = match db.get_user_by_username(username)? {
+        Some((user_id, _)) => user_id,
+        None => return Ok(false),
+    };
+    db.modify_user(user_id, |user| {
+        user.two_factor = None;
+        Ok(())
+    })?;
+    log::warn!(target: "audit", "Disabled two-factor authentication of {}", user_id);
+    Ok(true)
+}
+
+fn login_cookie(value: String, config: &Config) -> Cookie<'static> {
+    Cookie::build(LOGIN_COOKIE, value)
+        .path(LOGIN_PATH)
+        .http_only(true)
+        .secure(config.cookie.secure)
+        .same_site(SameSite::Strict)
+        .max_age(token::TWO_FACTOR_LOGIN_TTL as i64)
+        .finish()
+}
+
+/// Second step of the login, after the password of `user_id` was checked
+pub fn start_login<D: DbExt>(db: &D, config: &Config, user_id: u64) -> DbResult<HttpResponse> {
+    let token = token::issue(db, TokenPurpose::TwoFactorLogin, user_id, "")?;
+    Ok(HttpResponse::Found()
+        .header("location", LOGIN_PATH)
+        .cookie(login_cookie(token, config))
+        .finish())
+}
+
+fn render_login(
+    tera: &tera::Tera,
+    csrf: &CsrfToken,
+    error: Option<&str>,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("error", &error);
+    render(tera, "login_2fa.html", &ctx, response)
+}
+
+fn restart_login() -> HttpResponse {
+    HttpResponse::Found()
+        .header("location", "/login?expired")
+        .finish()
+}
+
+pub async fn login(
+    req: HttpRequest,
+    csrf: CsrfToken,
+    tera: Tera,
+) -> actix_web::Result<HttpResponse> {
+    if req.cookie(LOGIN_COOKIE).is_none() {
+        return Ok(restart_login());
+    }
+    render_login(&tera, &csrf, None, HttpResponse::Ok())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CodeParams {
+    pub code: String,
+}
+
+pub async fn login_post<D: DbExt>(
+    params: web::Form<CodeParams>,
+    req: HttpRequest,
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    let login_token = match req.cookie(LOGIN_COOKIE) {
+        Some(cookie) => cookie.value().to_owned(),
+        None => return Ok(restart_login()),
+    };
+    let user_id = match token::peek(&**db, &login_token, TokenPurpose::TwoFactorLogin)? {
+        Some(token) => token.user_id,
+        None => return Ok(restart_login()),
+    };
+    let user = db.get_user(user_id)?.ok_or(DbError::NotFound)?;
+    let ip = rate_limit::client_ip(&req);
+    if let Some(retry_after) = rate_limit::check(&**db, &user.username, &ip)? {
+        return Ok(rate_limit::too_many_attempts(retry_after));
+    }
+    if !check_code(&**db, &totp, user_id, &params.code)? {
+        rate_limit::record_failure(&**db, &user.username, &ip)?;
+        return render_login(
+            &tera,
+            &csrf,
+            Some("Wrong code"),
+            HttpResponse::UnprocessableEntity(),
+        );
+    }
+    // The token could have been used by a concurrent request in the meantime
+    if token::redeem(&**db, &login_token, TokenPurpose::TwoFactorLogin)?.is_none() {
+        rate_limit::cancel(&**db, &user.username, &ip)?;
+        return Ok(restart_login());
+    }
+    rate_limit::record_success(&**db, &user.username, &ip)?;
+    session::start_session(&id, &**db, user_id, &req)?;
+    Ok(HttpResponse::Found()
+        .header("location", "/")
+        .del_cookie(&Cookie::build(LOGIN_COOKIE, "").path(LOGIN_PATH).finish())
+        .finish())
+}
+
+fn render_settings<D: DbExt>(
+    tera: &tera::Tera,
+    db: &D,
+    totp: &Totp,
+    current: &session::CurrentUser,
+    csrf: &CsrfToken,
+    page: SettingsPage,
+    response: HttpResponseBuilder,
+) -> actix_web::Result<HttpResponse> {
+    let mut ctx = session::user_context(db, current)?;
+    ctx.insert("csrf_token", &csrf.0);
+    ctx.insert("error", &page.error);
+    ctx.insert("recovery_codes", &page.recovery_codes);
+    match &current.user.two_factor {
+        Some(two_factor) if two_factor.enabled => {
+            ctx.insert("enabled", &true);
+            ctx.insert("remaining_codes", &two_factor.recovery_codes.len());
+        }
+        Some(two_factor) => {
+            let secret = totp.decrypt(current.user_id, &two_factor.secret)?;
+            let uri = otpauth_uri(&totp.issuer, &current.user.username, &secret);
+            let qr_code = qrcode::QrCode::new(uri.as_bytes())
+                .map_err(|err| log_error(err, "QR code error"))?
+                .render::<qrcode::render::svg::Color>()
+                .min_dimensions(200, 200)
+                .build();
+            ctx.insert("enabled", &false);
+            ctx.insert("secret", &base32(&secret));
+            ctx.insert("otpauth_uri", &uri);
+            ctx.insert("qr_code", &qr_code);
+        }
+        None => ctx.insert("enabled", &false),
+    }
+    render(tera, "two_factor.html", &ctx, response)
+}
+
+/// What the settings page shows besides the current state
+#[derive(Default)]
+struct SettingsPage<'a> {
+    error: Option<&'a str>,
+    /// New recovery codes, only shown once
+    recovery_codes: Option<Vec<String>>,
+}
+
+pub async fn settings<D: DbExt>(
+    id: Identity,
+    csrf: CsrfToken,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    render_settings(
+        &tera,
+        &**db,
+        &totp,
+        &current,
+        &csrf,
+        SettingsPage::default(),
+        HttpResponse::Ok(),
+    )
+}
+
+fn redirect_to_settings() -> HttpResponse {
+    HttpResponse::Found()
+        .header("location", "/settings/2fa")
+        .finish()
+}
+
+/// Generates a new secret, which has to be confirmed with a code before it is used
+pub async fn enroll_post<D: DbExt>(
+    id: Identity,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    if !is_enabled(&current.user) {
+        let mut secret = [0u8; SECRET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let two_factor = TwoFactor {
+            secret: totp.encrypt(current.user_id, &secret),
+            enabled: false,
+            recovery_codes: Vec::new(),
+            last_step: 0,
+        };
+        db.modify_user(current.user_id, |user| {
+            // Enabled in the meantime by another request
+            if !is_enabled(user) {
+                user.two_factor = Some(two_factor.clone());
+            }
+            Ok(())
+        })?;
+    }
+    Ok(redirect_to_settings())
+}
+
+/// Handles a form that needs a valid code, `action` is called with the user after the code
+/// was accepted. Both happen in one transaction. Wrong codes count as failed logins, so that a
+/// stolen session can't be used to guess them.
+async fn with_code<D: DbExt, F: Fn(&mut User) -> Option<Vec<String>>>(
+    params: web::Form<CodeParams>,
+    req: HttpRequest,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+    action: F,
+) -> actix_web::Result<HttpResponse> {
+    let csrf = CsrfToken::extract(&req).await?;
+    let mut current = session::require_user(&id, &**db)?;
+    if current.user.two_factor.is_none() {
+        return Ok(redirect_to_settings());
+    }
+    let ip = rate_limit::client_ip(&req);
+    if let Some(retry_after) = rate_limit::check(&**db, &current.user.username, &ip)? {
+        return Ok(rate_limit::too_many_attempts(retry_after));
+    }
+    let result = db.modify_user(current.user_id, |user| {
+        Ok(
+            if accept_code(&totp, current.user_id, user, &params.code)? {
+                Some(action(user))
+            } else {
+                None
+            },
+        )
+    })?;
+    let recovery_codes = match result {
+        Some(recovery_codes) => recovery_codes,
+        None => {
+            rate_limit::record_failure(&**db, &current.user.username, &ip)?;
+            let page = SettingsPage {
+                error: Some("Wrong code"),
+                ..Default::default()
+            };
+            return render_settings(
+                &tera,
+                &**db,
+                &totp,
+                &current,
+                &csrf,
+                page,
+                HttpResponse::UnprocessableEntity(),
+            );
+        }
+    };
+    rate_limit::record_success(&**db, &current.user.username, &ip)?;
+    current.user = db.get_user(current.user_id)?.ok_or(DbError::NotFound)?;
+    if recovery_codes.is_none() {
+        return Ok(redirect_to_settings());
+    }
+    let page = SettingsPage {
+        recovery_codes,
+        ..Default::default()
+    };
+    render_settings(
+        &tera,
+        &**db,
+        &totp,
+        &current,
+        &csrf,
+        page,
+        HttpResponse::Ok(),
+    )
+}
+
+/// Replaces the recovery codes and returns the new ones
+fn new_recovery_codes(user: &mut User) -> Option<Vec<String>> {
+    let two_factor = user.two_factor.as_mut()?;
+    let (codes, hashes) = generate_recovery_codes();
+    two_factor.recovery_codes = hashes;
+    Some(codes)
+}
+
+pub async fn confirm_post<D: DbExt>(
+    params: web::Form<CodeParams>,
+    req: HttpRequest,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    with_code(params, req, id, tera, db, totp, |user| {
+        let two_factor = user.two_factor.as_mut()?;
+        if two_factor.enabled {
+            return None;
+        }
+        two_factor.enabled = true;
+        new_recovery_codes(user)
+    })
+    .await
+}
+
+pub async fn recovery_codes_post<D: DbExt>(
+    params: web::Form<CodeParams>,
+    req: HttpRequest,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    with_code(params, req, id, tera, db, totp, new_recovery_codes).await
+}
+
+pub async fn disable_post<D: DbExt>(
+    params: web::Form<CodeParams>,
+    req: HttpRequest,
+    id: Identity,
+    tera: Tera,
+    db: web::Data<D>,
+    totp: web::Data<Totp>,
+) -> actix_web::Result<HttpResponse> {
+    with_code(params, req, id, tera, db, totp, |user| {
+        user.two_factor = None;
+        None
+    })
+    .await
+}
+
+/// The code an authenticator app would show for the base32 encoded `secret`
+#[cfg(test)]
+pub fn current_code(secret: &str) -> String {
+    let mut decoded = Vec::new();
+    let (mut bits, mut count) = (0u64, 0);
+    for c in secret.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c).unwrap();
+        bits = bits << 5 | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    format!(
+        "{:0width$}",
+        hotp(&decoded, unix_time() / STEP),
+        width = DIGITS as usize
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_db::MemoryDb;
+
+    #[test]
+    fn rfc6238() {
+        // Test vectors from RFC 6238 for SHA-1, truncated to 6 digits
+        let secret = b"12345678901234567890";
+        for (time, code) in &[
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+        ] {
+            assert_eq!(verify_code(secret, code, *time, 0), Some(time / STEP));
+        }
+        assert_eq!(verify_code(secret, "287082", 59 + STEP, 0), Some(1));
+        assert_eq!(verify_code(secret, "287082", 59 + 2 * STEP, 0), None);
+        // Codes can't be used twice
+        assert_eq!(verify_code(secret, "287082", 59, 1), None);
+        assert_eq!(verify_code(secret, "28708", 59, 0), None);
+    }
+
+    #[test]
+    fn otpauth() {
+        assert_eq!(
+            base32(b"12345678901234567890"),
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        );
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(
+            current_code("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"),
+            format!("{:06}", hotp(b"12345678901234567890", unix_time() / STEP))
+        );
+        assert_eq!(
+            otpauth_uri("next flix", "alice", b"f"),
+            "otpauth://totp/next%20flix:alice?secret=MY&issuer=next+flix&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn encryption() {
+        let totp = Totp::new(&identity::generate_key(), "nextflix");
+        let encrypted = totp.encrypt(1, b"secret");
+        assert_eq!(totp.decrypt(1, &encrypted).unwrap(), b"secret");
+        assert!(totp.decrypt(2, &encrypted).is_err());
+        let other = Totp::new(&identity::generate_key(), "nextflix");
+        assert!(other.decrypt(1, &encrypted).is_err());
+    }
+
+    #[test]
+    fn recovery_codes() {
+        let db = MemoryDb::new();
+        let totp = Totp::new(&identity::generate_key(), "nextflix");
+        let user_id = db
+            .add_user(&User {
+                username: "alice".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        let (codes, hashes) = generate_recovery_codes();
+        let user = User {
+            username: "alice".to_owned(),
+            two_factor: Some(TwoFactor {
+                secret: totp.encrypt(user_id, b"12345678901234567890"),
+                enabled: true,
+                recovery_codes: hashes,
+                last_step: 0,
+            }),
+            ..Default::default()
+        };
+        db.update_user(user_id, &user).unwrap();
+        let code = codes[0].to_lowercase().replace('-', " ");
+        assert!(check_code(&db, &totp, user_id, &code).unwrap());
+        assert!(!check_code(&db, &totp, user_id, &codes[0]).unwrap());
+        assert_eq!(
+            db.get_user(user_id)
+                .unwrap()
+                .unwrap()
+                .two_factor
+                .unwrap()
+                .recovery_codes
+                .len(),
+            RECOVERY_CODES - 1
+        );
+    }
+}
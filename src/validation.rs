@@ -0,0 +1,244 @@
+//! Validation of user input with messages that can be shown next to form fields.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+const PASSWORD_MIN_LENGTH: usize = 8;
+const EMAIL_MAX_LENGTH: usize = 254;
+const DISPLAY_NAME_MAX_LENGTH: usize = 64;
+
+/// Names that could be confused with the site itself or its routes
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "login",
+    "logout",
+    "me",
+    "moderator",
+    "nextflix",
+    "register",
+    "root",
+    "sessions",
+    "settings",
+    "support",
+    "system",
+];
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "12345678",
+    "123456789",
+    "1234567890",
+    "11111111",
+    "password",
+    "password1",
+    "password123",
+    "qwertyuiop",
+    "qwerty123",
+    "iloveyou",
+    "sunshine",
+    "letmein1",
+    "football",
+    "baseball",
+    "princess",
+    "abc12345",
+];
+
+/// Error messages by form field
+#[derive(Serialize, Debug, Default)]
+pub struct FieldErrors(BTreeMap<&'static str, Vec<String>>);
+
+impl FieldErrors {
+    pub fn add<S: Into<String>>(&mut self, field: &'static str, message: S) {
+        self.0.entry(field).or_default().push(message.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub fn is_username_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+pub fn validate_username(username: &str, errors: &mut FieldErrors) {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        errors.add(
+            "username",
+            format!(
+                "Must be between {} and {} characters long",
+                USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+            ),
+        );
+    }
+    if !username.chars().all(is_username_character) {
+        errors.add(
+            "username",
+            "May only contain letters, digits, '_', '-' and '.'",
+        );
+    } else if !username
+        .chars()
+        .next()
+        .map(|c| c.is_ascii_alphanumeric())
+        .unwrap_or(true)
+    {
+        errors.add("username", "Must start with a letter or digit");
+    }
+    if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        errors.add("username", "This name is reserved");
+    }
+}
+
+/// `max_bytes` depends on the hashing algorithm, see [`crate::password::max_bytes`]
+pub fn validate_password(
+    password: &str,
+    username: &str,
+    max_bytes: usize,
+    errors: &mut FieldErrors,
+) {
+    if password.chars().count() < PASSWORD_MIN_LENGTH {
+        errors.add(
+            "password",
+            format!("Must be at least {} characters long", PASSWORD_MIN_LENGTH),
+        );
+    }
+    if password.len() > max_bytes {
+        errors.add(
+            "password",
+            format!("Must not be longer than {} bytes", max_bytes),
+        );
+    }
+    let lower = password.to_lowercase();
+    if COMMON_PASSWORDS.contains(&lower.as_str()) {
+        errors.add("password", "This password is too common");
+    } else if !username.is_empty() && lower.contains(&username.to_lowercase()) {
+        errors.add("password", "Must not contain the username");
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|c| **c).count() < 2 {
+        errors.add(
+            "password",
+            "Must contain at least two of lowercase letters, uppercase letters, digits and symbols",
+        );
+    }
+}
+
+/// Only catches obvious typos, whether the address exists is checked by sending a verification
+/// email
+pub fn validate_email(email: &str, errors: &mut FieldErrors) {
+    let valid = match email.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+                && !email.contains(['<', '>', ',', ';'])
+        }
+        None => false,
+    };
+    if !valid {
+        errors.add("email", "Not a valid email address");
+    } else if email.len() > EMAIL_MAX_LENGTH {
+        errors.add(
+            "email",
+            format!("Must not be longer than {} characters", EMAIL_MAX_LENGTH),
+        );
+    }
+}
+
+pub fn validate_display_name(display_name: &str, errors: &mut FieldErrors) {
+    if display_name.chars().count() > DISPLAY_NAME_MAX_LENGTH {
+        errors.add(
+            "display_name",
+            format!(
+                "Must not be longer than {} characters",
+                DISPLAY_NAME_MAX_LENGTH
+            ),
+        );
+    }
+    if display_name.chars().any(char::is_control) {
+        errors.add("display_name", "Must not contain control characters");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn username_errors(username: &str) -> usize {
+        let mut errors = FieldErrors::default();
+        validate_username(username, &mut errors);
+        errors.0.get("username").map(Vec::len).unwrap_or(0)
+    }
+
+    fn password_errors(password: &str) -> usize {
+        let mut errors = FieldErrors::default();
+        validate_password(password, "alice", 72, &mut errors);
+        errors.0.get("password").map(Vec::len).unwrap_or(0)
+    }
+
+    #[test]
+    fn username() {
+        assert_eq!(username_errors("alice"), 0);
+        assert_eq!(username_errors("bob_the-2nd.x"), 0);
+        assert_eq!(username_errors("al"), 1);
+        assert_eq!(username_errors(&"a".repeat(33)), 1);
+        assert_eq!(username_errors("alice smith"), 1);
+        assert_eq!(username_errors("_alice"), 1);
+        assert_eq!(username_errors("Admin"), 1);
+        assert_eq!(username_errors("ä"), 2);
+    }
+
+    #[test]
+    fn password() {
+        assert_eq!(password_errors("correct horse"), 0);
+        assert_eq!(password_errors("Tr0ub4dor"), 0);
+        assert_eq!(password_errors("short1"), 1);
+        assert_eq!(password_errors("Password123"), 1);
+        assert_eq!(password_errors("alice1234"), 1);
+        assert_eq!(password_errors("abcdefghij"), 1);
+        assert_eq!(password_errors(&"a1".repeat(40)), 1);
+        let mut errors = FieldErrors::default();
+        validate_password(&"a1".repeat(40), "alice", 1024, &mut errors);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn email() {
+        let email_errors = |email: &str| {
+            let mut errors = FieldErrors::default();
+            validate_email(email, &mut errors);
+            errors.0.len()
+        };
+        assert_eq!(email_errors("alice@example.com"), 0);
+        assert_eq!(email_errors("a.b+c@mail.example.org"), 0);
+        assert_eq!(email_errors("alice"), 1);
+        assert_eq!(email_errors("@example.com"), 1);
+        assert_eq!(email_errors("alice@localhost"), 1);
+        assert_eq!(email_errors("alice @example.com"), 1);
+        assert_eq!(email_errors("alice@example.com>\r\nBcc: x"), 1);
+    }
+
+    #[test]
+    fn display_name() {
+        let display_name_errors = |display_name: &str| {
+            let mut errors = FieldErrors::default();
+            validate_display_name(display_name, &mut errors);
+            errors.0.get("display_name").map(Vec::len).unwrap_or(0)
+        };
+        assert_eq!(display_name_errors("Alice Smith"), 0);
+        assert_eq!(display_name_errors("Zoë O'Brien"), 0);
+        assert_eq!(display_name_errors(&"a".repeat(65)), 1);
+        assert_eq!(display_name_errors("Alice\nBob"), 1);
+    }
+}
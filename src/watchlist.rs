@@ -0,0 +1,201 @@
+//! Friends and recommending movies to them.
+
+use crate::{database::*, model::*, notifications, session};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct WatchlistFilter {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub genre: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub year: Option<u16>,
+}
+
+/// Forms submit fields that were left empty as empty strings, which mean "no filter"
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)?
+        .as_deref()
+        .map(str::trim)
+    {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+pub struct WatchlistEntry {
+    pub movie_id: u64,
+    pub movie: Movie,
+    /// Friends who recommended the movie
+    pub recommended_by: Vec<u64>,
+}
+
+/// Movies recommended to the user that match `filter`, ordered by id
+pub fn watchlist<D: DbExt>(
+    db: &D,
+    user: &User,
+    filter: &WatchlistFilter,
+) -> DbResult<Vec<WatchlistEntry>> {
+    let mut recommended_by = BTreeMap::<u64, Vec<u64>>::new();
+    for (friend_id, friend_data) in &user.friends {
+        // Removed users stay in friend lists until `remove_user` gets to them
+        if db.get_user(*friend_id)?.is_none() {
+            continue;
+        }
+        for movie_id in &friend_data.movies {
+            recommended_by
+                .entry(*movie_id)
+                .or_default()
+                .push(*friend_id);
+        }
+    }
+    if let Some(genre) = &filter.genre {
+        let genre_ids = db
+            .get_movies_by_genre(genre)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        recommended_by.retain(|movie_id, _| genre_ids.contains(movie_id));
+    }
+    if let Some(year) = filter.year {
+        let year_ids = db
+            .get_movies_by_year(year, year)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        recommended_by.retain(|movie_id, _| year_ids.contains(movie_id));
+    }
+    let mut entries = Vec::new();
+    for (movie_id, mut friends) in recommended_by {
+        // Removed movies can stay in friend lists if they were recommended while `remove_movie`
+        // ran
+        let movie = match db.get_movie(movie_id)? {
+            Some(movie) => movie,
+            None => continue,
+        };
+        friends.sort_unstable();
+        entries.push(WatchlistEntry {
+            movie_id,
+            movie,
+            recommended_by: friends,
+        });
+    }
+    Ok(entries)
+}
+
+/// Adds the movie to the watchlist of `to` and notifies them. Returns false if `from` already
+/// recommended the movie.
+pub fn recommend<D: DbExt>(db: &D, from: u64, to: u64, movie_id: u64) -> DbResult<bool> {
+    let added = db.recommend_movie(from, to, movie_id)?;
+    if added {
+        notifications::send(db, to, Event::Recommendation { from, movie_id })?;
+    }
+    Ok(added)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecommendParams {
+    /// Username of the friend
+    pub friend: String,
+    pub movie_id: u64,
+}
+
+pub async fn recommend_post<D: DbExt>(
+    params: web::Form<RecommendParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let (friend_id, _) = db
+        .get_user_by_username(&params.friend)?
+        .ok_or(DbError::NotFound)?;
+    recommend(&**db, current.user_id, friend_id, params.movie_id)?;
+    Ok(HttpResponse::Found()
+        .header("location", "/?recommended")
+        .finish())
+}
+
+/// Users that added `user_id` as a friend, so that `user_id` can recommend movies to them,
+/// ordered by id
+pub fn added_by<D: DbExt>(db: &D, user_id: u64) -> DbResult<Vec<(u64, User)>> {
+    Ok(db
+        .get_users()?
+        .into_iter()
+        .filter(|(_, other)| other.friends.contains_key(&user_id))
+        .collect())
+}
+
+/// Friends of the user's friends that the user hasn't added yet, with the number of mutual
+/// friends, most mutual friends first
+pub fn suggest_friends<D: DbExt>(
+    db: &D,
+    user_id: u64,
+    user: &User,
+    limit: usize,
+) -> DbResult<Vec<(u64, User, usize)>> {
+    let mut mutual = BTreeMap::<u64, usize>::new();
+    for friend_id in user.friends.keys() {
+        let friend = match db.get_user(*friend_id)? {
+            Some(friend) => friend,
+            None => continue,
+        };
+        for candidate in friend.friends.keys() {
+            if *candidate != user_id && !user.friends.contains_key(candidate) {
+                *mutual.entry(*candidate).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut ranked = mutual.into_iter().collect::<Vec<_>>();
+    // Stable, so ties stay ordered by id
+    ranked.sort_by(|(_, a), (_, b)| b.cmp(a));
+    let mut suggestions = Vec::new();
+    for (candidate_id, count) in ranked {
+        if suggestions.len() == limit {
+            break;
+        }
+        match db.get_user(candidate_id)? {
+            Some(candidate) if !candidate.disabled => {
+                suggestions.push((candidate_id, candidate, count))
+            }
+            _ => {}
+        }
+    }
+    Ok(suggestions)
+}
+
+/// Lets `friend_id` recommend movies to the user, and asks them to add the user back
+pub fn add_friend<D: DbExt>(db: &D, user_id: u64, friend_id: u64) -> DbResult<bool> {
+    let added = db.add_friend(user_id, friend_id)?;
+    let friend = db.get_user(friend_id)?.ok_or(DbError::NotFound)?;
+    if added && !friend.friends.contains_key(&user_id) {
+        notifications::send(db, friend_id, Event::FriendRequest { from: user_id })?;
+    }
+    Ok(added)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddFriendParams {
+    pub username: String,
+}
+
+pub async fn add_friend_post<D: DbExt>(
+    params: web::Form<AddFriendParams>,
+    id: Identity,
+    db: web::Data<D>,
+) -> actix_web::Result<HttpResponse> {
+    let current = session::require_user(&id, &**db)?;
+    let (friend_id, _) = db
+        .get_user_by_username(params.username.trim())?
+        .ok_or(DbError::NotFound)?;
+    add_friend(&**db, current.user_id, friend_id)?;
+    Ok(HttpResponse::Found().header("location", "/").finish())
+}